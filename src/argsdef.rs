@@ -3,7 +3,7 @@ use clap::Parser;
 use clap::Subcommand;
 
 #[derive(Subcommand, Debug)]
-pub enum Spark {
+pub enum Commands {
     /// Used for benchmarking, profiling and debugging *WIP*
     Spark {
         #[arg(short, long)]
@@ -51,6 +51,88 @@ pub enum Spark {
         )]
         profile: bool,
     },
+
+    /// Summarizes a heap dump, showing the object types using the most memory
+    Heapdump {
+        /// Heap dump file
+        #[arg(value_name = "FILE")]
+        file: String,
+
+        /// Number of object types to show
+        #[arg(long, value_name = "TYPES", default_value = "10")]
+        top: usize,
+    },
+
+    /// Packs the modules and resources listed in a manifest.json into a Squid ARchive
+    Pack {
+        /// Manifest of the archive | Files are read from its folder unless --root is used
+        #[arg(value_name = "MANIFEST")]
+        manifest: String,
+
+        /// Archive to create | Don't need extension (.sar)
+        #[arg(short, long, value_name = "FILE")]
+        output: String,
+
+        /// Folder the modules and resources are read from
+        #[arg(long, value_name = "DIR")]
+        root: Option<String>,
+
+        /// Compresses the files with deflate
+        #[arg(long)]
+        compress: bool,
+
+        /// Secret key the archive is signed with | Made with the keygen subcommand
+        #[arg(long, value_name = "KEYFILE")]
+        sign: Option<String>,
+
+        /// Key the modules and resources are encrypted with | Made with keygen --encryption
+        #[arg(long, value_name = "KEYFILE")]
+        encrypt: Option<String>,
+    },
+
+    /// Generates a key pair for signing Squid ARchives, or a key for encrypting them
+    Keygen {
+        /// Key files to create, without extension | Writes FILE.key (secret) and FILE.pub (public)
+        #[arg(short, long, value_name = "FILE")]
+        output: String,
+
+        /// Generates an encryption key instead | Writes FILE.sarkey
+        #[arg(long)]
+        encryption: bool,
+    },
+
+    /// Extracts the files of a Squid ARchive
+    Unpack {
+        /// Squid ARchive File Input | Don't need extension (.sar)
+        #[arg(value_name = "FILE")]
+        sar: String,
+
+        /// Folder the files are extracted to
+        #[arg(short, long, value_name = "DIR", default_value = ".")]
+        output: String,
+    },
+
+    /// Shows the manifest and the files of a Squid ARchive
+    List {
+        /// Squid ARchive File Input | Don't need extension (.sar)
+        #[arg(value_name = "FILE")]
+        sar: String,
+    },
+
+    /// Creates an executable that runs a Squid ARchive when started without arguments
+    Bundle {
+        /// Squid ARchive File Input | Don't need extension (.sar)
+        #[arg(value_name = "FILE")]
+        sar: String,
+
+        /// Executable to create
+        #[arg(short, long, value_name = "FILE")]
+        output: String,
+
+        /// VM executable the archive is appended to | Defaults to this VM, it needs the 'bundle' feature
+        #[arg(long, value_name = "FILE")]
+        vm: Option<String>,
+    },
 }
 
 #[cfg(feature = "default")]
@@ -66,6 +148,22 @@ pub struct Args {
     #[arg(short, long, value_name = "FILE", conflicts_with = "bin")]
     pub sar: Option<String>,
 
+    /// Folder searched for the modules a binary imports, after the binary's own folder | Can be repeated
+    #[arg(long, value_name = "DIR")]
+    pub module_path: Vec<String>,
+
+    /// Runs Squid ARchives not signed by any key
+    #[arg(long)]
+    pub allow_unsigned: bool,
+
+    /// Folder with the public keys (.pub) Squid ARchives may be signed with | Defaults to ~/.squidvm/trusted-keys
+    #[arg(long, value_name = "DIR")]
+    pub trusted_keys: Option<String>,
+
+    /// Key encrypted Squid ARchives are decrypted with | Defaults to the SQUIDVM_SAR_KEY environment variable (hex)
+    #[arg(long, value_name = "KEYFILE")]
+    pub sar_key: Option<String>,
+
     /// Max Memory Allocated for the heap | Postfixes: GB, MB, KB, B
     #[arg(
         long,
@@ -93,6 +191,30 @@ pub struct Args {
     /// Size of the stack. Measured in objects
     pub stack_size: usize,
 
+    /// Shows the heap statistics (objects, bytes, regions, peak usage) when the program ends
+    #[arg(long)]
+    pub heap_stats: bool,
+
+    /// Dumps the heap to the file if it runs out of memory
+    #[arg(long, value_name = "FILE")]
+    pub heap_dump_on_oom: Option<String>,
+
+    /// Number of worker threads running the VM threads | Defaults to the number of CPUs
+    #[arg(long, value_name = "THREADS")]
+    pub threads: Option<usize>,
+
+    /// Shows the thread pool statistics (workers, queued threads, saturation) when the program ends
+    #[arg(long)]
+    pub thread_stats: bool,
+
+    /// Number of worker threads running the green-thread tasks | Defaults to the number of CPUs
+    #[arg(long, value_name = "THREADS")]
+    pub task_workers: Option<usize>,
+
+    /// Instructions a task runs before giving its worker to the next task
+    #[arg(long, value_name = "INSTRUCTIONS", default_value = "1000")]
+    pub task_slice: usize,
+
     /// Check the binary version for binaries with metadata included | Works with bin or sar
     #[arg(
         long,
@@ -116,7 +238,7 @@ pub struct Args {
     pub check_updates: bool,
 
     #[clap(subcommand)]
-    pub spark: Option<Spark>,
+    pub command: Option<Commands>,
 }
 
 #[cfg(feature = "default")]
@@ -20,3 +20,22 @@ pub const UPDATE_CHECK_ERR: i32 = 7;
 pub const FEATURE_ERR: i32 = 10;
 
 pub const PRINT_THREAD_ERR: i32 = 11;
+
+/// Error while reading a heap dump file.
+pub const HEAP_DUMP_ERR: i32 = 13;
+
+/// A VM thread or task failed with the propagate or abort supervisor policy.
+pub const THREAD_ERR: i32 = 14;
+
+/// The main thread panicked, either by running `PANIC` or by an error.
+pub const PANIC_ERR: i32 = 15;
+
+/// Error while reading a Squid ARchive or its manifest.
+pub const SAR_ERR: i32 = 16;
+
+/// Error while linking a module with the modules it imports.
+pub const LINK_ERR: i32 = 17;
+
+/// A Squid ARchive isn't signed by a trusted key, or isn't signed at all.
+#[cfg(feature = "sar")]
+pub const SIGNATURE_ERR: i32 = 18;
@@ -64,13 +64,18 @@ pub const D_VFD: u8 = 0x16;
 /// Debug variable from stack.
 pub const D_VFS: u8 = 0x17;
 
-/// Creates new task.
+/// Creates new task running the entry point (address, function or closure) popped from the stack.
+///
+/// Data is a flags byte: bit 0 pushes a handle for `AWAIT`,
+/// bits 1-2 are the supervisor policy if it fails (0 ignore, 1 propagate to the main thread, 2 abort the VM).
 pub const NTASK: u8 = 0x19;
 
-/// Creates new thread.
+/// Creates new thread running the entry point (address, function or closure) popped from the stack.
+///
+/// Data is a flags byte, the same as `NTASK`.
 pub const NTHRD: u8 = 0x1A;
 
-/// Panics the current thread
+/// Panics the current thread with the message (or any value) popped from the stack.
 pub const PANIC: u8 = 0x1B;
 
 pub const PEEK: u8 = 0x1C;
@@ -86,3 +91,233 @@ pub const GREATERTHAN: u8 = 0x20;
 pub const AND: u8 = 0x21;
 
 pub const OR: u8 = 0x22;
+
+/// Loads a function object (by index from data) and pushes its pointer to the stack.
+pub const LDFN: u8 = 0x23;
+
+/// Pops a function pointer or a closure from the stack and calls it.
+/// Asynchronous functions can't be called, they must be started with `NTASK`.
+pub const CALL: u8 = 0x24;
+
+/// Returns from the current function.
+pub const RET: u8 = 0x25;
+
+/// Pops captured values (count from data) and a function pointer or closure,
+/// then pushes the new closure.
+///
+/// Closures are values, not heap objects: they're released with the last copy holding them.
+pub const CLOSURE: u8 = 0x26;
+
+/// Pops a file path from the stack and dumps the heap to it.
+pub const HDUMP: u8 = 0x27;
+
+/// Pushes the value of a global variable (name from data) to the stack.
+pub const GETG: u8 = 0x28;
+
+/// Pops a value from the stack and stores it in a global variable (name from data).
+pub const SETG: u8 = 0x29;
+
+/// Deletes a global variable (name from data).
+pub const DELG: u8 = 0x2A;
+
+/// Atomically compares and swaps a global variable (name from data).
+/// Pops the new and the expected values, pushes if it was swapped.
+pub const GCAS: u8 = 0x2B;
+
+/// Atomically adds the value popped from the stack to a global variable (name from data).
+/// Pushes the old value.
+pub const GFADD: u8 = 0x2C;
+
+/// Atomically exchanges a global variable (name from data) with the value popped from the stack.
+/// Pushes the old value.
+pub const GXCHG: u8 = 0x2D;
+
+/// Joins the thread whose handle is popped from the stack.
+/// Pushes its return value (or error message) and its status:
+/// 0 finished, 1 failed, 2 cancelled.
+pub const JOIN: u8 = 0x2E;
+
+/// Awaits the task whose handle is popped from the stack.
+/// Pushes the same values as `JOIN`.
+pub const AWAIT: u8 = 0x2F;
+
+/// Creates a new channel, with the capacity from data (0 for unbounded).
+pub const CHNEW: u8 = 0x30;
+
+/// Pops a value and a channel from the stack and sends the value through the channel.
+/// Blocks while a bounded channel is full.
+pub const CHSEND: u8 = 0x31;
+
+/// Pops a channel from the stack and receives a value from it, blocking while it's empty.
+/// Pushes the value and `true`, or `Null` and `false` if the channel is closed.
+pub const CHRECV: u8 = 0x32;
+
+/// Pops a channel from the stack and receives a value from it without blocking.
+/// Pushes the value and `true`, or `Null` and `false` if there's no value.
+pub const CHTRY: u8 = 0x33;
+
+/// Pops a channel from the stack and closes it.
+pub const CHCLOSE: u8 = 0x34;
+
+/// Allocates a new mutex and pushes a pointer to it.
+pub const MXNEW: u8 = 0x35;
+
+/// Pops a mutex pointer and locks it, blocking while someone else holds it.
+pub const MXLOCK: u8 = 0x36;
+
+/// Pops a mutex pointer and unlocks it. Errors if the mutex isn't held by the thread.
+pub const MXUNLOCK: u8 = 0x37;
+
+/// Allocates a new condition and pushes a pointer to it.
+pub const CVNEW: u8 = 0x38;
+
+/// Pops a mutex and a condition pointer, releases the mutex until the condition is notified.
+/// May wake up without being notified, so it should be used inside a loop.
+pub const CVWAIT: u8 = 0x39;
+
+/// Pops a condition pointer and wakes up one of its waiters.
+pub const CVNOTIFY: u8 = 0x3C;
+
+/// Pops a condition pointer and wakes up all its waiters.
+pub const CVNOTIFYALL: u8 = 0x3D;
+
+/// Pops an Integer and allocates an atomic cell with it, pushes a pointer to it.
+pub const ATNEW: u8 = 0x3E;
+
+/// Pops an atomic pointer and pushes its value.
+pub const ATLOAD: u8 = 0x3F;
+
+/// Pops an Integer and an atomic pointer and stores the value in it.
+pub const ATSTORE: u8 = 0x40;
+
+/// Pops an Integer and an atomic pointer, adds the value to it and pushes the old value.
+pub const ATFADD: u8 = 0x41;
+
+/// Pops the new and expected Integers and an atomic pointer, swaps them if it's equal to expected.
+/// Pushes if it was swapped.
+pub const ATCAS: u8 = 0x42;
+
+/// Gives the worker thread up so other tasks can run.
+pub const YIELD: u8 = 0x43;
+
+/// Pops a timeout in milliseconds (UInteger) and a thread handle, then joins the thread.
+/// Pushes the same values as `JOIN`, or `Null` and status 3 if it timed out.
+pub const JOINTO: u8 = 0x44;
+
+/// Pops a timeout in milliseconds (UInteger) and a task handle, then awaits the task.
+/// Pushes the same values as `JOINTO`.
+pub const AWAITTO: u8 = 0x45;
+
+/// Cancels the thread whose handle is popped from the stack.
+pub const CANCEL: u8 = 0x46;
+
+/// Cancels the task whose handle is popped from the stack.
+pub const TCANCEL: u8 = 0x47;
+
+/// Starts a try region, data is the address of its handler.
+/// Exceptions thrown inside it unwind to the handler with the payload on top of the stack.
+pub const TRY: u8 = 0x48;
+
+/// Ends the latest try region.
+pub const ENDTRY: u8 = 0x49;
+
+/// Throws the value popped from the stack, failing the thread if no try region catches it.
+pub const THROW: u8 = 0x4A;
+
+/// Pops the name of a resource of the running SAR, pushing its bytes as a `Binary`.
+pub const RSREAD: u8 = 0x4B;
+
+/// Pops the name of a resource of the running SAR, pushing it as a string.
+pub const RSREADS: u8 = 0x4C;
+
+/// Pushes an array with the names of the resources of the running SAR.
+pub const RSLIST: u8 = 0x4D;
+
+/// Pops the name of a resource, pushing if the running SAR has it.
+pub const RSEXISTS: u8 = 0x4E;
+
+/// Pops the name of a native function declared by the running SAR and its arguments,
+/// calling it and pushing its result (nothing for `void`).
+pub const CALLNATIVE: u8 = 0x4F;
+
+/// Name of the instruction, used in panic reports and stack traces.
+pub fn mnemonic(instruction: u8) -> &'static str {
+    match instruction {
+        HALT => "HALT",
+        I_ADD => "I_ADD",
+        I_SUB => "I_SUB",
+        I_MUL => "I_MUL",
+        I_DVD => "I_DVD",
+        F_I_DVD => "F_I_DVD",
+        F_ADD => "F_ADD",
+        F_SUB => "F_SUB",
+        F_MUL => "F_MUL",
+        F_DVD => "F_DVD",
+        PDTS => "PDTS",
+        PDFS => "PDFS",
+        JMPFD => "JMPFD",
+        JMPFS => "JMPFS",
+        PRTFS => "PRTFS",
+        PRTFD => "PRTFD",
+        I_EXP => "I_EXP",
+        F_EXP => "F_EXP",
+        F_I_EXP => "F_I_EXP",
+        AVP => "AVP",
+        D_VFD => "D_VFD",
+        D_VFS => "D_VFS",
+        NTASK => "NTASK",
+        NTHRD => "NTHRD",
+        PANIC => "PANIC",
+        PEEK => "PEEK",
+        SWAP => "SWAP",
+        EQUALS => "EQUALS",
+        LESSTHAN => "LESSTHAN",
+        GREATERTHAN => "GREATERTHAN",
+        AND => "AND",
+        OR => "OR",
+        LDFN => "LDFN",
+        CALL => "CALL",
+        RET => "RET",
+        CLOSURE => "CLOSURE",
+        HDUMP => "HDUMP",
+        GETG => "GETG",
+        SETG => "SETG",
+        DELG => "DELG",
+        GCAS => "GCAS",
+        GFADD => "GFADD",
+        GXCHG => "GXCHG",
+        JOIN => "JOIN",
+        AWAIT => "AWAIT",
+        CHNEW => "CHNEW",
+        CHSEND => "CHSEND",
+        CHRECV => "CHRECV",
+        CHTRY => "CHTRY",
+        CHCLOSE => "CHCLOSE",
+        MXNEW => "MXNEW",
+        MXLOCK => "MXLOCK",
+        MXUNLOCK => "MXUNLOCK",
+        CVNEW => "CVNEW",
+        CVWAIT => "CVWAIT",
+        CVNOTIFY => "CVNOTIFY",
+        CVNOTIFYALL => "CVNOTIFYALL",
+        ATNEW => "ATNEW",
+        ATLOAD => "ATLOAD",
+        ATSTORE => "ATSTORE",
+        ATFADD => "ATFADD",
+        ATCAS => "ATCAS",
+        YIELD => "YIELD",
+        JOINTO => "JOINTO",
+        AWAITTO => "AWAITTO",
+        CANCEL => "CANCEL",
+        TCANCEL => "TCANCEL",
+        TRY => "TRY",
+        ENDTRY => "ENDTRY",
+        THROW => "THROW",
+        RSREAD => "RSREAD",
+        RSREADS => "RSREADS",
+        RSLIST => "RSLIST",
+        RSEXISTS => "RSEXISTS",
+        CALLNATIVE => "CALLNATIVE",
+        _ => "UNKNOWN",
+    }
+}
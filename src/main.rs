@@ -56,15 +56,29 @@ mod tests;
 #[cfg(feature = "default")]
 use argsdef::*;
 
-use crate::vm_internals::PrintMessage;
-#[cfg(feature = "green-threads")]
-use async_std::task;
+use crate::vm_internals::{println, PrintMessage};
 #[cfg(feature = "default")]
 use clap::Parser;
 use errdef::*;
+#[cfg(feature = "bundle")]
+use sqd_reader::sar_reader::bundle;
+#[cfg(feature = "sar")]
+use sqd_reader::sar_reader::encryption::EncryptionKey;
+#[cfg(feature = "sar")]
+use sqd_reader::sar_reader::signing::TrustedKeys;
+#[cfg(feature = "sar")]
+use sqd_reader::sar_reader::ArchiveReader;
+use sqd_reader::sqdbin_reader::linker::{link, search_path_loader};
 use sqd_reader::sqdbin_reader::FileReader;
+#[cfg(feature = "sar")]
+use std::io::{Read, Seek};
+use std::path::{Path, PathBuf};
 use std::process;
 use targetdef::*;
+#[cfg(feature = "sar")]
+use vm_internals::native::NativeFunctions;
+#[cfg(feature = "sar")]
+use vm_internals::resources::Resources;
 use vm_internals::VMStarter;
 
 #[cfg(feature = "default")]
@@ -102,6 +116,177 @@ fn version_args(args: &Args) {
     }
 }
 
+#[cfg(feature = "sar")]
+/// Reads the trusted keys from `directory`, or from the default folder when `None`.
+#[cfg(not(test))]
+fn trusted_keys(directory: &Option<String>) -> Result<TrustedKeys, String> {
+    match directory
+        .as_deref()
+        .map(PathBuf::from)
+        .or_else(TrustedKeys::default_dir)
+    {
+        Some(directory) => TrustedKeys::load(&directory),
+        None => Ok(TrustedKeys::new()),
+    }
+}
+
+#[cfg(feature = "sar")]
+/// Checks who signed the archive, exiting if it isn't trusted.
+#[cfg(not(test))]
+fn check_signer<R: Read + Seek>(
+    archive: &ArchiveReader<R>,
+    trusted_keys_dir: &Option<String>,
+    allow_unsigned: bool,
+) {
+    let checked = trusted_keys(trusted_keys_dir)
+        .and_then(|trusted| archive.check_signer(&trusted, allow_unsigned));
+
+    if let Err(err) = checked {
+        eprintln!("\x1B[31m{err}\x1b[0m");
+        process::exit(SIGNATURE_ERR);
+    }
+}
+
+#[cfg(feature = "sar")]
+/// Validates the archive, decoding its entry module, reading its resources
+/// and loading its native libraries, exiting if anything fails. Encrypted archives are decrypted with the key from `sar_key`.
+#[cfg(not(test))]
+fn sar_entry<R: Read + Seek>(
+    archive: Result<ArchiveReader<R>, String>,
+    sar_key: &Option<String>,
+    binver: bool,
+    force_newer_bin: bool,
+) -> (FileReader, Resources, NativeFunctions) {
+    let module = archive.and_then(|mut archive| {
+        if let Some(key) = EncryptionKey::load(sar_key.as_deref().map(Path::new))? {
+            archive.set_key(key);
+        }
+
+        // Like binaries, --force-newer-bin runs archives made for other VM versions.
+        if !force_newer_bin {
+            archive.manifest.check_vm()?;
+        }
+
+        // Like binaries, -v only shows the metadata of the entry module.
+        if binver {
+            let entry = archive.manifest.entry.clone();
+            FileReader::show_metadata(archive.read(&entry)?)?;
+            process::exit(0);
+        }
+
+        Ok((
+            archive.entry_module(force_newer_bin)?,
+            archive.resources()?,
+            archive.native_functions()?,
+        ))
+    });
+
+    match module {
+        Ok(module) => module,
+        Err(err) => {
+            eprintln!("\x1B[31m{err}\x1b[0m");
+            process::exit(SAR_ERR);
+        }
+    }
+}
+
+#[cfg(feature = "sar")]
+/// Runs the `pack`, `unpack`, `list` and `keygen` subcommands.
+#[cfg(not(test))]
+fn sar_command(command: &Commands, sar_key: &Option<String>) -> Result<(), String> {
+    use sqd_reader::sar_reader::signing::{self, key_id};
+    use sqd_reader::sar_reader::ArchiveWriter;
+    use std::path::Path;
+
+    match command {
+        Commands::Pack {
+            manifest,
+            output,
+            root,
+            compress,
+            sign,
+            encrypt,
+        } => {
+            let mut writer =
+                ArchiveWriter::from_dir(Path::new(manifest), root.as_deref().map(Path::new))?;
+
+            writer.set_compression(*compress);
+
+            if let Some(key) = sign {
+                writer.sign_with(signing::read_signing_key(Path::new(key))?);
+            }
+
+            if let Some(key) = encrypt {
+                writer.encrypt_with(EncryptionKey::read(Path::new(key))?);
+            }
+
+            let output = writer.create(output.clone())?;
+
+            println!("Packed {output}");
+        }
+        Commands::Unpack { sar, output } => {
+            let mut archive = ArchiveReader::open(sar.clone())?;
+
+            if let Some(key) = EncryptionKey::load(sar_key.as_deref().map(Path::new))? {
+                archive.set_key(key);
+            }
+
+            for path in archive.unpack(Path::new(output))? {
+                println!("{path}");
+            }
+        }
+        Commands::List { sar } => {
+            let mut archive = ArchiveReader::open(sar.clone())?;
+
+            let manifest = &archive.manifest;
+
+            println!(
+                "{} {} (VM {})",
+                manifest.name, manifest.version, manifest.vm
+            );
+            println!("Entry: {}", manifest.entry);
+
+            if !manifest.capabilities.is_empty() {
+                println!("Capabilities: {}", manifest.capabilities.join(", "));
+            }
+
+            if let Some(hash) = &manifest.hash {
+                println!("Hash: {hash}");
+            }
+
+            if let Some(encryption) = &manifest.encryption {
+                println!("Encrypted: {encryption}");
+            }
+
+            match &archive.signer {
+                Some(key) => println!("Signed by: {}", key_id(key)),
+                None => println!("Unsigned"),
+            }
+
+            for (name, size, compressed_size) in archive.entries()? {
+                println!("{size:>10} {compressed_size:>10}  {name}");
+            }
+        }
+        Commands::Keygen {
+            output,
+            encryption: true,
+        } => {
+            let key = EncryptionKey::write(output)?;
+
+            println!("Encryption key: {key} | Keep it private, it's needed to run the archives");
+        }
+        Commands::Keygen { output, .. } => {
+            let (secret, public) = signing::write_key_pair(output)?;
+
+            println!("Secret key: {secret} | Keep it private");
+            println!("Public key: {public} | Add it to the trusted keys of the VMs running your archives");
+        }
+        _ => unreachable!("only SAR subcommands are handled here"),
+    }
+
+    Ok(())
+}
+
 #[cfg(not(feature = "default"))]
 fn main() {
     dev_print!("Exiting...");
@@ -113,17 +298,91 @@ fn main() {
 /// File is read and converted to VM readble objects before the interpreter starts.
 #[cfg(not(test))]
 fn main() {
-    #[cfg(feature = "bundle")]
-    sqd_reader::sar_reader::archivereader::ArchiveReader::new();
-
     let mut fileread: Option<FileReader> = None;
     let mut bin: Option<String> = None;
     let mut sar: Option<String> = None;
+    #[cfg(feature = "sar")]
+    let mut resources = Resources::default();
+    #[cfg(feature = "sar")]
+    let mut natives = NativeFunctions::default();
 
     let args = Args::parse();
 
     version_args(&args);
 
+    if let Some(Commands::Heapdump { file, top }) = &args.command {
+        match vm_internals::heap_dump::summarize(file) {
+            Ok(mut summary) => {
+                summary.types.truncate(*top);
+                println!("{summary}");
+                process::exit(0);
+            }
+            Err(err) => {
+                eprintln!("\x1B[31m{}\x1b[0m", err);
+                process::exit(HEAP_DUMP_ERR);
+            }
+        }
+    }
+
+    if let Some(
+        command @ (Commands::Pack { .. }
+        | Commands::Unpack { .. }
+        | Commands::List { .. }
+        | Commands::Keygen { .. }),
+    ) = &args.command
+    {
+        #[cfg(not(feature = "sar"))]
+        {
+            let _ = command;
+            eprintln!("\x1B[31m'sar' feature not activated!\x1b[0m");
+            process::exit(FEATURE_ERR);
+        }
+
+        #[cfg(feature = "sar")]
+        match sar_command(command, &args.sar_key) {
+            Ok(()) => process::exit(0),
+            Err(err) => {
+                eprintln!("\x1B[31m{err}\x1b[0m");
+                process::exit(SAR_ERR);
+            }
+        }
+    }
+
+    if let Some(Commands::Bundle { sar, output, vm }) = &args.command {
+        #[cfg(not(feature = "bundle"))]
+        {
+            let _ = (sar, output, vm);
+            eprintln!("\x1B[31m'bundle' feature not activated!\x1b[0m");
+            process::exit(FEATURE_ERR);
+        }
+
+        // The bundled archive isn't checked when it runs, so it's checked here.
+        #[cfg(feature = "bundle")]
+        match ArchiveReader::open(sar.clone()) {
+            Ok(archive) => check_signer(&archive, &args.trusted_keys, args.allow_unsigned),
+            Err(err) => {
+                eprintln!("\x1B[31m{err}\x1b[0m");
+                process::exit(SAR_ERR);
+            }
+        }
+
+        #[cfg(feature = "bundle")]
+        match bundle::bundle_file(
+            sar.clone(),
+            vm.as_deref().map(std::path::Path::new),
+            std::path::Path::new(output),
+        ) {
+            Ok(()) => {
+                println!("Bundled {output}");
+                process::exit(0);
+            }
+            Err(err) => {
+                eprintln!("\x1B[31m{err}\x1b[0m");
+                process::exit(SAR_ERR);
+            }
+        }
+    }
+
     let maxmem = match string_to_bytesize(args.maxmem) {
         Ok(mem) => mem,
         Err(err) => {
@@ -140,17 +399,97 @@ fn main() {
     }
 
     if bin.is_none() && sar.is_none() {
-        eprintln!("\x1B[31mNo option specified!\x1b[0m");
-        process::exit(ARG_MISSING_ERR);
+        // Bundled executables run their archive when no file is given.
+        // It's trusted like the executable itself, so its signer isn't checked.
+        #[cfg(feature = "bundle")]
+        match bundle::embedded() {
+            Ok(Some(archive)) => {
+                let (module, archive_resources, archive_natives) = sar_entry(
+                    Ok(archive),
+                    &args.sar_key,
+                    args.binver,
+                    args.force_newer_bin,
+                );
+
+                fileread = Some(module);
+                resources = archive_resources;
+                natives = archive_natives;
+            }
+            Ok(None) => {}
+            Err(err) => {
+                eprintln!("\x1B[31m{err}\x1b[0m");
+                process::exit(SAR_ERR);
+            }
+        }
+
+        if fileread.is_none() {
+            eprintln!("\x1B[31mNo option specified!\x1b[0m");
+            process::exit(ARG_MISSING_ERR);
+        }
     }
 
     if let Some(bin) = bin {
-        fileread = Some(FileReader::new(bin, args.binver, args.force_newer_bin).unwrap());
-    } else if let Some(_sar) = sar {
+        let module = FileReader::new(bin.clone(), args.binver, args.force_newer_bin);
+
+        // Imported modules are searched next to the binary first.
+        let location = Path::new(&bin);
+        let name = location.file_name().unwrap_or_default().to_string_lossy();
+        let folder = location.parent().unwrap_or(Path::new("")).to_path_buf();
+
+        let search_path = std::iter::once(folder)
+            .chain(args.module_path.iter().map(PathBuf::from))
+            .collect();
+
+        match link(
+            &name,
+            module,
+            search_path_loader(search_path, args.force_newer_bin),
+        ) {
+            Ok(program) => fileread = Some(program),
+            Err(err) => {
+                eprintln!("\x1B[31m{err}\x1b[0m");
+                process::exit(LINK_ERR);
+            }
+        }
+    } else if let Some(sar) = sar {
+        #[cfg(not(feature = "sar"))]
+        {
+            let _ = sar;
+            eprintln!("\x1B[31m'sar' feature not activated!\x1b[0m");
+            process::exit(FEATURE_ERR);
+        }
+
+        #[cfg(feature = "sar")]
+        {
+            let archive = ArchiveReader::open(sar)
+                .inspect(|archive| check_signer(archive, &args.trusted_keys, args.allow_unsigned));
+
+            let (module, archive_resources, archive_natives) =
+                sar_entry(archive, &args.sar_key, args.binver, args.force_newer_bin);
+
+            fileread = Some(module);
+            resources = archive_resources;
+            natives = archive_natives;
+        }
     }
 
     let mut vm = VMStarter::new(maxmem, args.repo_size, args.stack_size);
 
+    vm.set_heap_dump_on_oom(args.heap_dump_on_oom);
+
+    #[cfg(feature = "sar")]
+    vm.set_resources(resources);
+
+    #[cfg(feature = "sar")]
+    vm.set_native_functions(natives);
+
+    if let Some(threads) = args.threads {
+        vm.set_thread_pool_size(threads);
+    }
+
+    #[cfg(feature = "green-threads")]
+    vm.set_task_scheduler(args.task_workers, args.task_slice);
+
     if let Some(fileread) = fileread {
         while vm.running {
             vm.interpreter(fileread.clone());
@@ -159,31 +498,48 @@ fn main() {
 
     #[cfg(feature = "green-threads")]
     if vm.task_handlers.len() > 0 {
-        task::block_on(async {
-            for task in vm.task_handlers {
-                match task.await {
-                    Ok(_) => {}
-                    Err(err) => {
-                        eprintln!("\x1B[41m{}\x1B[0m", err);
-                    }
-                };
+        for task in std::mem::take(&mut vm.task_handlers).into_iter().flatten() {
+            if let Some(Err(err)) = vm.wait(&task) {
+                eprintln!("\x1B[41m{}\x1B[0m", err);
             }
-        });
+        }
     }
 
     if vm.thread_handlers.len() > 0 {
-        for task in vm.thread_handlers {
-            match task.join().unwrap() {
-                Ok(_) => {}
-                Err(err) => {
-                    eprintln!("\x1B[41m{}\x1B[0m", err);
-                }
-            };
+        for task in std::mem::take(&mut vm.thread_handlers)
+            .into_iter()
+            .flatten()
+        {
+            if let Some(Err(err)) = vm.wait(&task) {
+                eprintln!("\x1B[41m{}\x1B[0m", err);
+            }
         }
     }
 
+    // A failure aborted the VM after the main thread ended.
+    if let Some(failure) = vm.supervisor.take_abort() {
+        vm.abort(failure);
+    }
+
+    // Failures propagated after the main thread ended, already printed when joined.
+    if vm.supervisor.take_failure().is_some() {
+        vm.exit_code = THREAD_ERR;
+    }
+
+    if args.heap_stats {
+        println(&vm.print_sender, vm.heap_stats());
+    }
+
+    if args.thread_stats {
+        println(&vm.print_sender, vm.thread_pool_stats());
+    }
+
     vm.print_sender.send(PrintMessage::End).unwrap();
     vm.print_handler.join().unwrap();
 
     dev_print!("Exiting...");
+
+    if vm.exit_code != 0 {
+        process::exit(vm.exit_code);
+    }
 }
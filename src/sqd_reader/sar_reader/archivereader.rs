@@ -1,38 +1,348 @@
-use crate::vm_internals::immediates::Immediates;
-use std::io::Read;
-
-/// Holds all the instructions and data that
-/// the VM will use in order to function properly.
-#[derive(Clone)]
-pub struct ArchiveReader {
-    /// Contains the instructions that the VM will utilize.
-    pub instructions: Vec<u8>,
-
-    /// Contains all the data the instructions will use to work.
-    pub data: Vec<Immediates>,
+use crate::sqd_reader::sar_reader::defs::*;
+use crate::sqd_reader::sar_reader::encryption::{EncryptionKey, KEY_VAR};
+use crate::sqd_reader::sar_reader::integrity::ContentHasher;
+use crate::sqd_reader::sar_reader::manifest::Manifest;
+use crate::sqd_reader::sar_reader::signing::{self, key_id, TrustedKeys};
+use crate::sqd_reader::sqdbin_reader::linker::link;
+use crate::sqd_reader::sqdbin_reader::FileReader;
+use crate::vm_internals::native::NativeFunctions;
+use crate::vm_internals::resources::Resources;
+use ed25519_dalek::VerifyingKey;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{Read, Seek};
+use std::path::Path;
+use zip::ZipArchive;
+
+/// ## Squid ARchive reader
+///
+/// A SAR is a zip archive holding the `.sqd` modules of a program,
+/// its resources and a `manifest.json` describing them (see `Manifest`).
+/// Signed archives also hold a `signature.json` (see `signing`),
+/// and the modules and resources of encrypted ones are decrypted when read (see `encryption`).
+pub struct ArchiveReader<R: Read + Seek> {
+    archive: ZipArchive<R>,
+
+    pub manifest: Manifest,
+
+    /// Key the archive was signed with, `None` if it's unsigned.
+    pub signer: Option<VerifyingKey>,
+
+    /// Key the modules and resources are decrypted with.
+    key: Option<EncryptionKey>,
+}
+
+impl ArchiveReader<File> {
+    /// Opens the archive at `archive_location`, adding the `.sar` extension if it's missing.
+    pub fn open(mut archive_location: String) -> Result<Self, String> {
+        if archive_location.ends_with('\\') || archive_location.ends_with('/') {
+            archive_location.pop();
+        }
+
+        if !archive_location.ends_with(SAR) {
+            archive_location.push_str(SAR);
+        }
+
+        let file = File::open(&archive_location)
+            .map_err(|err| format!("Couldn't open {archive_location}: {err}"))?;
+
+        Self::new(file)
+    }
 }
 
-#[cfg(feature = "bundle")]
-#[allow(dead_code)]
-const BYTECODE: &'static [u8] = include_bytes!("../../../examples/addnprint.sqdbin.zip");
+impl<R: Read + Seek> ArchiveReader<R> {
+    /// Reads the archive, validating its manifest and checking its integrity and signature.
+    ///
+    /// The VM version range isn't checked here, see `Manifest::check_vm`,
+    /// and neither is the signer, see `check_signer`.
+    pub fn new(reader: R) -> Result<Self, String> {
+        let mut archive =
+            ZipArchive::new(reader).map_err(|err| format!("Invalid Squid ARchive: {err}"))?;
+
+        let manifest_bytes = read_entry(&mut archive, MANIFEST)?;
+        let manifest = Manifest::parse(&manifest_bytes)?;
+
+        let files: Vec<&str> = archive.file_names().collect();
+        manifest.check_files(&files)?;
+
+        let signer = if files.contains(&SIGNATURE) {
+            // Without the content hash the signature would only cover the manifest.
+            if manifest.hash.is_none() {
+                return Err(format!(
+                    "Signed archive without a content hash in {MANIFEST}"
+                ));
+            }
+
+            let signature = read_entry(&mut archive, SIGNATURE)?;
+            Some(signing::verify(&signature, &manifest_bytes)?)
+        } else {
+            None
+        };
+
+        let mut reader = ArchiveReader {
+            archive,
+            manifest,
+            signer,
+            key: None,
+        };
+        reader.verify()?;
+
+        Ok(reader)
+    }
+
+    /// Sets the key the modules and resources are decrypted with, for encrypted archives.
+    pub fn set_key(&mut self, key: EncryptionKey) {
+        self.key = Some(key);
+    }
+
+    /// Checks that the archive was signed with one of the trusted keys.
+    /// Unsigned archives are only accepted with `allow_unsigned`.
+    pub fn check_signer(&self, trusted: &TrustedKeys, allow_unsigned: bool) -> Result<(), String> {
+        match &self.signer {
+            Some(key) if trusted.find(key).is_some() => Ok(()),
+            Some(key) => Err(format!(
+                "{} {} is signed by {}, which isn't a trusted key",
+                self.manifest.name,
+                self.manifest.version,
+                key_id(key)
+            )),
+            None if allow_unsigned => Ok(()),
+            None => Err(format!(
+                "{} {} isn't signed | Use --allow-unsigned to accept unsigned archives",
+                self.manifest.name, self.manifest.version
+            )),
+        }
+    }
+
+    /// Checks the CRC32 of every file, and the content hash if the manifest lists one.
+    fn verify(&mut self) -> Result<(), String> {
+        let mut names: Vec<String> = self
+            .archive
+            .file_names()
+            .filter(|name| *name != MANIFEST && *name != SIGNATURE)
+            .map(str::to_string)
+            .collect();
+        names.sort();
+
+        let mut hasher = ContentHasher::new();
+
+        for name in &names {
+            // Reading the whole file makes zip check its CRC32.
+            let bytes = read_entry(&mut self.archive, name)
+                .map_err(|err| format!("Corrupted or tampered archive: {err}"))?;
+
+            hasher.add(name, &bytes);
+        }
+
+        let found = hasher.finish();
+
+        match &self.manifest.hash {
+            Some(expected) if *expected != found => Err(format!(
+                "Tampered archive: its content hash is {found}, but {MANIFEST} lists {expected}"
+            )),
+            _ => Ok(()),
+        }
+    }
 
-#[cfg(feature = "bundle")]
-impl ArchiveReader {
-    pub fn new() {
-        let bytes = std::io::Cursor::new(BYTECODE);
+    /// Reads the bytes of a file inside the archive, decrypting it if the archive is encrypted.
+    pub fn read(&mut self, name: &str) -> Result<Vec<u8>, String> {
+        let bytes = read_entry(&mut self.archive, name)?;
 
-        let something = zip::ZipArchive::new(bytes).unwrap();
+        if self.manifest.encryption.is_none() || name == MANIFEST || name == SIGNATURE {
+            return Ok(bytes);
+        }
 
-        let mut idk = something.clone();
+        match &self.key {
+            Some(key) => key.decrypt(name, &bytes),
+            None => Err(format!(
+                "{name} is encrypted | Give the key with --sar-key or {KEY_VAR}"
+            )),
+        }
+    }
 
-        let mut idk2 = idk.by_name("addnprint.sqdbin").unwrap();
+    /// Decodes a module of the archive.
+    pub fn module(&mut self, name: &str, force_newer_ver: bool) -> Result<FileReader, String> {
+        let bytes = self.read(name)?;
 
-        let mut buf = Vec::new();
+        FileReader::from_bytes(bytes, force_newer_ver)
+    }
 
-        idk2.read_to_end(&mut buf).unwrap();
+    /// Decodes the entry module, the one the program starts at,
+    /// linked with the modules it imports.
+    pub fn entry_module(&mut self, force_newer_ver: bool) -> Result<FileReader, String> {
+        let entry = self.manifest.entry.clone();
+        let module = self.module(&entry, force_newer_ver)?;
 
-        println!("Buffer: {:X?}", buf);
+        link(&entry, module, |name| {
+            if !self.manifest.modules.iter().any(|module| module == name) {
+                return Err(format!("It isn't listed in {MANIFEST}"));
+            }
 
-        // println!("{something:?}");
+            self.module(name, force_newer_ver)
+        })
     }
+
+    /// Reads every resource listed in the manifest.
+    pub fn resources(&mut self) -> Result<Resources, String> {
+        let mut resources = BTreeMap::new();
+
+        for name in self.manifest.resources.clone() {
+            let bytes = self.read(&name)?;
+            resources.insert(name, bytes);
+        }
+
+        Ok(Resources::new(resources))
+    }
+
+    /// Loads the native libraries declared in the manifest, finding their functions.
+    ///
+    /// `dlopen` needs a file, so libraries inside the archive are written to a new folder
+    /// only the current user can use, removed once they're loaded.
+    #[cfg(feature = "native")]
+    pub fn native_functions(&mut self) -> Result<NativeFunctions, String> {
+        if self.manifest.native.is_empty() {
+            return Ok(NativeFunctions::default());
+        }
+
+        let folder = std::env::temp_dir().join(format!(
+            "squidvm-native-{}-{:016x}",
+            std::process::id(),
+            rand::random::<u64>()
+        ));
+
+        let natives = self
+            .manifest
+            .native
+            .clone()
+            .into_iter()
+            .map(|library| {
+                let location = if self.manifest.resources.contains(&library.library) {
+                    self.extract_library(&folder, &library.library)?
+                } else {
+                    library.library
+                };
+
+                Ok((location, library.functions))
+            })
+            .collect::<Result<Vec<_>, String>>()
+            .and_then(NativeFunctions::load);
+
+        // Loaded libraries stay mapped after their files are removed.
+        if folder.exists() {
+            let _ = fs::remove_dir_all(&folder);
+        }
+
+        natives
+    }
+
+    /// VMs without the `native` feature can't run archives declaring native libraries.
+    #[cfg(not(feature = "native"))]
+    pub fn native_functions(&mut self) -> Result<NativeFunctions, String> {
+        if self.manifest.native.is_empty() {
+            return Ok(NativeFunctions::default());
+        }
+
+        Err(format!(
+            "{} {} declares native libraries, but this VM wasn't compiled with the 'native' feature",
+            self.manifest.name, self.manifest.version
+        ))
+    }
+
+    /// Writes a library of the archive into `folder`, returning its path.
+    #[cfg(feature = "native")]
+    fn extract_library(&mut self, folder: &Path, name: &str) -> Result<String, String> {
+        let bytes = self.read(name)?;
+
+        if !folder.exists() {
+            let mut builder = fs::DirBuilder::new();
+
+            #[cfg(unix)]
+            std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+
+            builder
+                .create(folder)
+                .map_err(|err| format!("Couldn't create {}: {err}", folder.display()))?;
+        }
+
+        // Libraries in different folders of the archive can have the same file name.
+        let index = fs::read_dir(folder).map_or(0, |entries| entries.count());
+        let file_name = Path::new(name)
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy();
+        let path = folder.join(format!("{index}-{file_name}"));
+
+        fs::write(&path, bytes)
+            .map_err(|err| format!("Couldn't write {}: {err}", path.display()))?;
+
+        Ok(path.display().to_string())
+    }
+
+    /// Names, sizes and compressed sizes of the files inside the archive, in archive order.
+    pub fn entries(&mut self) -> Result<Vec<(String, u64, u64)>, String> {
+        (0..self.archive.len())
+            .map(|index| {
+                let file = self
+                    .archive
+                    .by_index(index)
+                    .map_err(|err| format!("Couldn't read the archive: {err}"))?;
+
+                Ok((file.name().to_string(), file.size(), file.compressed_size()))
+            })
+            .collect()
+    }
+
+    /// Extracts every file of the archive into `directory`, returning their paths.
+    /// The files of encrypted archives are decrypted.
+    ///
+    /// Files that would end up outside of `directory` (like `../file`) are refused.
+    pub fn unpack(&mut self, directory: &Path) -> Result<Vec<String>, String> {
+        let mut paths = Vec::with_capacity(self.archive.len());
+
+        for index in 0..self.archive.len() {
+            let file = self
+                .archive
+                .by_index(index)
+                .map_err(|err| format!("Couldn't read the archive: {err}"))?;
+
+            let path = match file.enclosed_name() {
+                Some(name) => directory.join(name),
+                None => return Err(format!("Unsafe path in the archive: {}", file.name())),
+            };
+
+            if file.is_dir() {
+                continue;
+            }
+
+            let name = file.name().to_string();
+            drop(file);
+
+            let bytes = self.read(&name)?;
+
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)
+                    .map_err(|err| format!("Couldn't create {}: {err}", parent.display()))?;
+            }
+
+            fs::write(&path, bytes)
+                .map_err(|err| format!("Couldn't write {}: {err}", path.display()))?;
+
+            paths.push(path.display().to_string());
+        }
+
+        Ok(paths)
+    }
+}
+
+fn read_entry<R: Read + Seek>(archive: &mut ZipArchive<R>, name: &str) -> Result<Vec<u8>, String> {
+    let mut file = archive
+        .by_name(name)
+        .map_err(|err| format!("Couldn't find {name} in the archive: {err}"))?;
+
+    let mut bytes = Vec::with_capacity(file.size() as usize);
+
+    file.read_to_end(&mut bytes)
+        .map_err(|err| format!("Couldn't read {name} from the archive: {err}"))?;
+
+    Ok(bytes)
 }
@@ -0,0 +1,208 @@
+use crate::sqd_reader::sar_reader::defs::*;
+use crate::sqd_reader::sar_reader::encryption::{EncryptionKey, ENCRYPTION};
+use crate::sqd_reader::sar_reader::integrity::ContentHasher;
+use crate::sqd_reader::sar_reader::manifest::Manifest;
+use crate::sqd_reader::sar_reader::signing::sign;
+use crate::sqd_reader::sqdbin_reader::FileReader;
+use ed25519_dalek::SigningKey;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{Seek, Write};
+use std::path::Path;
+use zip::write::FileOptions;
+use zip::{CompressionMethod, DateTime, ZipWriter};
+
+/// ## Squid ARchive writer
+///
+/// Packs the files listed in a manifest into a SAR.
+/// The same files always give the same archive: entries are sorted by name
+/// (the manifest and its signature first) and every timestamp and permission is fixed.
+/// The content hash of the files is added to the manifest,
+/// and the manifest is signed when a signing key is given.
+/// The modules and resources are encrypted when an encryption key is given.
+pub struct ArchiveWriter {
+    manifest_bytes: Vec<u8>,
+
+    pub manifest: Manifest,
+
+    /// Files of the archive, sorted by name.
+    files: BTreeMap<String, Vec<u8>>,
+
+    /// Stored by default, deflated with `set_compression`.
+    compression: CompressionMethod,
+
+    /// Key the manifest is signed with, unsigned when `None`.
+    signing_key: Option<SigningKey>,
+
+    /// Key the modules and resources are encrypted with, not encrypted when `None`.
+    encryption_key: Option<EncryptionKey>,
+}
+
+impl ArchiveWriter {
+    /// Starts an archive, validating its manifest.
+    pub fn new(manifest_bytes: Vec<u8>) -> Result<Self, String> {
+        let manifest = Manifest::parse(&manifest_bytes)?;
+
+        Ok(ArchiveWriter {
+            manifest_bytes,
+            manifest,
+            files: BTreeMap::new(),
+            compression: CompressionMethod::Stored,
+            signing_key: None,
+            encryption_key: None,
+        })
+    }
+
+    /// Deflates the entries of the archive if `compressed`, storing them as they are otherwise.
+    pub fn set_compression(&mut self, compressed: bool) {
+        self.compression = if compressed {
+            CompressionMethod::Deflated
+        } else {
+            CompressionMethod::Stored
+        };
+    }
+
+    /// Signs the archive with `key`, adding its `signature.json`.
+    pub fn sign_with(&mut self, key: SigningKey) {
+        self.signing_key = Some(key);
+    }
+
+    /// Encrypts the modules and resources of the archive with `key`.
+    pub fn encrypt_with(&mut self, key: EncryptionKey) {
+        self.encryption_key = Some(key);
+    }
+
+    /// Starts an archive from a manifest file, reading the files it lists
+    /// from `root` (the manifest's folder when `None`).
+    pub fn from_dir(manifest_location: &Path, root: Option<&Path>) -> Result<Self, String> {
+        let manifest_bytes = fs::read(manifest_location)
+            .map_err(|err| format!("Couldn't read {}: {err}", manifest_location.display()))?;
+
+        let mut writer = Self::new(manifest_bytes)?;
+
+        let root = match root {
+            Some(root) => root,
+            None => manifest_location.parent().unwrap_or(Path::new("")),
+        };
+
+        let names: Vec<String> = writer
+            .manifest
+            .modules
+            .iter()
+            .chain(&writer.manifest.resources)
+            .cloned()
+            .collect();
+
+        for name in names {
+            let path = root.join(&name);
+
+            let bytes = fs::read(&path)
+                .map_err(|err| format!("Couldn't read {}: {err}", path.display()))?;
+
+            writer.add(name, bytes)?;
+        }
+
+        Ok(writer)
+    }
+
+    /// Adds a file to the archive. Modules are decoded first, so broken ones aren't packed.
+    pub fn add(&mut self, name: String, bytes: Vec<u8>) -> Result<(), String> {
+        if name == MANIFEST || name == SIGNATURE {
+            return Err(format!("{name} can't be added as a file"));
+        }
+
+        if self.manifest.modules.contains(&name) {
+            // The VM version range is the manifest's business, not the module's.
+            FileReader::from_bytes(bytes.clone(), true)
+                .map_err(|err| format!("Invalid module {name}: {err}"))?;
+        } else if !self.manifest.resources.contains(&name) {
+            return Err(format!("{name} isn't listed in {MANIFEST}"));
+        }
+
+        self.files.insert(name, bytes);
+
+        Ok(())
+    }
+
+    /// Writes the archive to `archive_location`, adding the `.sar` extension if it's missing.
+    /// Returns where it was written.
+    pub fn create(self, mut archive_location: String) -> Result<String, String> {
+        if !archive_location.ends_with(SAR) {
+            archive_location.push_str(SAR);
+        }
+
+        let file = File::create(&archive_location)
+            .map_err(|err| format!("Couldn't create {archive_location}: {err}"))?;
+
+        self.write(file)?;
+
+        Ok(archive_location)
+    }
+
+    /// Writes the archive, checking that every file listed in the manifest was added.
+    pub fn write<W: Write + Seek>(self, writer: W) -> Result<W, String> {
+        let names: Vec<&str> = self.files.keys().map(String::as_str).collect();
+        self.manifest.check_files(&names)?;
+
+        let files = match &self.encryption_key {
+            Some(key) => self
+                .files
+                .iter()
+                .map(|(name, bytes)| Ok((name.clone(), key.encrypt(name, bytes)?)))
+                .collect::<Result<_, String>>()?,
+            None => self.files.clone(),
+        };
+
+        let manifest = self.hashed_manifest(&files)?;
+
+        let signature = match &self.signing_key {
+            Some(key) => Some(sign(key, &manifest)?),
+            None => None,
+        };
+
+        let options = FileOptions::default()
+            .compression_method(self.compression)
+            .last_modified_time(DateTime::default())
+            .unix_permissions(0o644);
+
+        let mut zip = ZipWriter::new(writer);
+
+        let entries = [(MANIFEST, &manifest)]
+            .into_iter()
+            .chain(signature.as_ref().map(|signature| (SIGNATURE, signature)))
+            .chain(files.iter().map(|(name, bytes)| (name.as_str(), bytes)));
+
+        for (name, bytes) in entries {
+            zip.start_file(name, options)
+                .and_then(|_| Ok(zip.write_all(bytes)?))
+                .map_err(|err| format!("Couldn't write {name} to the archive: {err}"))?;
+        }
+
+        zip.finish()
+            .map_err(|err| format!("Couldn't write the archive: {err}"))
+    }
+
+    /// Gives the manifest with the content hash of the files as they're stored,
+    /// and the encryption if they're encrypted, replacing the ones it had.
+    fn hashed_manifest(&self, files: &BTreeMap<String, Vec<u8>>) -> Result<Vec<u8>, String> {
+        let mut hasher = ContentHasher::new();
+
+        for (name, bytes) in files {
+            hasher.add(name, bytes);
+        }
+
+        let mut json: Value = serde_json::from_slice(&self.manifest_bytes)
+            .map_err(|err| format!("Invalid {MANIFEST}: {err}"))?;
+
+        json["hash"] = Value::String(hasher.finish());
+
+        if self.encryption_key.is_some() {
+            json["encryption"] = Value::String(ENCRYPTION.to_string());
+        } else if let Some(fields) = json.as_object_mut() {
+            fields.remove("encryption");
+        }
+
+        serde_json::to_vec_pretty(&json).map_err(|err| format!("Couldn't write {MANIFEST}: {err}"))
+    }
+}
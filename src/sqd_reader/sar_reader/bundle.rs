@@ -0,0 +1,120 @@
+//! Self-contained executables: a copy of the VM with a SAR appended to it.
+//!
+//! The archive is followed by a trailer, `<archive length (u64 LE)><BUNDLE_MAGIC>`,
+//! so the VM finds it by reading the end of its own executable.
+
+use crate::sqd_reader::sar_reader::defs::SAR;
+use crate::sqd_reader::sar_reader::ArchiveReader;
+use byteorder::{LittleEndian, ReadBytesExt};
+use std::fs;
+use std::io::{Cursor, Read, Seek, SeekFrom};
+use std::path::Path;
+
+/// Last bytes of a bundled executable.
+pub const BUNDLE_MAGIC: &[u8; 8] = b"SQDBUNDL";
+
+/// Size of the trailer after the archive.
+const TRAILER_SIZE: u64 = 8 + BUNDLE_MAGIC.len() as u64;
+
+/// Finds the archive appended to an executable, returning its offset and length.
+pub fn find_archive<R: Read + Seek>(executable: &mut R) -> Result<Option<(u64, u64)>, String> {
+    let error = |err: std::io::Error| format!("Couldn't read the bundled archive: {err}");
+
+    let size = executable.seek(SeekFrom::End(0)).map_err(error)?;
+
+    if size < TRAILER_SIZE {
+        return Ok(None);
+    }
+
+    executable
+        .seek(SeekFrom::Start(size - TRAILER_SIZE))
+        .map_err(error)?;
+
+    let length = executable.read_u64::<LittleEndian>().map_err(error)?;
+
+    let mut magic = [0u8; BUNDLE_MAGIC.len()];
+    executable.read_exact(&mut magic).map_err(error)?;
+
+    if &magic != BUNDLE_MAGIC {
+        return Ok(None);
+    }
+
+    match (size - TRAILER_SIZE).checked_sub(length) {
+        Some(offset) => Ok(Some((offset, length))),
+        None => Err("Bundled archive is bigger than the executable".to_string()),
+    }
+}
+
+/// Reads the archive bundled with the running VM, if there's one.
+pub fn embedded() -> Result<Option<ArchiveReader<Cursor<Vec<u8>>>>, String> {
+    let location =
+        std::env::current_exe().map_err(|err| format!("Couldn't find the VM executable: {err}"))?;
+
+    let mut executable = fs::File::open(&location)
+        .map_err(|err| format!("Couldn't open {}: {err}", location.display()))?;
+
+    let Some((offset, length)) = find_archive(&mut executable)? else {
+        return Ok(None);
+    };
+
+    let mut archive = vec![0u8; length as usize];
+
+    executable
+        .seek(SeekFrom::Start(offset))
+        .and_then(|_| executable.read_exact(&mut archive))
+        .map_err(|err| format!("Couldn't read the bundled archive: {err}"))?;
+
+    ArchiveReader::new(Cursor::new(archive)).map(Some)
+}
+
+/// Appends the archive and its trailer to an executable.
+/// An archive already bundled with it is replaced.
+pub fn bundle(mut executable: Vec<u8>, archive: &[u8]) -> Result<Vec<u8>, String> {
+    // Refuses archives the bundled VM couldn't run.
+    ArchiveReader::new(Cursor::new(archive))?;
+
+    if let Some((offset, _)) = find_archive(&mut Cursor::new(&executable))? {
+        executable.truncate(offset as usize);
+    }
+
+    executable.extend_from_slice(archive);
+    executable.extend_from_slice(&(archive.len() as u64).to_le_bytes());
+    executable.extend_from_slice(BUNDLE_MAGIC);
+
+    Ok(executable)
+}
+
+/// Writes a copy of the VM at `vm` (the running one when `None`) bundled with the SAR at `sar`,
+/// adding the `.sar` extension to it if it's missing.
+pub fn bundle_file(mut sar: String, vm: Option<&Path>, output: &Path) -> Result<(), String> {
+    if !sar.ends_with(SAR) {
+        sar.push_str(SAR);
+    }
+
+    let vm = match vm {
+        Some(vm) => vm.to_path_buf(),
+        None => std::env::current_exe()
+            .map_err(|err| format!("Couldn't find the VM executable: {err}"))?,
+    };
+
+    let read = |path: &Path| {
+        fs::read(path).map_err(|err| format!("Couldn't read {}: {err}", path.display()))
+    };
+
+    let executable = bundle(read(&vm)?, &read(Path::new(&sar))?)?;
+
+    fs::write(output, executable)
+        .map_err(|err| format!("Couldn't write {}: {err}", output.display()))?;
+
+    // Keeps the VM permissions, so the bundle stays executable.
+    let permissions = fs::metadata(&vm)
+        .map_err(|err| format!("Couldn't read {}: {err}", vm.display()))?
+        .permissions();
+
+    fs::set_permissions(output, permissions).map_err(|err| {
+        format!(
+            "Couldn't set the permissions of {}: {err}",
+            output.display()
+        )
+    })
+}
@@ -1 +1,8 @@
+/// .sar file extension.
+pub const SAR: &str = ".sar";
 
+/// Name of the manifest inside the archive.
+pub const MANIFEST: &str = "manifest.json";
+
+/// Name of the signature of the manifest inside the archive.
+pub const SIGNATURE: &str = "signature.json";
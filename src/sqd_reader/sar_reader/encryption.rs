@@ -0,0 +1,130 @@
+//! Encryption of the files of the Squid ARchives.
+//!
+//! Modules and resources can be encrypted with XChaCha20-Poly1305, so they can't be read
+//! or changed without the key. Each one is stored as `<nonce (24 bytes)><ciphertext and tag>`,
+//! with its name as associated data, so encrypted files can't be swapped either.
+//!
+//! The nonce is derived from the key, the name and the contents of the file,
+//! so packing the same files with the same key still gives the same archive.
+//! The manifest and its signature aren't encrypted, and the content hash covers the encrypted bytes,
+//! so archives are checked without needing the key.
+
+use crate::sqd_reader::sar_reader::defs::*;
+use crate::sqd_reader::sar_reader::signing::{key_bytes, write_secret};
+use chacha20poly1305::aead::{Aead, Payload};
+use chacha20poly1305::{KeyInit, XChaCha20Poly1305, XNonce};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::Path;
+
+/// Encryption written in the manifest of encrypted archives.
+pub const ENCRYPTION: &str = "xchacha20poly1305";
+
+/// Environment variable the key can be given with, as hex digits.
+pub const KEY_VAR: &str = "SQUIDVM_SAR_KEY";
+
+/// Extension of the encryption key files.
+pub const ENCRYPTION_KEY: &str = ".sarkey";
+
+/// Size of the nonce before every encrypted file.
+const NONCE_SIZE: usize = 24;
+
+/// ## Key the files of an archive are encrypted with.
+#[derive(Clone)]
+pub struct EncryptionKey([u8; 32]);
+
+impl EncryptionKey {
+    pub fn new(key: [u8; 32]) -> Self {
+        EncryptionKey(key)
+    }
+
+    /// Generates a new key from the OS random number generator.
+    pub fn generate() -> Self {
+        let mut key = [0u8; 32];
+        OsRng.fill_bytes(&mut key);
+
+        Self::new(key)
+    }
+
+    /// Reads a key written as 64 hex digits.
+    pub fn from_hex(digits: &str) -> Option<Self> {
+        key_bytes(digits).map(Self::new)
+    }
+
+    /// Reads a key file, as written by `write`.
+    pub fn read(path: &Path) -> Result<Self, String> {
+        let digits = fs::read_to_string(path)
+            .map_err(|err| format!("Couldn't read {}: {err}", path.display()))?;
+
+        Self::from_hex(&digits).ok_or_else(|| format!("{} isn't an encryption key", path.display()))
+    }
+
+    /// Reads the key from the file at `path` if there's one,
+    /// from the `SQUIDVM_SAR_KEY` environment variable otherwise.
+    pub fn load(path: Option<&Path>) -> Result<Option<Self>, String> {
+        if let Some(path) = path {
+            return Self::read(path).map(Some);
+        }
+
+        match std::env::var(KEY_VAR) {
+            Ok(digits) => Self::from_hex(&digits)
+                .map(Some)
+                .ok_or_else(|| format!("{KEY_VAR} isn't an encryption key")),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// Writes a new key to `<output>.sarkey`, returning its path.
+    pub fn write(output: &str) -> Result<String, String> {
+        let path = format!("{output}{ENCRYPTION_KEY}");
+
+        write_secret(&path, &Self::generate().0)?;
+
+        Ok(path)
+    }
+
+    /// Encrypts a file of the archive.
+    pub fn encrypt(&self, name: &str, bytes: &[u8]) -> Result<Vec<u8>, String> {
+        let digest = Sha256::new()
+            .chain_update(self.0)
+            .chain_update(name.as_bytes())
+            .chain_update([0])
+            .chain_update(bytes)
+            .finalize();
+
+        let nonce = XNonce::from_slice(&digest[..NONCE_SIZE]);
+
+        let payload = Payload {
+            msg: bytes,
+            aad: name.as_bytes(),
+        };
+
+        let ciphertext = XChaCha20Poly1305::new(&self.0.into())
+            .encrypt(nonce, payload)
+            .map_err(|_| format!("Couldn't encrypt {name}"))?;
+
+        Ok([nonce.as_slice(), &ciphertext].concat())
+    }
+
+    /// Decrypts a file of the archive, checking it wasn't changed.
+    pub fn decrypt(&self, name: &str, bytes: &[u8]) -> Result<Vec<u8>, String> {
+        let error = || format!("Couldn't decrypt {name}: wrong key or tampered {SAR} file");
+
+        if bytes.len() < NONCE_SIZE {
+            return Err(error());
+        }
+
+        let (nonce, ciphertext) = bytes.split_at(NONCE_SIZE);
+
+        let payload = Payload {
+            msg: ciphertext,
+            aad: name.as_bytes(),
+        };
+
+        XChaCha20Poly1305::new(&self.0.into())
+            .decrypt(XNonce::from_slice(nonce), payload)
+            .map_err(|_| error())
+    }
+}
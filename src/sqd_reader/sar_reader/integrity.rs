@@ -0,0 +1,59 @@
+//! Integrity checks of the Squid ARchives.
+//!
+//! Zip already stores a CRC32 for every entry, checked when the entry is read.
+//! The manifest can also list a content hash, a SHA-256 of every file but the manifest
+//! and its signature, so files changed, added or removed after packing are noticed too.
+
+use sha2::{Digest, Sha256};
+
+/// Prefix of the content hash in the manifest.
+pub const HASH_PREFIX: &str = "sha256:";
+
+/// ## Computes the content hash of an archive.
+///
+/// Files must be added sorted by name, leaving the manifest and its signature out.
+/// Each one is hashed as `<name><0x00><length (u64 LE)><bytes>`.
+#[derive(Default)]
+pub struct ContentHasher(Sha256);
+
+impl ContentHasher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, name: &str, bytes: &[u8]) {
+        self.0.update(name.as_bytes());
+        self.0.update([0]);
+        self.0.update((bytes.len() as u64).to_le_bytes());
+        self.0.update(bytes);
+    }
+
+    /// Gives the hash as written in the manifest, like `sha256:<64 hex digits>`.
+    pub fn finish(self) -> String {
+        format!("{HASH_PREFIX}{}", to_hex(&self.0.finalize()))
+    }
+}
+
+/// Checks that a hash written in the manifest looks like `sha256:<64 hex digits>`.
+pub fn is_valid_hash(hash: &str) -> bool {
+    hash.strip_prefix(HASH_PREFIX).is_some_and(|digits| {
+        digits.len() == 64 && digits.chars().all(|char| char.is_ascii_hexdigit())
+    })
+}
+
+/// Writes bytes as lowercase hex digits.
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// Reads hex digits as bytes, `None` if they aren't valid hex.
+pub fn from_hex(digits: &str) -> Option<Vec<u8>> {
+    if !digits.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..digits.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(digits.get(index..index + 2)?, 16).ok())
+        .collect()
+}
@@ -0,0 +1,478 @@
+use crate::sqd_reader::sar_reader::defs::*;
+use crate::sqd_reader::sar_reader::encryption::ENCRYPTION;
+use crate::sqd_reader::sar_reader::integrity::{is_valid_hash, HASH_PREFIX};
+use crate::sqd_reader::sqdbin_reader::defs::SQDBIN;
+use crate::vm_internals::native::{NativeSignature, NativeType};
+use serde_json::Value;
+use std::cmp::Ordering;
+use std::fmt::{Display, Formatter};
+
+/// Capabilities a SAR can declare, telling what the program is allowed to use.
+pub const KNOWN_CAPABILITIES: [&str; 4] = ["threads", "tasks", "window", "native"];
+
+/// ## Version of a package or of the VM.
+///
+/// Pre-release details (like `-alpha`) are kept for display, but ignored when comparing.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Version {
+    pub major: u64,
+    pub minor: u64,
+    pub patch: u64,
+    pub pre: Option<String>,
+}
+
+impl Version {
+    /// Parses a `major.minor.patch` version, optionally followed by `-details`.
+    pub fn parse(version: &str) -> Result<Self, String> {
+        let (numbers, pre) = match version.split_once('-') {
+            Some((numbers, pre)) => (numbers, Some(pre.to_string())),
+            None => (version, None),
+        };
+
+        let numbers: Vec<&str> = numbers.trim().split('.').collect();
+
+        let [major, minor, patch] = numbers[..] else {
+            return Err(format!("\"{version}\" isn't a major.minor.patch version"));
+        };
+
+        let number = |part: &str| {
+            part.parse::<u64>()
+                .map_err(|_| format!("\"{version}\" isn't a major.minor.patch version"))
+        };
+
+        Ok(Version {
+            major: number(major)?,
+            minor: number(minor)?,
+            patch: number(patch)?,
+            pre,
+        })
+    }
+
+    /// Version of this VM.
+    pub fn vm() -> Self {
+        Version::parse(env!("CARGO_PKG_VERSION")).expect("VM version should be valid")
+    }
+
+    fn numbers(&self) -> (u64, u64, u64) {
+        (self.major, self.minor, self.patch)
+    }
+}
+
+impl PartialOrd for Version {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        self.numbers().partial_cmp(&other.numbers())
+    }
+}
+
+impl Display for Version {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)?;
+
+        if let Some(pre) = &self.pre {
+            write!(f, "-{pre}")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// ## Range of versions, like `>=0.10.0, <0.11.0`.
+///
+/// Every comparison separated by commas must match.
+/// Supports `>=`, `>`, `<=`, `<`, `=` and `^` (same major, or same minor for `0.x` versions).
+#[derive(Debug, Clone, PartialEq)]
+pub struct VersionRange {
+    comparisons: Vec<(String, Version)>,
+}
+
+impl VersionRange {
+    pub fn parse(range: &str) -> Result<Self, String> {
+        let mut comparisons = Vec::new();
+
+        for comparison in range.split(',') {
+            let comparison = comparison.trim();
+
+            let operator_len = comparison
+                .find(|char: char| char.is_ascii_digit())
+                .ok_or_else(|| format!("\"{comparison}\" doesn't have a version"))?;
+
+            let (operator, version) = comparison.split_at(operator_len);
+
+            let operator = match operator.trim() {
+                "" => "=",
+                operator @ (">=" | ">" | "<=" | "<" | "=" | "^") => operator,
+                operator => return Err(format!("Unknown version operator \"{operator}\"")),
+            };
+
+            comparisons.push((operator.to_string(), Version::parse(version)?));
+        }
+
+        Ok(VersionRange { comparisons })
+    }
+
+    /// Checks if the version is inside the range.
+    pub fn matches(&self, version: &Version) -> bool {
+        self.comparisons
+            .iter()
+            .all(|(operator, other)| match operator.as_str() {
+                ">=" => version >= other,
+                ">" => version > other,
+                "<=" => version <= other,
+                "<" => version < other,
+                "^" if other.major == 0 => {
+                    version >= other && version.major == 0 && version.minor == other.minor
+                }
+                "^" => version >= other && version.major == other.major,
+                _ => version.numbers() == other.numbers(),
+            })
+    }
+}
+
+impl Display for VersionRange {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let comparisons: Vec<String> = self
+            .comparisons
+            .iter()
+            .map(|(operator, version)| format!("{operator}{version}"))
+            .collect();
+
+        write!(f, "{}", comparisons.join(", "))
+    }
+}
+
+/// ## Shared library declared in the manifest.
+///
+/// `library` is a file of the archive when it's listed in `resources`,
+/// a path or a name searched by the system loader (like `libm.so.6`) otherwise.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NativeLibrary {
+    pub library: String,
+    /// Functions called from the library, with their signatures.
+    pub functions: Vec<(String, NativeSignature)>,
+}
+
+/// Parses the `native` libraries of the manifest, adding the problems found to `problems`.
+fn parse_native(value: &Value, problems: &mut Vec<String>) -> Vec<NativeLibrary> {
+    let libraries = match value {
+        Value::Null => return Vec::new(),
+        Value::Array(libraries) => libraries,
+        _ => {
+            problems.push("\"native\" must be a list of libraries".to_string());
+            return Vec::new();
+        }
+    };
+
+    let mut names: Vec<String> = Vec::new();
+    let mut native = Vec::new();
+
+    for library in libraries {
+        let Some(location) = library["library"].as_str().filter(|name| !name.is_empty()) else {
+            problems.push("Native libraries must have a non-empty \"library\"".to_string());
+            continue;
+        };
+
+        let Some(functions) = library["functions"]
+            .as_array()
+            .filter(|list| !list.is_empty())
+        else {
+            problems.push(format!(
+                "Native library \"{location}\" must have a non-empty \"functions\" list"
+            ));
+            continue;
+        };
+
+        let mut declared = Vec::new();
+
+        for function in functions {
+            let Some(name) = function["name"].as_str().filter(|name| !name.is_empty()) else {
+                problems.push(format!(
+                    "Functions of \"{location}\" must have a non-empty \"name\""
+                ));
+                continue;
+            };
+
+            let mut kind = |value: &Value, param: bool| {
+                let kind = value.as_str().and_then(NativeType::parse);
+
+                match kind {
+                    Some(NativeType::Void) if param => {
+                        problems.push(format!("Native function \"{name}\" can't take void"));
+                        None
+                    }
+                    Some(kind) => Some(kind),
+                    None => {
+                        problems.push(format!(
+                            "Native function \"{name}\" has an unknown type {value} \
+                             (known: void, bool, i32, i64, u32, u64, f32, f64, str)"
+                        ));
+                        None
+                    }
+                }
+            };
+
+            let params: Vec<Option<NativeType>> = match &function["params"] {
+                Value::Null => Vec::new(),
+                Value::Array(params) => params.iter().map(|param| kind(param, true)).collect(),
+                _ => {
+                    problems.push(format!(
+                        "\"params\" of native function \"{name}\" must be a list"
+                    ));
+                    continue;
+                }
+            };
+
+            let returns = match &function["returns"] {
+                Value::Null => Some(NativeType::Void),
+                returns => kind(returns, false),
+            };
+
+            if names.iter().any(|declared| declared == name) {
+                problems.push(format!(
+                    "Native function \"{name}\" is declared more than once"
+                ));
+            }
+
+            names.push(name.to_string());
+
+            if let (Some(params), Some(returns)) = (params.into_iter().collect(), returns) {
+                declared.push((name.to_string(), NativeSignature { params, returns }));
+            }
+        }
+
+        native.push(NativeLibrary {
+            library: location.to_string(),
+            functions: declared,
+        });
+    }
+
+    native
+}
+
+/// ## Manifest of a Squid ARchive (`manifest.json`).
+///
+/// ```json
+/// {
+///     "name": "hello",
+///     "version": "1.0.0",
+///     "vm": ">=0.10.0, <0.11.0",
+///     "entry": "main.sqd",
+///     "modules": ["main.sqd"],
+///     "resources": ["greeting.txt"],
+///     "capabilities": ["threads", "native"],
+///     "native": [
+///         {
+///             "library": "libm.so.6",
+///             "functions": [{ "name": "cos", "params": ["f64"], "returns": "f64" }]
+///         }
+///     ],
+///     "hash": "sha256:...",
+///     "encryption": "xchacha20poly1305"
+/// }
+/// ```
+///
+/// `resources`, `capabilities` and `native` can be left out.
+/// `native` declares the shared libraries the program calls, and needs the `native` capability.
+/// `hash` is the content hash of the archive (see `integrity`), added when packing.
+/// `encryption` is only there when the modules and resources are encrypted (see `encryption`).
+#[derive(Debug, Clone, PartialEq)]
+pub struct Manifest {
+    pub name: String,
+    pub version: Version,
+    /// VM versions able to run the archive.
+    pub vm: VersionRange,
+    /// Module the program starts at.
+    pub entry: String,
+    pub modules: Vec<String>,
+    /// Files the program can read at runtime.
+    pub resources: Vec<String>,
+    pub capabilities: Vec<String>,
+    /// Shared libraries the program calls with `CALLNATIVE`.
+    pub native: Vec<NativeLibrary>,
+    /// Content hash of the other files of the archive.
+    pub hash: Option<String>,
+    /// How the modules and resources are encrypted, `None` if they aren't.
+    pub encryption: Option<String>,
+}
+
+impl Manifest {
+    /// Parses the manifest, listing every problem found in the error.
+    pub fn parse(bytes: &[u8]) -> Result<Self, String> {
+        let json: Value =
+            serde_json::from_slice(bytes).map_err(|err| format!("Invalid {MANIFEST}: {err}"))?;
+
+        let mut problems = Vec::new();
+
+        let mut string = |key: &str| match &json[key] {
+            Value::String(value) if !value.is_empty() => Some(value.clone()),
+            Value::Null => {
+                problems.push(format!("\"{key}\" is missing"));
+                None
+            }
+            _ => {
+                problems.push(format!("\"{key}\" must be a non-empty string"));
+                None
+            }
+        };
+
+        let name = string("name");
+        let version = string("version");
+        let vm = string("vm");
+        let entry = string("entry");
+
+        let mut list = |key: &str, required: bool| match &json[key] {
+            Value::Array(values) if !values.is_empty() || !required => {
+                let strings: Vec<String> = values
+                    .iter()
+                    .filter_map(|value| value.as_str().map(str::to_string))
+                    .collect();
+
+                if strings.len() != values.len() {
+                    problems.push(format!("\"{key}\" must only have strings"));
+                }
+
+                strings
+            }
+            Value::Null if !required => Vec::new(),
+            Value::Null => {
+                problems.push(format!("\"{key}\" is missing"));
+                Vec::new()
+            }
+            _ => {
+                problems.push(format!("\"{key}\" must be a non-empty list"));
+                Vec::new()
+            }
+        };
+
+        let modules = list("modules", true);
+        let resources = list("resources", false);
+        let capabilities = list("capabilities", false);
+
+        let native = parse_native(&json["native"], &mut problems);
+
+        if !native.is_empty() && !capabilities.iter().any(|capability| capability == "native") {
+            problems.push("\"native\" needs the \"native\" capability".to_string());
+        }
+
+        let hash = match &json["hash"] {
+            Value::Null => None,
+            Value::String(hash) if is_valid_hash(hash) => Some(hash.clone()),
+            _ => {
+                problems.push(format!(
+                    "\"hash\" must be \"{HASH_PREFIX}\" followed by 64 hex digits"
+                ));
+                None
+            }
+        };
+
+        let encryption = match &json["encryption"] {
+            Value::Null => None,
+            Value::String(encryption) if encryption == ENCRYPTION => Some(encryption.clone()),
+            _ => {
+                problems.push(format!("\"encryption\" must be \"{ENCRYPTION}\""));
+                None
+            }
+        };
+
+        if let Some(name) = &name {
+            let valid = |char: char| char.is_ascii_alphanumeric() || "-_.".contains(char);
+
+            if !name.chars().all(valid) {
+                problems.push(format!(
+                    "\"name\" can only have letters, digits, '-', '_' and '.' (found \"{name}\")"
+                ));
+            }
+        }
+
+        let version = version.and_then(|version| {
+            Version::parse(&version)
+                .map_err(|err| problems.push(format!("\"version\": {err}")))
+                .ok()
+        });
+
+        let vm = vm.and_then(|vm| {
+            VersionRange::parse(&vm)
+                .map_err(|err| problems.push(format!("\"vm\": {err}")))
+                .ok()
+        });
+
+        if let Some(entry) = &entry {
+            if !modules.contains(entry) {
+                problems.push(format!("Entry module \"{entry}\" isn't in \"modules\""));
+            }
+        }
+
+        for (index, module) in modules.iter().enumerate() {
+            if !module.ends_with(SQDBIN) {
+                problems.push(format!("Module \"{module}\" must be a {SQDBIN} file"));
+            }
+
+            if modules[..index].contains(module) {
+                problems.push(format!("Module \"{module}\" is listed more than once"));
+            }
+        }
+
+        for capability in &capabilities {
+            if !KNOWN_CAPABILITIES.contains(&capability.as_str()) {
+                problems.push(format!(
+                    "Unknown capability \"{capability}\" (known: {})",
+                    KNOWN_CAPABILITIES.join(", ")
+                ));
+            }
+        }
+
+        match (name, version, vm, entry) {
+            (Some(name), Some(version), Some(vm), Some(entry)) if problems.is_empty() => {
+                Ok(Manifest {
+                    name,
+                    version,
+                    vm,
+                    entry,
+                    modules,
+                    resources,
+                    capabilities,
+                    native,
+                    hash,
+                    encryption,
+                })
+            }
+            _ => Err(format!(
+                "Invalid {MANIFEST}:\n\t- {}",
+                problems.join("\n\t- ")
+            )),
+        }
+    }
+
+    /// Checks that every module and resource is inside the archive.
+    pub fn check_files(&self, files: &[&str]) -> Result<(), String> {
+        let missing: Vec<String> = self
+            .modules
+            .iter()
+            .chain(&self.resources)
+            .filter(|name| !files.contains(&name.as_str()))
+            .map(|name| format!("\"{name}\""))
+            .collect();
+
+        if missing.is_empty() {
+            return Ok(());
+        }
+
+        Err(format!(
+            "Files listed in {MANIFEST} aren't in the archive: {}",
+            missing.join(", ")
+        ))
+    }
+
+    /// Checks that this VM is inside the VM version range.
+    pub fn check_vm(&self) -> Result<(), String> {
+        let vm = Version::vm();
+
+        if self.vm.matches(&vm) {
+            return Ok(());
+        }
+
+        Err(format!(
+            "{} {} requires a VM version matching \"{}\", but this VM is {vm}",
+            self.name, self.version, self.vm
+        ))
+    }
+}
@@ -1,7 +1,37 @@
-/// Module used for reading the files and searching for a specific format.
+/// Module used for reading the Squid ARchives and the modules inside them.
+#[cfg(feature = "sar")]
 pub mod archivereader;
 
+/// Module used for packing files into Squid ARchives.
+#[cfg(feature = "sar")]
+pub mod archivewriter;
+
+/// Encryption of the modules and resources of the archives.
+#[cfg(feature = "sar")]
+pub mod encryption;
+
+/// Executables with a Squid ARchive appended to them.
+#[cfg(feature = "bundle")]
+pub mod bundle;
+
+/// Integrity checks of the archives.
+#[cfg(feature = "sar")]
+pub mod integrity;
+
+/// Manifest of the archives and its validation.
+#[cfg(feature = "sar")]
+pub mod manifest;
+
+/// Signing and signature verification of the archives.
+#[cfg(feature = "sar")]
+pub mod signing;
+
 /// All the definitions the reader needs.
+#[cfg(feature = "sar")]
 mod defs;
 
+#[cfg(feature = "sar")]
 pub use archivereader::*;
+
+#[cfg(feature = "sar")]
+pub use archivewriter::*;
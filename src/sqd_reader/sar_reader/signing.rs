@@ -0,0 +1,210 @@
+//! Signatures of the Squid ARchives.
+//!
+//! A signed archive has a `signature.json` next to its manifest, holding the public key of the signer
+//! and an Ed25519 signature of the manifest's exact bytes. The manifest lists the content hash
+//! of every other file, so the signature covers the whole archive.
+//!
+//! Keys are kept as hex text files: `<name>.key` for the secret key and `<name>.pub` for the public one.
+//! Archives are trusted when signed by a key whose `.pub` file is in the trusted keys folder,
+//! so nothing needs the network.
+
+use crate::sqd_reader::sar_reader::defs::*;
+use crate::sqd_reader::sar_reader::integrity::{from_hex, to_hex};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde_json::{json, Value};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Extension of the secret key files.
+pub const SECRET_KEY: &str = ".key";
+
+/// Extension of the public key files.
+pub const PUBLIC_KEY: &str = ".pub";
+
+/// Folder the trusted keys are read from when none is given, inside the user's home.
+const TRUSTED_KEYS_DIR: &str = ".squidvm/trusted-keys";
+
+/// Generates a new signing key from the OS random number generator.
+pub fn generate_key() -> SigningKey {
+    let mut seed = [0u8; 32];
+    OsRng.fill_bytes(&mut seed);
+
+    SigningKey::from_bytes(&seed)
+}
+
+/// Writes a new key pair to `<output>.key` and `<output>.pub`, returning their paths.
+///
+/// The secret key is only readable by its owner on Unix.
+pub fn write_key_pair(output: &str) -> Result<(String, String), String> {
+    let key = generate_key();
+
+    let secret = format!("{output}{SECRET_KEY}");
+    let public = format!("{output}{PUBLIC_KEY}");
+
+    for path in [&secret, &public] {
+        if Path::new(path).exists() {
+            return Err(format!("{path} already exists"));
+        }
+    }
+
+    write_secret(&secret, &key.to_bytes())?;
+
+    fs::write(&public, format!("{}\n", key_id(&key.verifying_key())))
+        .map_err(|err| format!("Couldn't write {public}: {err}"))?;
+
+    Ok((secret, public))
+}
+
+/// Writes a secret key as hex digits, only readable by its owner on Unix.
+/// Files already there aren't replaced.
+pub(super) fn write_secret(path: &str, key: &[u8]) -> Result<(), String> {
+    if Path::new(path).exists() {
+        return Err(format!("{path} already exists"));
+    }
+
+    fs::write(path, format!("{}\n", to_hex(key)))
+        .map_err(|err| format!("Couldn't write {path}: {err}"))?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600))
+            .map_err(|err| format!("Couldn't set the permissions of {path}: {err}"))?;
+    }
+
+    Ok(())
+}
+
+/// Hex digits of a public key, the way it's shown and written in `.pub` files.
+pub fn key_id(key: &VerifyingKey) -> String {
+    to_hex(key.as_bytes())
+}
+
+/// Reads 32 bytes written as hex digits, from a key file or `signature.json`.
+pub(super) fn key_bytes(digits: &str) -> Option<[u8; 32]> {
+    from_hex(digits.trim())?.try_into().ok()
+}
+
+fn parse_public_key(digits: &str) -> Option<VerifyingKey> {
+    VerifyingKey::from_bytes(&key_bytes(digits)?).ok()
+}
+
+/// Reads a secret key file, as written by `write_key_pair`.
+pub fn read_signing_key(path: &Path) -> Result<SigningKey, String> {
+    let digits = fs::read_to_string(path)
+        .map_err(|err| format!("Couldn't read {}: {err}", path.display()))?;
+
+    key_bytes(&digits)
+        .map(|bytes| SigningKey::from_bytes(&bytes))
+        .ok_or_else(|| format!("{} isn't a secret key", path.display()))
+}
+
+/// Reads a public key file, as written by `write_key_pair`.
+pub fn read_verifying_key(path: &Path) -> Result<VerifyingKey, String> {
+    let digits = fs::read_to_string(path)
+        .map_err(|err| format!("Couldn't read {}: {err}", path.display()))?;
+
+    parse_public_key(&digits).ok_or_else(|| format!("{} isn't a public key", path.display()))
+}
+
+/// Signs the bytes of a manifest, giving the contents of `signature.json`.
+pub fn sign(key: &SigningKey, manifest_bytes: &[u8]) -> Result<Vec<u8>, String> {
+    let signature = json!({
+        "key": key_id(&key.verifying_key()),
+        "signature": to_hex(&key.sign(manifest_bytes).to_bytes()),
+    });
+
+    serde_json::to_vec_pretty(&signature)
+        .map_err(|err| format!("Couldn't write {SIGNATURE}: {err}"))
+}
+
+/// Checks `signature.json` against the bytes of the manifest, returning the key that signed it.
+pub fn verify(signature_bytes: &[u8], manifest_bytes: &[u8]) -> Result<VerifyingKey, String> {
+    let json: Value = serde_json::from_slice(signature_bytes)
+        .map_err(|err| format!("Invalid {SIGNATURE}: {err}"))?;
+
+    let field = |name: &str| {
+        json[name]
+            .as_str()
+            .ok_or_else(|| format!("Invalid {SIGNATURE}: \"{name}\" must be a string"))
+    };
+
+    let key = parse_public_key(field("key")?)
+        .ok_or_else(|| format!("Invalid {SIGNATURE}: \"key\" isn't a public key"))?;
+
+    let signature = from_hex(field("signature")?)
+        .and_then(|bytes| Signature::from_slice(&bytes).ok())
+        .ok_or_else(|| format!("Invalid {SIGNATURE}: \"signature\" isn't a signature"))?;
+
+    key.verify(manifest_bytes, &signature).map_err(|_| {
+        format!(
+            "Tampered archive: the signature by {} doesn't match {MANIFEST}",
+            key_id(&key)
+        )
+    })?;
+
+    Ok(key)
+}
+
+/// ## Public keys archives may be signed with.
+///
+/// Each key is named after the `.pub` file it was read from.
+#[derive(Debug, Default)]
+pub struct TrustedKeys(Vec<(String, VerifyingKey)>);
+
+impl TrustedKeys {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reads every `.pub` file of `directory`. A missing folder has no keys.
+    pub fn load(directory: &Path) -> Result<Self, String> {
+        let mut keys = TrustedKeys::new();
+
+        if !directory.exists() {
+            return Ok(keys);
+        }
+
+        let entries = fs::read_dir(directory)
+            .map_err(|err| format!("Couldn't read {}: {err}", directory.display()))?;
+
+        let mut paths: Vec<PathBuf> = entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.to_string_lossy().ends_with(PUBLIC_KEY))
+            .collect();
+        paths.sort();
+
+        for path in paths {
+            let name = path
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().to_string())
+                .unwrap_or_default();
+
+            keys.add(name, read_verifying_key(&path)?);
+        }
+
+        Ok(keys)
+    }
+
+    /// Folder the trusted keys are read from when none is given: `.squidvm/trusted-keys` in the user's home.
+    pub fn default_dir() -> Option<PathBuf> {
+        std::env::var_os("HOME")
+            .or_else(|| std::env::var_os("USERPROFILE"))
+            .map(|home| PathBuf::from(home).join(TRUSTED_KEYS_DIR))
+    }
+
+    pub fn add(&mut self, name: String, key: VerifyingKey) {
+        self.0.push((name, key));
+    }
+
+    /// Name of a trusted key, `None` if it isn't trusted.
+    pub fn find(&self, key: &VerifyingKey) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(_, trusted)| trusted == key)
+            .map(|(name, _)| name.as_str())
+    }
+}
@@ -77,6 +77,14 @@ pub const FNOBJSTART: u8 = 0x3A;
 /// Defines the FnObjEnd (FnObj) for reading a Function-Object from a file;
 pub const FNOBJEND: u8 = 0x3B;
 
+// Linking
+
+/// Defines an Export (name, function index) entry of the module's export table.
+pub const EXPORT: u8 = 0x5A;
+
+/// Defines an Import (module, name) entry of the module's import table.
+pub const IMPORT: u8 = 0x5B;
+
 // Compiler markers
 
 /// Defines the CanJitStart (usize) marker for knowing portions of bytecode that can be JITtted.
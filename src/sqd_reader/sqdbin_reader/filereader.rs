@@ -1,13 +1,14 @@
 use crate::errdef::*;
 use crate::instructiondefs::*;
 use crate::sqd_reader::sqdbin_reader::defs::*;
+use crate::vm_internals::heap::Function;
 use crate::vm_internals::immediates::Immediates::{
     self, Boolean, Float, Integer, MutStr, Null, UInteger,
 };
 use byteorder::{LittleEndian, ReadBytesExt};
 use std::fmt::Display;
 use std::fs::File;
-use std::io::{Error, Read, Seek, SeekFrom};
+use std::io::{Cursor, Error, Read, Seek, SeekFrom};
 use std::{fs, process};
 
 /// Holds all the instructions and data that
@@ -19,40 +20,80 @@ pub struct FileReader {
 
     /// Contains all the data the instructions will use to work.
     pub data: Vec<Immediates>,
+
+    /// Contains the function objects, in the order they appear in the file.
+    pub functions: Vec<Function>,
+
+    /// Named entry points of the module, with the index of the function they name.
+    pub exports: Vec<(String, u64)>,
+
+    /// Functions used from other modules, numbered after the module's own functions.
+    pub imports: Vec<Import>,
+
+    /// Address the program starts at, 0 unless it was linked with modules having initializers.
+    pub start: usize,
 }
 
-/// Converts vector of bytes into UTF8 compatible strings.
-fn to_string(string: Vec<u8>) -> String {
-    match String::from_utf8(string) {
-        Ok(uf8string) => uf8string,
-        Err(e) => {
-            panic!("INVALID FILE DATA! {}", e);
-        }
-    }
+/// ## Function imported from another module.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Import {
+    /// Name of the module exporting the function.
+    pub module: String,
+    /// Name it is exported with.
+    pub name: String,
+}
+
+/// Error decoding a binary, with the code the CLI exits with.
+struct DecodeError {
+    message: String,
+    exit_code: i32,
 }
 
-fn handle_error<V, E: Display>(result: Result<V, E>, exit_code: i32) -> V {
-    match result {
-        Ok(value) => value,
-        Err(err) => {
-            eprintln!("\x1B[41m{}\x1b[0m", err.to_string());
-            process::exit(exit_code);
+impl DecodeError {
+    fn new(message: impl Display, exit_code: i32) -> Self {
+        DecodeError {
+            message: message.to_string(),
+            exit_code,
         }
     }
 }
 
-fn to_boolean(value: u8) -> Result<Immediates, &'static str> {
-    let bool = if value == 1 {
-        true
-    } else if value == 0 {
-        false
-    } else {
+/// Converts vector of bytes into UTF8 compatible strings.
+fn to_string(string: Vec<u8>) -> Result<String, DecodeError> {
+    String::from_utf8(string)
+        .map_err(|err| DecodeError::new(format!("INVALID FILE DATA! {err}"), FILE_DATA_ERR))
+}
+
+fn handle_error<V, E: Display>(result: Result<V, E>, exit_code: i32) -> Result<V, DecodeError> {
+    result.map_err(|err| DecodeError::new(err, exit_code))
+}
+
+/// Reads a name of `length` bytes, used by the export and import tables.
+fn read_name<R: Read>(file: &mut R, length: u8) -> Result<String, DecodeError> {
+    let mut name = vec![0u8; length as usize];
+    handle_error(file.read_exact(&mut name), FILE_DATA_ERR)?;
+    to_string(name)
+}
+
+/// Checks the `NTHRD`/`NTASK` flags (tracked bit and supervisor policy).
+fn to_spawn_flags(value: u8) -> Result<Immediates, &'static str> {
+    if value > 0b101 {
         return Err("INVALID FILE DATA!");
-    };
-    Ok(Boolean(bool))
+    }
+    Ok(UInteger(value as u64))
+}
+
+/// Reads the u64 operand that follows a one-byte instruction (addresses, indexes and counts).
+fn get_operand<R: Read + Seek>(file: &mut R, offset: u64) -> Result<u64, DecodeError> {
+    handle_error(file.seek(SeekFrom::Start(offset + 1)), FILE_DATA_ERR)?;
+    handle_error(file.read_u64::<LittleEndian>(), FILE_DATA_ERR)
 }
 
-fn get_data(data_type: u8, mut file: &File, mut buffer: [u8; 2]) -> (Immediates, u64) {
+fn get_data<R: Read>(
+    data_type: u8,
+    file: &mut R,
+    mut buffer: [u8; 2],
+) -> Result<(Immediates, u64), DecodeError> {
     let mut offset = 0;
 
     let data;
@@ -65,111 +106,117 @@ fn get_data(data_type: u8, mut file: &File, mut buffer: [u8; 2]) -> (Immediates,
         }
         BOOL => {
             // Boolean type
-            handle_error(file.read_exact(&mut buffer), FILE_DATA_ERR);
+            handle_error(file.read_exact(&mut buffer), FILE_DATA_ERR)?;
             data = Boolean(if buffer[1] == 1 {
                 true
             } else if buffer[1] == 0 {
                 false
             } else {
-                panic!("INVALID FILE DATA!");
+                return Err(DecodeError::new("INVALID FILE DATA!", FILE_DATA_ERR));
             });
             offset += 2;
         }
         INTEGER => {
             // Integer type
             offset += 2;
-            data = Integer(handle_error(file.read_i64::<LittleEndian>(), FILE_DATA_ERR));
+            data = Integer(handle_error(
+                file.read_i64::<LittleEndian>(),
+                FILE_DATA_ERR,
+            )?);
             offset += 8;
         }
         UINTEGER => {
             // Unsigned Integer type
             offset += 2;
-            data = UInteger(handle_error(file.read_u64::<LittleEndian>(), FILE_DATA_ERR));
+            data = UInteger(handle_error(
+                file.read_u64::<LittleEndian>(),
+                FILE_DATA_ERR,
+            )?);
             offset += 8;
         }
         FLOAT => {
             // Float type
             offset += 2;
-            data = Float(handle_error(file.read_f64::<LittleEndian>(), FILE_DATA_ERR));
+            data = Float(handle_error(
+                file.read_f64::<LittleEndian>(),
+                FILE_DATA_ERR,
+            )?);
             offset += 8;
         }
         STRING8 => {
             // 8bit string type
             offset += 2;
-            let int = handle_error(file.read_u8(), FILE_DATA_ERR);
+            let int = handle_error(file.read_u8(), FILE_DATA_ERR)?;
             let mut counter = 0;
             offset += 1;
             let mut byte_string: Vec<u8> = Vec::with_capacity(255);
             while counter < int {
-                byte_string.push(handle_error(file.read_u8(), FILE_DATA_ERR));
+                byte_string.push(handle_error(file.read_u8(), FILE_DATA_ERR)?);
                 offset += 1;
                 counter += 1;
             }
 
-            data = MutStr(to_string(byte_string));
+            data = MutStr(to_string(byte_string)?);
         }
         STRING16 => {
             // 16bit string type
             offset += 2;
-            let int = handle_error(file.read_u16::<LittleEndian>(), FILE_DATA_ERR);
+            let int = handle_error(file.read_u16::<LittleEndian>(), FILE_DATA_ERR)?;
             let mut counter = 0;
             offset += 2;
             let mut byte_string: Vec<u8> = Vec::with_capacity(2usize.pow(16));
             while counter < int {
-                byte_string.push(handle_error(file.read_u8(), FILE_DATA_ERR));
+                byte_string.push(handle_error(file.read_u8(), FILE_DATA_ERR)?);
                 offset += 1;
                 counter += 1;
             }
-            data = MutStr(to_string(byte_string));
+            data = MutStr(to_string(byte_string)?);
         }
         STRING32 => {
             // 32bit string type
             offset += 2;
-            let int = handle_error(file.read_u32::<LittleEndian>(), FILE_DATA_ERR);
+            let int = handle_error(file.read_u32::<LittleEndian>(), FILE_DATA_ERR)?;
             let mut counter = 0;
             offset += 4;
             let mut byte_string: Vec<u8> = Vec::with_capacity(2usize.pow(32));
             while counter < int {
-                byte_string.push(handle_error(file.read_u8(), FILE_DATA_ERR));
+                byte_string.push(handle_error(file.read_u8(), FILE_DATA_ERR)?);
                 offset += 1;
                 counter += 1;
             }
-            data = MutStr(to_string(byte_string));
+            data = MutStr(to_string(byte_string)?);
         }
         STRING64 => {
             // 64bit string type
             offset += 2;
-            let int = handle_error(file.read_u64::<LittleEndian>(), FILE_DATA_ERR);
+            let int = handle_error(file.read_u64::<LittleEndian>(), FILE_DATA_ERR)?;
             let mut counter = 0;
             offset += 8;
             let mut byte_string: Vec<u8> = Vec::with_capacity(2usize.pow(64));
             while counter < int {
-                byte_string.push(handle_error(file.read_u8(), FILE_DATA_ERR));
+                byte_string.push(handle_error(file.read_u8(), FILE_DATA_ERR)?);
                 offset += 1;
                 counter += 1;
             }
-            data = MutStr(to_string(byte_string));
+            data = MutStr(to_string(byte_string)?);
         }
         STRING128 => {
             // 128bit string type
             offset += 2;
-            let int = handle_error(file.read_u128::<LittleEndian>(), FILE_DATA_ERR);
+            let int = handle_error(file.read_u128::<LittleEndian>(), FILE_DATA_ERR)?;
             let mut counter = 0;
             offset += 16;
             let mut byte_string: Vec<u8> = Vec::with_capacity(2usize.pow(128));
             while counter < int {
-                byte_string.push(handle_error(file.read_u8(), FILE_DATA_ERR));
+                byte_string.push(handle_error(file.read_u8(), FILE_DATA_ERR)?);
                 offset += 1;
                 counter += 1;
             }
-            data = MutStr(to_string(byte_string));
-        }
-        _ => {
-            eprintln!("\x1B[31mInvalid file data!\x1b[0m");
-            process::exit(FILE_DATA_ERR);
+            data = MutStr(to_string(byte_string)?);
         }
+        _ => return Err(DecodeError::new("Invalid file data!", FILE_DATA_ERR)),
     }
-    (data, offset)
+    Ok((data, offset))
 }
 
 #[allow(unused_assignments)]
@@ -184,11 +231,54 @@ impl FileReader {
     /// For example, if the instruction is for adding to the stack,
     /// it will probably contain some data info, like Integers,
     /// Strings, Floats or even Null values.
-    pub fn new(
+    ///
+    /// Used by the CLI: errors exit the process, and `filearg` only shows the metadata and exits.
+    pub fn new(file_location: String, filearg: bool, force_newer_ver: bool) -> FileReader {
+        match Self::decode_file(file_location, filearg, force_newer_ver) {
+            Ok(_) if filearg => process::exit(0),
+            Ok(reader) => reader,
+            Err(err) => {
+                eprintln!("\x1B[31m{}\x1b[0m", err.message);
+                process::exit(err.exit_code);
+            }
+        }
+    }
+
+    /// Reads the binary at `file_location` like `new`, returning errors instead of exiting.
+    pub fn open(file_location: String, force_newer_ver: bool) -> Result<FileReader, String> {
+        Self::decode_file(file_location, false, force_newer_ver).map_err(|err| err.message)
+    }
+
+    /// Reads a binary already in memory, like the modules inside a Squid ARchive.
+    pub fn from_bytes(bytes: Vec<u8>, force_newer_ver: bool) -> Result<FileReader, String> {
+        let length = bytes.len() as u64;
+
+        Self::from_reader(&mut Cursor::new(bytes), length, force_newer_ver)
+    }
+
+    /// Reads a binary of `filelength` bytes from any seekable source.
+    pub fn from_reader<R: Read + Seek>(
+        file: &mut R,
+        filelength: u64,
+        force_newer_ver: bool,
+    ) -> Result<FileReader, String> {
+        Self::decode(file, filelength, false, force_newer_ver).map_err(|err| err.message)
+    }
+
+    /// Shows the version and compiler of a binary already in memory, like `new` does with `filearg`.
+    pub fn show_metadata(bytes: Vec<u8>) -> Result<(), String> {
+        let length = bytes.len() as u64;
+
+        Self::decode(&mut Cursor::new(bytes), length, true, false)
+            .map(|_| ())
+            .map_err(|err| err.message)
+    }
+
+    fn decode_file(
         mut file_location: String,
         filearg: bool,
         force_newer_ver: bool,
-    ) -> Result<FileReader, String> {
+    ) -> Result<FileReader, DecodeError> {
         if file_location.ends_with('\\') || file_location.ends_with('/') {
             file_location.pop();
         }
@@ -197,50 +287,49 @@ impl FileReader {
             file_location.push_str(SQDBIN);
         }
 
+        let file = File::open(file_location.clone());
+        let mut file = handle_error(file, FILE_DATA_ERR)?;
+
+        let filelength = handle_error(fs::metadata(file_location), FILE_DATA_ERR)?.len();
+        // println!("{filelength}");
+
+        Self::decode(&mut file, filelength, filearg, force_newer_ver)
+    }
+
+    /// Decodes a binary. With `filearg`, only its metadata is shown and nothing is decoded.
+    fn decode<R: Read + Seek>(
+        file: &mut R,
+        filelength: u64,
+        filearg: bool,
+        force_newer_ver: bool,
+    ) -> Result<FileReader, DecodeError> {
         let mut instructions: Vec<u8> = Vec::new();
         let mut data: Vec<Immediates> = Vec::new();
-        let file = File::open(file_location.clone());
-        let mut file = handle_error(file, FILE_DATA_ERR);
+        let mut functions: Vec<Function> = Vec::new();
+        let mut function: Option<Function> = None;
+        let mut exports: Vec<(String, u64)> = Vec::new();
+        let mut imports: Vec<Import> = Vec::new();
 
         let mut offset = 0x00;
         let mut counter = 0;
-        let filelength = handle_error(fs::metadata(file_location), FILE_DATA_ERR).len();
-        // println!("{filelength}");
 
         loop {
-            let crsr = handle_error(file.seek(SeekFrom::Start(offset)), FILE_DATA_ERR);
-
-            #[allow(unused_variables)]
-            fn set_crsr(mut crsr: u64, mut file: &File, offset: &u64) {
-                crsr = match file.seek(SeekFrom::Start(*offset)) {
-                    Ok(crsr) => crsr,
-                    Err(err) => {
-                        eprintln!("\x1B[31m{}\x1b[0m", err);
-                        process::exit(FILE_DATA_ERR);
-                    }
-                }
+            let crsr = handle_error(file.seek(SeekFrom::Start(offset)), FILE_DATA_ERR)?;
+
+            fn set_crsr<R: Seek>(file: &mut R, offset: &u64) -> Result<(), DecodeError> {
+                handle_error(file.seek(SeekFrom::Start(*offset)), FILE_DATA_ERR).map(|_| ())
             }
 
             let mut buffer = [0u8; 2];
 
-            match file.read_exact(&mut buffer) {
-                Ok(_) => {}
-                Err(e) => {
-                    eprintln!("\x1B[31m{}\x1b[0m", e);
-                    process::exit(FILE_DATA_ERR);
-                }
-            };
+            handle_error(file.read_exact(&mut buffer), FILE_DATA_ERR)?;
 
             if counter < 1 {
-                fn error_handler<T>(res: Result<T, Error>) -> T {
-                    match res {
-                        Ok(byte) => byte,
-                        Err(err) => {
-                            dev_print!("Error: {:?}", err);
-                            eprintln!("\x1B[31mINVALID FILE METADATA!\x1b[0m");
-                            process::exit(METADATA_ERR);
-                        }
-                    }
+                fn error_handler<T>(res: Result<T, Error>) -> Result<T, DecodeError> {
+                    res.map_err(|err| {
+                        dev_print!("Error: {:?}", err);
+                        DecodeError::new("INVALID FILE METADATA!", METADATA_ERR)
+                    })
                 }
 
                 // Check if file has metadata
@@ -249,20 +338,20 @@ impl FileReader {
                     1 => {
                         offset += METADATA_IDENTIFIER_BYTE;
 
-                        set_crsr(crsr, &file, &offset);
+                        set_crsr(file, &offset)?;
 
-                        let major = error_handler(file.read_u32::<LittleEndian>());
+                        let major = error_handler(file.read_u32::<LittleEndian>())?;
 
                         offset += MAJOR_SIZE;
 
-                        let minor = error_handler(file.read_u16::<LittleEndian>());
+                        let minor = error_handler(file.read_u16::<LittleEndian>())?;
 
                         let wrong_ver = if !force_newer_ver {
                             // Binary major is higher than VM's or
                             // Binary major is equal to VM's, but minor is higher
-                            if major > handle_error(VM_MAJOR.parse(), METADATA_ERR)
-                                || minor > handle_error(VM_MINOR.parse(), METADATA_ERR)
-                                    && major == handle_error(VM_MAJOR.parse::<u32>(), METADATA_ERR)
+                            if major > handle_error(VM_MAJOR.parse(), METADATA_ERR)?
+                                || minor > handle_error(VM_MINOR.parse(), METADATA_ERR)?
+                                    && major == handle_error(VM_MAJOR.parse::<u32>(), METADATA_ERR)?
                             {
                                 true
                             } else {
@@ -277,17 +366,17 @@ impl FileReader {
                         if filearg || wrong_ver {
                             offset += MINOR_PATCH_SIZE;
 
-                            let patch = error_handler(file.read_u16::<LittleEndian>());
+                            let patch = error_handler(file.read_u16::<LittleEndian>())?;
 
                             offset += MINOR_PATCH_SIZE;
 
-                            let details = error_handler(file.read_u8());
+                            let details = error_handler(file.read_u8())?;
 
                             let mut counter: u32 = 0;
                             let mut byte_string = vec![0; 6];
 
                             while counter < COMPILER_NAME_SIZE {
-                                byte_string.push(error_handler(file.read_u8()));
+                                byte_string.push(error_handler(file.read_u8())?);
                                 offset += 1;
                                 counter += 1;
                             }
@@ -302,20 +391,19 @@ impl FileReader {
                                     _ => "unknown",
                                 };
 
-                                eprintln!("\x1B[41mBinary was compiled for a more recent version of the VM!\x1B[0m");
-                                println!(
-                                    "\x1B[32mCurrent VM version: {}\x1B[0m",
-                                    env!("CARGO_PKG_VERSION")
-                                );
-                                let version = format!(
-                                    "{}.{}.{}-{} and up until next major",
-                                    major, minor, patch, details
-                                );
-                                println!(
-                                    "\x1B[33mBinary was compiled for version {}\x1B[0m",
-                                    version
-                                );
-                                process::exit(FILE_DATA_ERR);
+                                return Err(DecodeError::new(
+                                    format!(
+                                        "Binary was compiled for a more recent version of the VM!\n\
+                                        Current VM version: {}\n\
+                                        Binary was compiled for version {}.{}.{}-{} and up until next major",
+                                        env!("CARGO_PKG_VERSION"),
+                                        major,
+                                        minor,
+                                        patch,
+                                        details
+                                    ),
+                                    FILE_DATA_ERR,
+                                ));
                             }
 
                             let details = match details {
@@ -334,22 +422,17 @@ impl FileReader {
                             println!("\x1B[31mIn alpha and beta versions the VM will change a lot, so most things will change.");
                             println!("Binaries for SquidVM {} may not be compatible with older versions and vice versa.\x1b[0m", env!("CARGO_PKG_VERSION"));
 
-                            let compiler = match String::from_utf8(byte_string) {
-                                Ok(string) => string,
-                                Err(err) => {
-                                    dev_print!("Error: {:?}", err);
-                                    eprintln!("\x1B[31mINVALID FILE METADATA!\x1b[0m");
-                                    process::exit(METADATA_ERR);
-                                }
-                            };
+                            let compiler = error_handler(
+                                String::from_utf8(byte_string).map_err(Error::other),
+                            )?;
 
                             println!("Compiled with: {compiler}");
                         } else {
                             offset += 27;
-                            set_crsr(crsr, &file, &offset);
+                            set_crsr(file, &offset)?;
                         }
 
-                        handle_error(file.read_exact(&mut buffer), METADATA_ERR);
+                        handle_error(file.read_exact(&mut buffer), METADATA_ERR)?;
                     }
                     // File does not have metadata
                     _ => {
@@ -362,7 +445,14 @@ impl FileReader {
                 }
 
                 if filearg {
-                    process::exit(0);
+                    return Ok(FileReader {
+                        instructions,
+                        data,
+                        functions,
+                        exports,
+                        imports,
+                        start: 0,
+                    });
                 }
             }
 
@@ -371,9 +461,9 @@ impl FileReader {
             if counter < 1 && buffer[0] == METADATA_IDENTIFIER_BYTE as u8 {
                 offset += HEADER_SIZE;
 
-                set_crsr(crsr, &file, &offset);
+                set_crsr(file, &offset)?;
 
-                handle_error(file.read_exact(&mut buffer), METADATA_ERR);
+                handle_error(file.read_exact(&mut buffer), METADATA_ERR)?;
             }
 
             if counter > 1 && crsr == 0 {
@@ -390,46 +480,68 @@ impl FileReader {
             // println!("Idk");
 
             match buffer[0] {
-                PDTS => {
-                    instructions.push(buffer[0]);
-                    let (file_data, data_offset) = get_data(buffer[1], &file, buffer);
-                    data.push(file_data);
-                    offset += data_offset;
+                FNOBJSTART => {
+                    if function.is_some() {
+                        return Err(DecodeError::new(
+                            "Nested function objects are not allowed!",
+                            FILE_DATA_ERR,
+                        ));
+                    }
+
+                    function = Some(Function {
+                        instructions: Vec::new(),
+                        data: Vec::new(),
+                        asynchronous: buffer[1] & 1 == 1,
+                    });
+                    offset += 2;
                 }
-                JMPFD => {
-                    instructions.push(0x0C);
+                FNOBJEND => {
+                    match function.take() {
+                        Some(function) => functions.push(function),
+                        None => {
+                            return Err(DecodeError::new(
+                                "Function object end without a start!",
+                                FILE_DATA_ERR,
+                            ));
+                        }
+                    }
                     offset += 1;
-                    let int = handle_error(file.read_u64::<LittleEndian>(), FILE_DATA_ERR);
-                    data.push(UInteger(int));
-                    offset += 8;
                 }
-                0x18 => {
-                    instructions.push(0x18);
-                    data.push(Null);
-                    offset += 1;
+                EXPORT | IMPORT if function.is_some() => {
+                    return Err(DecodeError::new(
+                        "Exports and imports can't be inside function objects!",
+                        FILE_DATA_ERR,
+                    ));
                 }
-                NTASK => {
-                    instructions.push(0x19);
-                    // println!("Instruction: {:X}", buffer[0]);
-                    // println!("Buffer: {:X}", buffer[1]);
-                    set_crsr(crsr, &file, &offset);
-                    handle_error(file.read_exact(&mut buffer), FILE_DATA_ERR);
-                    data.push(handle_error(to_boolean(buffer[1]), FILE_DATA_ERR));
-                    offset += 2;
+                EXPORT => {
+                    // <EXPORT><name length (u8)><name><function index (u64)>
+                    set_crsr(file, &(offset + 2))?;
+
+                    let name = read_name(file, buffer[1])?;
+                    let index = handle_error(file.read_u64::<LittleEndian>(), FILE_DATA_ERR)?;
+
+                    exports.push((name, index));
+                    offset += 2 + buffer[1] as u64 + 8;
                 }
-                NTHRD => {
-                    instructions.push(0x20);
-                    // println!("Instruction: {:X}", buffer[0]);
-                    // println!("Buffer: {:X}", buffer[1]);
-                    set_crsr(crsr, &file, &offset);
-                    handle_error(file.read_exact(&mut buffer), FILE_DATA_ERR);
-                    data.push(handle_error(to_boolean(buffer[1]), FILE_DATA_ERR));
-                    offset += 2;
+                IMPORT => {
+                    // <IMPORT><module length (u8)><module><name length (u8)><name>
+                    set_crsr(file, &(offset + 2))?;
+
+                    let module = read_name(file, buffer[1])?;
+                    let name_length = handle_error(file.read_u8(), FILE_DATA_ERR)?;
+                    let name = read_name(file, name_length)?;
+
+                    imports.push(Import { module, name });
+                    offset += 2 + buffer[1] as u64 + 1 + name_length as u64;
                 }
                 _ => {
-                    instructions.push(buffer[0]);
-                    data.push(Null);
-                    offset += 1;
+                    // Instructions inside a function object section belong to the function.
+                    let (instructions, data) = match &mut function {
+                        Some(function) => (&mut function.instructions, &mut function.data),
+                        None => (&mut instructions, &mut data),
+                    };
+
+                    read_instruction(instructions, data, &mut offset, file, buffer)?;
                 }
             }
 
@@ -445,6 +557,74 @@ impl FileReader {
             counter += 1;
         }
 
-        Ok(FileReader { instructions, data })
+        if function.is_some() {
+            return Err(DecodeError::new(
+                "Function object without an end!",
+                FILE_DATA_ERR,
+            ));
+        }
+
+        Ok(FileReader {
+            instructions,
+            data,
+            functions,
+            exports,
+            imports,
+            start: 0,
+        })
+    }
+}
+
+/// Reads a single instruction (and its data) into the instruction and data vectors.
+fn read_instruction<R: Read + Seek>(
+    instructions: &mut Vec<u8>,
+    data: &mut Vec<Immediates>,
+    offset: &mut u64,
+    file: &mut R,
+    mut buffer: [u8; 2],
+) -> Result<(), DecodeError> {
+    match buffer[0] {
+        PDTS => {
+            instructions.push(buffer[0]);
+            let (file_data, data_offset) = get_data(buffer[1], file, buffer)?;
+            data.push(file_data);
+            *offset += data_offset;
+        }
+        JMPFD | LDFN | CLOSURE | D_VFD | GETG | SETG | DELG | GCAS | GFADD | GXCHG | CHNEW
+        | TRY => {
+            instructions.push(buffer[0]);
+            data.push(UInteger(get_operand(file, *offset)?));
+            *offset += 9;
+        }
+        0x18 => {
+            instructions.push(0x18);
+            data.push(Null);
+            *offset += 1;
+        }
+        NTASK => {
+            instructions.push(NTASK);
+            // println!("Instruction: {:X}", buffer[0]);
+            // println!("Buffer: {:X}", buffer[1]);
+            handle_error(file.seek(SeekFrom::Start(*offset)), FILE_DATA_ERR)?;
+            handle_error(file.read_exact(&mut buffer), FILE_DATA_ERR)?;
+            data.push(handle_error(to_spawn_flags(buffer[1]), FILE_DATA_ERR)?);
+            *offset += 2;
+        }
+        NTHRD => {
+            instructions.push(NTHRD);
+            // println!("Instruction: {:X}", buffer[0]);
+            // println!("Buffer: {:X}", buffer[1]);
+            handle_error(file.seek(SeekFrom::Start(*offset)), FILE_DATA_ERR)?;
+            handle_error(file.read_exact(&mut buffer), FILE_DATA_ERR)?;
+            data.push(handle_error(to_spawn_flags(buffer[1]), FILE_DATA_ERR)?);
+            *offset += 2;
+        }
+        _ => {
+            instructions.push(buffer[0]);
+            data.push(Null);
+            *offset += 1;
+        }
     }
+
+    Ok(())
 }
@@ -0,0 +1,273 @@
+//! Links modules importing functions from each other into a single program.
+//!
+//! Every module keeps its own function indexes, with its imports numbered after its own functions,
+//! and `JMPFD`/`TRY` addresses relative to its own code. Linking:
+//! - Loads the imported modules, the ones imported first coming first and the entry module last.
+//! - Puts the functions of every module together, relocating the `LDFN` indexes
+//!   and resolving the imported ones through the export tables.
+//! - Puts the code of every module together: the entry module's code comes first, so it keeps
+//!   its addresses, including the ones pushed to the stack for `JMPFS`, `NTHRD` and `NTASK`.
+//!   The code of the imported modules follows it, as their initializers, and the program starts there:
+//!   `HALT` ends an initializer, going to the next one, and the last one goes to the entry module's code.
+//!   The `JMPFD`/`TRY` addresses of the initializers are relocated,
+//!   but not the addresses they push to the stack.
+
+use crate::instructiondefs::*;
+use crate::sqd_reader::sqdbin_reader::defs::SQDBIN;
+use crate::sqd_reader::sqdbin_reader::FileReader;
+use crate::vm_internals::immediates::Immediates::{self, UInteger};
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+
+/// Adds the `.sqd` extension to module names missing it, so `math` and `math.sqd` are the same module.
+pub fn module_name(name: &str) -> String {
+    if name.ends_with(SQDBIN) {
+        name.to_string()
+    } else {
+        format!("{name}{SQDBIN}")
+    }
+}
+
+/// Links the entry module with every module it imports, loading them with `load`.
+pub fn link<F>(entry_name: &str, entry: FileReader, mut load: F) -> Result<FileReader, String>
+where
+    F: FnMut(&str) -> Result<FileReader, String>,
+{
+    let mut seen = HashSet::from([module_name(entry_name)]);
+    let mut modules = Vec::new();
+
+    load_imports(
+        module_name(entry_name),
+        entry,
+        &mut load,
+        &mut seen,
+        &mut modules,
+    )?;
+
+    let mut problems = Vec::new();
+
+    // Index of the first function of each module once linked.
+    let mut bases = Vec::with_capacity(modules.len());
+    let mut functions = 0;
+
+    for (_, module) in &modules {
+        bases.push(functions);
+        functions += module.functions.len() as u64;
+    }
+
+    let mut exports = HashMap::new();
+
+    for ((name, module), base) in modules.iter().zip(&bases) {
+        for (export, index) in &module.exports {
+            if *index >= module.functions.len() as u64 {
+                problems.push(format!(
+                    "{name} exports \"{export}\" as function {index}, but it only has {} functions",
+                    module.functions.len()
+                ));
+            } else if exports
+                .insert((name.as_str(), export.as_str()), base + index)
+                .is_some()
+            {
+                problems.push(format!("{name} exports \"{export}\" more than once"));
+            }
+        }
+    }
+
+    // Function index each module's `LDFN` indexes become.
+    let mut relocations: Vec<Vec<Option<u64>>> = Vec::with_capacity(modules.len());
+
+    for ((name, module), base) in modules.iter().zip(&bases) {
+        let own = (0..module.functions.len() as u64).map(|index| Some(base + index));
+
+        let imported = module.imports.iter().map(|import| {
+            let module_name = module_name(&import.module);
+            let index = exports.get(&(module_name.as_str(), import.name.as_str()));
+
+            if index.is_none() {
+                problems.push(format!(
+                    "{name} imports \"{}\" from {module_name}, but it isn't exported there",
+                    import.name
+                ));
+            }
+
+            index.copied()
+        });
+
+        relocations.push(own.chain(imported).collect());
+    }
+
+    if !problems.is_empty() {
+        return Err(format!(
+            "Couldn't link {entry_name}:\n\t- {}",
+            problems.join("\n\t- ")
+        ));
+    }
+
+    let last = modules.len() - 1;
+
+    // The entry module's code comes first, so the addresses it uses stay the same.
+    // The initializers follow it, each one going to the next when it ends,
+    // and the last one to the entry module's code.
+    let entry_end = modules[last].1.instructions.len() as u64;
+    let mut offsets = Vec::with_capacity(modules.len());
+    let mut next = entry_end + 1;
+
+    for (position, (_, module)) in modules.iter().enumerate() {
+        if position == last {
+            offsets.push(0);
+        } else {
+            offsets.push(next);
+            next += module.instructions.len() as u64;
+        }
+    }
+
+    let mut program = FileReader {
+        instructions: Vec::new(),
+        data: Vec::new(),
+        functions: Vec::new(),
+        exports: Vec::new(),
+        imports: Vec::new(),
+        start: 0,
+    };
+
+    let mut initializers = Vec::new();
+    let mut initializers_data = Vec::new();
+
+    for (position, (((name, mut module), relocation), offset)) in modules
+        .into_iter()
+        .zip(relocations)
+        .zip(offsets)
+        .enumerate()
+    {
+        for function in &mut module.functions {
+            relocate_functions(
+                &name,
+                &function.instructions,
+                &mut function.data,
+                &relocation,
+            )?;
+        }
+
+        relocate_functions(&name, &module.instructions, &mut module.data, &relocation)?;
+
+        let end = offset + module.instructions.len() as u64;
+
+        for (instruction, data) in module.instructions.iter_mut().zip(&mut module.data) {
+            match (*instruction, &data) {
+                (JMPFD | TRY, UInteger(address)) => *data = UInteger(address + offset),
+                // Initializers end by going to the code of the next module.
+                (HALT, _) if position != last => {
+                    *instruction = JMPFD;
+                    *data = UInteger(end);
+                }
+                _ => {}
+            }
+        }
+
+        if position == last {
+            program.exports = module
+                .exports
+                .iter()
+                .map(|(export, index)| (export.clone(), relocation[*index as usize].unwrap()))
+                .collect();
+
+            program.instructions.append(&mut module.instructions);
+            program.data.append(&mut module.data);
+        } else {
+            initializers.append(&mut module.instructions);
+            initializers_data.append(&mut module.data);
+        }
+
+        program.functions.append(&mut module.functions);
+    }
+
+    if last > 0 {
+        // Ends the program when the entry module's code ends, like it did before linking.
+        program.instructions.push(HALT);
+        program.data.push(Immediates::Null);
+
+        program.start = program.instructions.len();
+        program.instructions.append(&mut initializers);
+        program.data.append(&mut initializers_data);
+
+        program.instructions.push(JMPFD);
+        program.data.push(UInteger(0));
+    }
+
+    Ok(program)
+}
+
+/// Loads the modules imported by `module` before adding it,
+/// so the ones it depends on come first. Modules already seen aren't loaded again.
+fn load_imports<F>(
+    name: String,
+    module: FileReader,
+    load: &mut F,
+    seen: &mut HashSet<String>,
+    modules: &mut Vec<(String, FileReader)>,
+) -> Result<(), String>
+where
+    F: FnMut(&str) -> Result<FileReader, String>,
+{
+    for import in &module.imports {
+        let import_name = module_name(&import.module);
+
+        if seen.insert(import_name.clone()) {
+            let imported = load(&import_name)
+                .map_err(|err| format!("Couldn't load {import_name}, imported by {name}: {err}"))?;
+
+            load_imports(import_name, imported, load, seen, modules)?;
+        }
+    }
+
+    modules.push((name, module));
+
+    Ok(())
+}
+
+/// Changes the `LDFN` indexes of a module to the ones of the linked program.
+fn relocate_functions(
+    name: &str,
+    instructions: &[u8],
+    data: &mut [Immediates],
+    relocation: &[Option<u64>],
+) -> Result<(), String> {
+    for (instruction, data) in instructions.iter().zip(data) {
+        if let (LDFN, UInteger(index)) = (*instruction, &data) {
+            match relocation.get(*index as usize) {
+                Some(Some(linked)) => *data = UInteger(*linked),
+                _ => {
+                    return Err(format!(
+                        "{name} loads function {index}, but it only has {} functions and imports",
+                        relocation.len()
+                    ))
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Loads modules from the first folder of `search_path` having them.
+pub fn search_path_loader(
+    search_path: Vec<PathBuf>,
+    force_newer_ver: bool,
+) -> impl FnMut(&str) -> Result<FileReader, String> {
+    move |name| {
+        let location = search_path
+            .iter()
+            .map(|folder| folder.join(name))
+            .find(|location| location.is_file())
+            .ok_or_else(|| {
+                let folders: Vec<String> = search_path
+                    .iter()
+                    .map(|folder| format!("\"{}\"", folder.display()))
+                    .collect();
+
+                format!("Not found in the module path ({})", folders.join(", "))
+            })?;
+
+        FileReader::open(location.display().to_string(), force_newer_ver)
+    }
+}
@@ -2,5 +2,7 @@
 pub mod defs;
 /// Filereader is the module that reads the binary and converts them to a VM readble form.
 pub mod filereader;
+/// Links modules importing functions from each other.
+pub mod linker;
 
 pub use filereader::*;
@@ -4,7 +4,6 @@ use crate::errdef::MAXMEM_CONVERSION_ERR;
 use crate::getup::get_update;
 use crate::sqd_reader::sqdbin_reader::FileReader;
 use crate::vm_internals::VMStarter;
-use async_std::task;
 use clap::Parser;
 use std::process;
 
@@ -137,9 +136,9 @@ fn test_main_bin() {
 
     let bin = "./examples/coveragetest".to_string();
 
-    fileread = Some(FileReader::new(bin, false, false).unwrap());
+    fileread = Some(FileReader::open(bin, false).unwrap());
 
-    let mut vm = VMStarter::new(maxmem, 20);
+    let mut vm = VMStarter::new(maxmem, 20, 1000);
     // dev_print!("{:?}", vm);
 
     if let Some(fileread) = fileread {
@@ -149,21 +148,19 @@ fn test_main_bin() {
     }
 
     if vm.task_handlers.len() > 0 {
-        task::block_on(async {
-            for task in vm.task_handlers {
-                match task.await {
-                    Ok(_) => {}
-                    Err(err) => {
-                        eprintln!("\x1B[41m{}\x1B[0m", err);
-                    }
-                };
-            }
-        });
+        for task in vm.task_handlers.into_iter().flatten() {
+            match task.join() {
+                Ok(_) => {}
+                Err(err) => {
+                    eprintln!("\x1B[41m{}\x1B[0m", err);
+                }
+            };
+        }
     }
 
     if vm.thread_handlers.len() > 0 {
-        for task in vm.thread_handlers {
-            match task.join().unwrap() {
+        for task in vm.thread_handlers.into_iter().flatten() {
+            match task.join() {
                 Ok(_) => {}
                 Err(err) => {
                     eprintln!("\x1B[41m{}\x1B[0m", err);
@@ -174,3 +171,1296 @@ fn test_main_bin() {
 
     dev_print!("Exiting...");
 }
+
+#[test]
+fn test_function_objects() {
+    let fileread = FileReader::open("./examples/functions".to_string(), false).unwrap();
+
+    assert_eq!(fileread.functions.len(), 2);
+    assert_eq!(fileread.functions[0].instructions, vec![0x01, 0x25]);
+    assert_eq!(fileread.functions[1].instructions, vec![0x01]);
+    assert!(!fileread.functions[0].asynchronous);
+    assert_eq!(fileread.instructions.len(), fileread.data.len());
+
+    use crate::instructiondefs::{CALL, RET};
+    use crate::vm_internals::heap::{DataType, Function};
+    use crate::vm_internals::immediates::Immediates::{self, Null};
+
+    let mut thread = thread(vec![CALL], vec![Null]);
+
+    // Asynchronous functions only run as tasks.
+    let function = thread
+        .heap
+        .write()
+        .unwrap()
+        .allocate(
+            0,
+            DataType::Function(Function {
+                instructions: vec![RET],
+                data: vec![Null],
+                asynchronous: true,
+            }),
+        )
+        .unwrap();
+
+    thread.stack.push(Immediates::RefPtr(function)).unwrap();
+
+    assert_eq!(thread.run(), Err("[ ASYNC FUNCTION ]".to_string()));
+
+    use crate::instructiondefs::{CLOSURE, PDTS};
+    use crate::vm_internals::immediates::Immediates::{Integer, UInteger};
+
+    // Closing over a closure adds to its captures, which are pushed when it's called.
+    let mut closures = self::thread(
+        vec![CLOSURE, PDTS, CLOSURE, CALL],
+        vec![UInteger(1), Integer(2), UInteger(1), Null],
+    );
+
+    let function = closures
+        .heap
+        .write()
+        .unwrap()
+        .allocate(
+            0,
+            DataType::Function(Function {
+                instructions: vec![RET],
+                data: vec![Null],
+                asynchronous: false,
+            }),
+        )
+        .unwrap();
+
+    closures.stack.push(Immediates::RefPtr(function)).unwrap();
+    closures.stack.push(Integer(1)).unwrap();
+
+    assert_eq!(closures.run(), Ok(Integer(2)));
+    assert_eq!(closures.stack.pop(), Ok(Integer(1)));
+
+    // Closures are values, only the function object is in the heap.
+    assert_eq!(closures.heap.read().unwrap().stats().objects, 1);
+}
+
+#[test]
+fn test_linker() {
+    use crate::instructiondefs::*;
+    use crate::sqd_reader::sqdbin_reader::linker::{link, search_path_loader};
+    use crate::sqd_reader::sqdbin_reader::Import;
+    use crate::vm_internals::immediates::Immediates;
+    use std::path::PathBuf;
+
+    let main = FileReader::open("./examples/linking/main".to_string(), false).unwrap();
+
+    assert_eq!(main.imports.len(), 2);
+    assert_eq!(
+        main.imports[1],
+        Import {
+            module: "math".to_string(),
+            name: "double".to_string()
+        }
+    );
+
+    let loader = search_path_loader(vec![PathBuf::from("./examples/linking")], false);
+    let program = link("main.sqd", main.clone(), loader).unwrap();
+
+    // main.sqd comes first, then the initializer of math.sqd, which the program starts at.
+    let math = FileReader::open("./examples/linking/math".to_string(), false).unwrap();
+    let start = main.instructions.len() + 1;
+    let end = start + math.instructions.len();
+
+    assert_eq!(program.functions.len(), 3);
+    assert_eq!(program.start, start);
+    assert_eq!(program.instructions.len(), end + 1);
+    assert_eq!(program.instructions[start - 1], HALT);
+    assert_eq!(program.instructions[start + 2], JMPFD);
+    assert_eq!(program.data[start + 2], Immediates::UInteger(end as u64));
+    assert_eq!(program.instructions[end], JMPFD);
+    assert_eq!(program.data[end], Immediates::UInteger(0));
+
+    // LDFN add2, LDFN double and LDFN of its own function are relocated, JMPFD 9 stays.
+    assert_eq!(program.data[1], Immediates::UInteger(0));
+    assert_eq!(program.data[3], Immediates::UInteger(1));
+    assert_eq!(program.data[6], Immediates::UInteger(9));
+    assert_eq!(program.data[9], Immediates::UInteger(2));
+
+    // Addresses pushed to the stack by the entry module stay right too.
+    let spawner = FileReader {
+        instructions: vec![PDTS, PDTS, NTHRD, HALT, PDTS],
+        data: vec![
+            Immediates::UInteger(0),
+            Immediates::UInteger(4),
+            Immediates::UInteger(1),
+            Immediates::Null,
+            Immediates::Integer(42),
+        ],
+        functions: Vec::new(),
+        exports: Vec::new(),
+        imports: main.imports.clone(),
+        start: 0,
+    };
+
+    let loader = search_path_loader(vec![PathBuf::from("./examples/linking")], false);
+    let program = link("spawner.sqd", spawner, loader).unwrap();
+
+    let mut vm = VMStarter::new(1024 * 1024, 20, 1000);
+    vm.interpreter(program);
+
+    let handle = vm.thread_handlers[0].take().unwrap();
+
+    assert_eq!(handle.join(), Ok(Immediates::Integer(42)));
+
+    let mut broken = main.clone();
+    broken.imports.push(Import {
+        module: "math".to_string(),
+        name: "triple".to_string(),
+    });
+
+    let loader = search_path_loader(vec![PathBuf::from("./examples/linking")], false);
+
+    assert_eq!(
+        link("main.sqd", broken, loader).err().unwrap(),
+        "Couldn't link main.sqd:\n\t- main.sqd imports \"triple\" from math.sqd, but it isn't exported there"
+    );
+
+    let loader = search_path_loader(vec![PathBuf::from("./examples")], false);
+
+    assert!(link("main.sqd", main, loader)
+        .err()
+        .unwrap()
+        .starts_with("Couldn't load math.sqd, imported by main.sqd"));
+}
+
+#[test]
+fn test_heap_stats() {
+    use crate::vm_internals::heap::{AllocatedObject, DataType};
+    use crate::vm_internals::immediates::Immediates;
+    use crate::vm_internals::VMHeap;
+
+    let string = DataType::Immediate(Immediates::MutStr("Hello, World!".to_string()));
+    let size = AllocatedObject::new(string.clone()).size;
+
+    let mut heap = VMHeap::new(size * 2);
+
+    heap.allocate(0, string.clone()).unwrap();
+    heap.allocate(0, string.clone()).unwrap();
+
+    assert!(heap.allocate(0, string.clone()).is_err());
+
+    let stats = heap.stats();
+
+    assert_eq!(stats.objects, 2);
+    assert_eq!(stats.bytes, size * 2);
+    assert_eq!(stats.peak, size * 2);
+    assert_eq!(stats.allocations, 2);
+    assert_eq!(stats.gc_cycles, 0);
+    assert_eq!(stats.regions.len(), 1);
+    assert_eq!(stats.regions[0].bytes, size * 2);
+
+    // Ended threads give their empty regions back, regions with objects are only marked.
+    let mut heap = VMHeap::new(size * 2);
+    let empty = heap.allocate_thread_region();
+    let holding = heap.allocate_task_region();
+
+    heap.allocate(holding, string).unwrap();
+    heap.end_region(empty);
+    heap.end_region(holding);
+
+    let stats = heap.stats();
+
+    assert_eq!(stats.regions.len(), 2);
+    assert!(stats.regions.iter().all(|region| region.key != empty));
+    assert!(stats
+        .regions
+        .iter()
+        .any(|region| region.key == holding && region.ended));
+    assert_eq!(heap.allocate_thread_region(), empty);
+}
+
+#[test]
+fn test_heap_dump() {
+    use crate::instructiondefs::MXNEW;
+    use crate::vm_internals::heap::{DataType, Function};
+    use crate::vm_internals::heap_dump::{summarize, write_heap_dump, HeapRoot};
+    use crate::vm_internals::immediates::Immediates;
+    use crate::vm_internals::vm_threads::{ThreadEntry, VMThread};
+    use crate::vm_internals::{VMHeap, VMRepository};
+    use std::sync::{Arc, RwLock};
+
+    let mut heap = VMHeap::new(1024 * 1024);
+
+    let function = heap
+        .allocate(
+            0,
+            DataType::Function(Function {
+                instructions: vec![0x01],
+                data: vec![Immediates::Null],
+                asynchronous: false,
+            }),
+        )
+        .unwrap();
+
+    heap.allocate(
+        0,
+        DataType::Immediate(Immediates::Array(vec![Immediates::RefPtr(
+            function.clone(),
+        )])),
+    )
+    .unwrap();
+
+    let path = std::env::temp_dir().join("squidvm_test_heap_dump.txt");
+    let path = path.to_str().unwrap();
+
+    let roots = [HeapRoot {
+        kind: "stack",
+        index: 0,
+        pointer: function,
+    }];
+
+    write_heap_dump(&heap, &roots, &[(1, "10".to_string())], path).unwrap();
+
+    let summary = summarize(path).unwrap();
+
+    assert_eq!(summary.objects, 2);
+    assert_eq!(summary.edges, 1);
+    assert_eq!(summary.roots, 1);
+    assert_eq!(summary.globals, 1);
+    assert_eq!(summary.used, heap.used());
+    assert_eq!(summary.types.len(), 2);
+    assert!(summary.types[0].bytes >= summary.types[1].bytes);
+
+    // Threads dump the heap too when they run out of memory, with their stack as roots.
+    let heap = Arc::new(RwLock::new(VMHeap::new(256)));
+    let repo = Arc::new(RwLock::new(VMRepository::new(1)));
+
+    let entry = ThreadEntry {
+        instructions: vec![MXNEW; 64],
+        data: vec![Immediates::Null; 64],
+        pc: 0,
+        function: None,
+        args: Vec::new(),
+    };
+
+    let region = heap.write().unwrap().allocate_thread_region();
+
+    let mut thread = VMThread::new(
+        entry,
+        Vec::new(),
+        region,
+        Arc::clone(&heap),
+        Arc::clone(&repo),
+        64,
+    )
+    .unwrap();
+
+    let oom_path = std::env::temp_dir().join("squidvm_test_heap_dump_oom.txt");
+    let oom_path = oom_path.to_str().unwrap();
+    let _ = std::fs::remove_file(oom_path);
+
+    thread.heap_dump_on_oom = Some(oom_path.to_string());
+
+    let err = thread.run().unwrap_err();
+    assert!(
+        err.ends_with(&format!("Heap dumped to {oom_path}")),
+        "{err}"
+    );
+
+    let summary = summarize(oom_path).unwrap();
+    assert!(summary.objects > 0);
+    assert_eq!(summary.roots, summary.objects);
+}
+
+#[test]
+fn test_repository() {
+    use crate::vm_internals::immediates::Immediates;
+    use crate::vm_internals::VMRepository;
+
+    let mut repo = VMRepository::new(1);
+
+    repo.add_var(1, Immediates::Integer(10)).unwrap();
+    repo.add_var(1, Immediates::MutStr("Replaced".to_string()))
+        .unwrap();
+
+    assert!(repo.add_var(2, Immediates::Null).is_err());
+    assert_eq!(
+        repo.get_var(1).unwrap(),
+        &Immediates::MutStr("Replaced".to_string())
+    );
+
+    repo.remove_var(1).unwrap();
+
+    assert!(repo.get_var(1).is_err());
+    assert!(repo.pop_var(1).is_err());
+}
+
+#[test]
+fn test_repository_atomics() {
+    use crate::vm_internals::immediates::Immediates;
+    use crate::vm_internals::VMRepository;
+    use std::sync::{Arc, RwLock};
+    use std::thread;
+
+    let repo = Arc::new(RwLock::new(VMRepository::new(2)));
+
+    repo.write()
+        .unwrap()
+        .add_var(1, Immediates::Integer(0))
+        .unwrap();
+
+    let handles: Vec<_> = (0..8)
+        .map(|_| {
+            let repo = Arc::clone(&repo);
+            thread::spawn(move || {
+                for _ in 0..1000 {
+                    repo.write()
+                        .unwrap()
+                        .fetch_add(1, Immediates::Integer(1))
+                        .unwrap();
+                }
+            })
+        })
+        .collect();
+
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    let mut repo = repo.write().unwrap();
+
+    assert_eq!(repo.get_var(1).unwrap(), &Immediates::Integer(8000));
+    assert!(!repo
+        .compare_and_swap(1, &Immediates::Integer(0), Immediates::Integer(1))
+        .unwrap());
+    assert!(repo
+        .compare_and_swap(1, &Immediates::Integer(8000), Immediates::Integer(1))
+        .unwrap());
+    assert_eq!(
+        repo.swap(1, Immediates::Boolean(true)).unwrap(),
+        Immediates::Integer(1)
+    );
+    assert!(repo.fetch_add(1, Immediates::Integer(1)).is_err());
+}
+
+/// Thread running `instructions` from the start, with its own heap and repository.
+fn thread(
+    instructions: Vec<u8>,
+    data: Vec<crate::vm_internals::immediates::Immediates>,
+) -> crate::vm_internals::vm_threads::VMThread {
+    use crate::vm_internals::vm_threads::{ThreadEntry, VMThread};
+    use crate::vm_internals::{VMHeap, VMRepository};
+    use std::sync::{Arc, RwLock};
+
+    let heap = Arc::new(RwLock::new(VMHeap::new(4096)));
+    let repo = Arc::new(RwLock::new(VMRepository::new(2)));
+
+    let entry = ThreadEntry {
+        instructions,
+        data,
+        pc: 0,
+        function: None,
+        args: Vec::new(),
+    };
+
+    let region = heap.write().unwrap().allocate_thread_region();
+
+    VMThread::new(entry, Vec::new(), region, heap, repo, 10).unwrap()
+}
+
+#[test]
+fn test_thread_entry() {
+    use crate::instructiondefs::{GETG, I_ADD, SETG};
+    use crate::vm_internals::immediates::Immediates;
+
+    let mut thread = thread(
+        vec![I_ADD, SETG, GETG],
+        vec![
+            Immediates::Null,
+            Immediates::UInteger(1),
+            Immediates::UInteger(1),
+        ],
+    );
+
+    // Arguments are on the stack when the thread starts.
+    thread.stack.push(Immediates::Integer(2)).unwrap();
+    thread.stack.push(Immediates::Integer(3)).unwrap();
+
+    // The value left on top of the stack is what `JOIN` gets back.
+    assert_eq!(thread.run().unwrap(), Immediates::Integer(5));
+    assert_eq!(
+        thread.repository.read().unwrap().get_var(1).unwrap(),
+        &Immediates::Integer(5)
+    );
+}
+
+#[test]
+fn test_channels() {
+    use crate::vm_internals::channel::VMChannel;
+    use crate::vm_internals::immediates::Immediates;
+    use std::thread;
+
+    let channel = VMChannel::new(Some(1));
+    let sender = channel.clone();
+
+    let handle = thread::spawn(move || {
+        for value in 0..5 {
+            sender.send(Immediates::Integer(value)).unwrap();
+        }
+        sender.close();
+    });
+
+    let mut received = Vec::new();
+
+    while let Some(value) = channel.recv() {
+        received.push(value);
+    }
+
+    handle.join().unwrap();
+
+    assert_eq!(
+        received,
+        (0..5).map(Immediates::Integer).collect::<Vec<_>>()
+    );
+    assert_eq!(channel.try_recv(), None);
+    assert!(channel.send(Immediates::Null).is_err());
+}
+
+#[test]
+fn test_sync_objects() {
+    use crate::vm_internals::sync_objects::{next_owner_id, VMCondition, VMMutex, MAIN_OWNER};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+
+    let mutex = VMMutex::new();
+
+    assert!(mutex.unlock(MAIN_OWNER).is_err());
+    mutex.lock(MAIN_OWNER).unwrap();
+    assert!(mutex.lock(MAIN_OWNER).is_err());
+
+    let condition = VMCondition::new();
+    let ready = Arc::new(AtomicBool::new(false));
+
+    let handle = {
+        let (mutex, condition, ready) = (mutex.clone(), condition.clone(), Arc::clone(&ready));
+        thread::spawn(move || {
+            let owner = next_owner_id();
+            mutex.lock(owner).unwrap();
+            ready.store(true, Ordering::SeqCst);
+            condition.notify_one();
+            mutex.unlock(owner).unwrap();
+        })
+    };
+
+    // The other thread can only lock the mutex while this one waits.
+    while !ready.load(Ordering::SeqCst) {
+        condition.wait(&mutex, MAIN_OWNER).unwrap();
+    }
+
+    handle.join().unwrap();
+    mutex.unlock(MAIN_OWNER).unwrap();
+
+    use crate::instructiondefs::{CVWAIT, I_ADD, MXLOCK, MXUNLOCK};
+    use crate::vm_internals::heap::DataType;
+    use crate::vm_internals::immediates::Immediates::{self, Null};
+    use crate::vm_internals::sync_objects::SyncObject;
+    use crate::vm_internals::vm_threads::Slice;
+
+    let allocate = |thread: &crate::vm_internals::vm_threads::VMThread, object| {
+        let pointer = thread
+            .heap
+            .write()
+            .unwrap()
+            .allocate(0, DataType::Sync(object))
+            .unwrap();
+
+        Immediates::RefPtr(pointer)
+    };
+
+    // A task waiting on a condition stays blocked until it's notified,
+    // and lets others lock the mutex meanwhile.
+    let mut task = thread(vec![MXLOCK, CVWAIT, MXUNLOCK], vec![Null, Null, Null]);
+    let (mutex, condition) = (VMMutex::new(), VMCondition::new());
+
+    task.cooperative = true;
+
+    let mutex_pointer = allocate(&task, SyncObject::Mutex(mutex.clone()));
+    let condition_pointer = allocate(&task, SyncObject::Condition(condition.clone()));
+
+    for value in [
+        mutex_pointer.clone(),
+        condition_pointer,
+        mutex_pointer.clone(),
+        mutex_pointer,
+    ] {
+        task.stack.push(value).unwrap();
+    }
+
+    assert!(matches!(task.run_slice(100), Ok(Slice::Blocked { .. })));
+    assert_eq!(mutex.try_lock(MAIN_OWNER), Ok(true));
+    assert!(matches!(task.run_slice(100), Ok(Slice::Blocked { .. })));
+
+    condition.notify_one();
+
+    // Notified, but the mutex is still held.
+    assert!(matches!(task.run_slice(100), Ok(Slice::Blocked { .. })));
+
+    mutex.unlock(MAIN_OWNER).unwrap();
+
+    assert!(matches!(task.run_slice(100), Ok(Slice::Finished(Null))));
+
+    // A thread failing while holding a mutex releases it.
+    let mut failing = thread(vec![MXLOCK, I_ADD], vec![Null, Null]);
+    let mutex_pointer = allocate(&failing, SyncObject::Mutex(mutex.clone()));
+
+    failing.stack.push(mutex_pointer).unwrap();
+
+    assert!(failing.run().is_err());
+    assert_eq!(mutex.try_lock(MAIN_OWNER), Ok(false));
+
+    drop(failing);
+
+    assert_eq!(mutex.try_lock(MAIN_OWNER), Ok(true));
+}
+
+#[test]
+#[cfg(feature = "green-threads")]
+fn test_task_scheduler() {
+    use crate::instructiondefs::{CHRECV, CHSEND, GETG, JMPFD, PDTS, YIELD};
+    use crate::vm_internals::channel::VMChannel;
+    use crate::vm_internals::immediates::Immediates;
+    use crate::vm_internals::scheduler::Scheduler;
+    use std::sync::Arc;
+
+    let channel = Immediates::UInteger(1);
+
+    let sender = thread(
+        [GETG, PDTS, CHSEND].repeat(3),
+        (0..3)
+            .flat_map(|_| [channel.clone(), Immediates::Integer(1), Immediates::Null])
+            .collect(),
+    );
+    let mut receiver = thread(
+        [GETG, CHRECV].repeat(3),
+        (0..3)
+            .flat_map(|_| [channel.clone(), Immediates::Null])
+            .collect(),
+    );
+
+    // Both get the channel from the same global.
+    receiver.repository = Arc::clone(&sender.repository);
+
+    sender
+        .repository
+        .write()
+        .unwrap()
+        .add_var(1, Immediates::Channel(VMChannel::new(Some(1))))
+        .unwrap();
+
+    // With a single worker the sender must give it up when the channel is full,
+    // otherwise the receiver would never run.
+    let scheduler = Scheduler::new(1, 100);
+
+    let sender = scheduler.spawn(0, sender);
+    let receiver = scheduler.spawn(1, receiver);
+
+    assert_eq!(sender.join().unwrap(), Immediates::Null);
+    assert_eq!(receiver.join().unwrap(), Immediates::Boolean(true));
+
+    // Parked tasks are retried, since what they wait on can come from outside the scheduler,
+    // even while another task keeps yielding.
+    let spinning = scheduler.spawn(
+        3,
+        thread(
+            vec![YIELD, JMPFD],
+            vec![Immediates::Null, Immediates::UInteger(0)],
+        ),
+    );
+
+    let channel = VMChannel::new(None);
+    let waiting = thread(
+        vec![GETG, CHRECV],
+        vec![Immediates::UInteger(1), Immediates::Null],
+    );
+
+    waiting
+        .repository
+        .write()
+        .unwrap()
+        .add_var(1, Immediates::Channel(channel.clone()))
+        .unwrap();
+
+    let waiting = scheduler.spawn(2, waiting);
+
+    std::thread::sleep(std::time::Duration::from_millis(20));
+    channel.send(Immediates::Integer(1)).unwrap();
+
+    assert_eq!(waiting.join().unwrap(), Immediates::Boolean(true));
+
+    spinning.cancel();
+    assert!(spinning.join().is_err());
+}
+
+#[test]
+fn test_thread_pool() {
+    use crate::instructiondefs::{CHRECV, GETG};
+    use crate::vm_internals::channel::VMChannel;
+    use crate::vm_internals::immediates::Immediates;
+    use crate::vm_internals::thread_pool::ThreadPool;
+
+    let channel = VMChannel::new(None);
+
+    let receiver = || {
+        let receiver = thread(
+            vec![GETG, CHRECV],
+            vec![Immediates::UInteger(1), Immediates::Null],
+        );
+
+        receiver
+            .repository
+            .write()
+            .unwrap()
+            .add_var(1, Immediates::Channel(channel.clone()))
+            .unwrap();
+
+        receiver
+    };
+
+    let pool = ThreadPool::new(1);
+
+    let first = pool.spawn(0, receiver());
+    // The only worker is taken, so this one waits in the queue.
+    let second = pool.spawn(1, receiver());
+
+    assert_eq!(pool.stats().saturated, 1);
+
+    channel.send(Immediates::Integer(1)).unwrap();
+    channel.send(Immediates::Integer(2)).unwrap();
+
+    assert_eq!(first.join().unwrap(), Immediates::Boolean(true));
+    assert_eq!(second.join().unwrap(), Immediates::Boolean(true));
+
+    let stats = pool.stats();
+
+    assert_eq!((stats.submitted, stats.completed, stats.queued), (2, 2, 0));
+}
+
+#[test]
+fn test_thread_cancel() {
+    use crate::instructiondefs::JMPFD;
+    use crate::vm_internals::immediates::Immediates;
+    use crate::vm_internals::thread_pool::{ThreadPool, ThreadStatus};
+    use std::time::Duration;
+
+    // Loops forever, so it only ends when cancelled.
+    let thread = thread(vec![JMPFD], vec![Immediates::UInteger(0)]);
+
+    let pool = ThreadPool::new(1);
+    let handle = pool.spawn(0, thread);
+
+    assert!(handle.join_timeout(Duration::from_millis(20)).is_none());
+
+    handle.cancel();
+
+    let result = handle.join_timeout(Duration::from_secs(5)).unwrap();
+
+    assert_eq!(handle.status(&result), ThreadStatus::Cancelled);
+}
+
+#[test]
+fn test_thread_failure() {
+    use crate::instructiondefs::I_ADD;
+    use crate::vm_internals::immediates::Immediates;
+    use crate::vm_internals::supervisor::{Supervisor, SupervisorPolicy, ThreadError};
+    use crate::vm_internals::thread_pool::ThreadPool;
+
+    // Adding with an empty stack fails.
+    let mut thread = thread(vec![I_ADD], vec![Immediates::Null]);
+
+    let supervisor = Supervisor::new();
+
+    thread.policy = SupervisorPolicy::Propagate;
+    thread.supervisor = supervisor.clone();
+
+    let pool = ThreadPool::new(1);
+    let error = pool.spawn(3, thread).join().unwrap_err();
+
+    assert_eq!(error.id, 3);
+    assert_eq!(error.trace, vec!["main code, instruction 0 I_ADD (0x01)"]);
+    assert_eq!(supervisor.take_failure(), Some(error.clone()));
+    assert_eq!(supervisor.take_failure(), None);
+
+    // Only the first abort is reported, and the VM stays aborted after that.
+    supervisor.report(SupervisorPolicy::Abort, &error);
+    supervisor.report(
+        SupervisorPolicy::Abort,
+        &ThreadError {
+            id: 4,
+            ..error.clone()
+        },
+    );
+
+    assert!(supervisor.is_aborted());
+    assert_eq!(supervisor.take_abort(), Some(error));
+    assert_eq!(supervisor.take_abort(), None);
+    assert!(supervisor.is_aborted());
+
+    assert_eq!(
+        SupervisorPolicy::from_flags(0b101),
+        Ok(SupervisorPolicy::Abort)
+    );
+    assert!(SupervisorPolicy::from_flags(0b111).is_err());
+}
+
+#[test]
+fn test_exceptions() {
+    use crate::instructiondefs::{ENDTRY, HALT, PDTS, THROW, TRY};
+    use crate::vm_internals::immediates::Immediates::{Integer, Null, UInteger};
+
+    // The value pushed inside the try region is dropped when unwinding.
+    let mut caught = thread(
+        vec![TRY, PDTS, PDTS, THROW, ENDTRY, HALT],
+        vec![UInteger(5), Integer(1), Integer(7), Null, Null, Null],
+    );
+
+    assert_eq!(caught.run(), Ok(Integer(7)));
+    assert!(caught.stack.check_empty());
+
+    let mut uncaught = thread(vec![PDTS, THROW], vec![Integer(7), Null]);
+
+    assert_eq!(uncaught.run(), Err("[ UNCAUGHT EXCEPTION ] 7".to_string()));
+}
+
+#[test]
+fn test_panic_message() {
+    use crate::instructiondefs::{mnemonic, PANIC, PDTS};
+    use crate::vm_internals::immediates::Immediates::{Null, StaticStr};
+
+    let mut thread = thread(
+        vec![PDTS, PANIC],
+        vec![StaticStr("out of cheese".into()), Null],
+    );
+
+    assert_eq!(thread.run(), Err("[ PANIC ] out of cheese".to_string()));
+    assert_eq!(
+        thread.stack_trace(),
+        vec!["main code, instruction 1 PANIC (0x1B)"]
+    );
+    assert_eq!(mnemonic(0xFF), "UNKNOWN");
+}
+
+#[test]
+#[cfg(feature = "sar")]
+fn test_sar_reader() {
+    use crate::sqd_reader::sar_reader::ArchiveReader;
+    use crate::sqd_reader::sqdbin_reader::FileReader;
+    use std::io::{Cursor, Write};
+    use zip::write::FileOptions;
+    use zip::ZipWriter;
+
+    let module = std::fs::read("./examples/helloworld.sqd").unwrap();
+
+    let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+
+    writer
+        .start_file("manifest.json", FileOptions::default())
+        .unwrap();
+    writer
+        .write_all(
+            br#"{
+                "name": "hello",
+                "version": "1.0.0",
+                "vm": ">=0.10.0",
+                "entry": "main.sqd",
+                "modules": ["main.sqd"]
+            }"#,
+        )
+        .unwrap();
+    writer
+        .start_file("main.sqd", FileOptions::default())
+        .unwrap();
+    writer.write_all(&module).unwrap();
+
+    let archive = writer.finish().unwrap();
+
+    let mut reader = ArchiveReader::new(archive).unwrap();
+
+    assert_eq!(reader.manifest.entry, "main.sqd");
+    assert!(reader.manifest.check_vm().is_ok());
+
+    let from_archive = reader.entry_module(false).unwrap();
+    let from_file = FileReader::open("./examples/helloworld".to_string(), false).unwrap();
+
+    assert_eq!(from_archive.instructions, from_file.instructions);
+    assert!(reader.module("missing.sqd", false).is_err());
+}
+
+#[test]
+#[cfg(feature = "sar")]
+fn test_sar_writer() {
+    use crate::sqd_reader::sar_reader::{ArchiveReader, ArchiveWriter};
+    use std::io::Cursor;
+
+    let manifest = br#"{
+        "name": "hello",
+        "version": "1.0.0",
+        "vm": ">=0.10.0",
+        "entry": "main.sqd",
+        "modules": ["main.sqd"],
+        "resources": ["b.txt", "a.txt"]
+    }"#;
+
+    let module = std::fs::read("./examples/helloworld.sqd").unwrap();
+
+    let pack = |files: [&str; 2]| {
+        let mut writer = ArchiveWriter::new(manifest.to_vec()).unwrap();
+
+        writer.add("main.sqd".to_string(), module.clone()).unwrap();
+
+        for name in files {
+            writer
+                .add(name.to_string(), name.as_bytes().to_vec())
+                .unwrap();
+        }
+
+        writer.write(Cursor::new(Vec::new())).unwrap().into_inner()
+    };
+
+    // Adding files in another order gives the same archive.
+    let archive = pack(["b.txt", "a.txt"]);
+    assert_eq!(archive, pack(["a.txt", "b.txt"]));
+
+    let mut reader = ArchiveReader::new(Cursor::new(archive)).unwrap();
+
+    let names: Vec<String> = reader
+        .entries()
+        .unwrap()
+        .into_iter()
+        .map(|(name, _, _)| name)
+        .collect();
+
+    assert_eq!(names, ["manifest.json", "a.txt", "b.txt", "main.sqd"]);
+    assert_eq!(reader.read("a.txt").unwrap(), b"a.txt");
+
+    let mut writer = ArchiveWriter::new(manifest.to_vec()).unwrap();
+
+    assert!(writer.add("c.txt".to_string(), Vec::new()).is_err());
+    assert!(writer.add("manifest.json".to_string(), Vec::new()).is_err());
+
+    writer.add("main.sqd".to_string(), module).unwrap();
+
+    assert_eq!(
+        writer.write(Cursor::new(Vec::new())).unwrap_err(),
+        "Files listed in manifest.json aren't in the archive: \"b.txt\", \"a.txt\""
+    );
+
+    // Broken modules are refused instead of ending the process.
+    let mut writer = ArchiveWriter::new(manifest.to_vec()).unwrap();
+    let truncated = std::fs::read("./examples/helloworld.sqd").unwrap()[..60].to_vec();
+
+    let err = writer.add("main.sqd".to_string(), truncated).unwrap_err();
+    assert!(err.starts_with("Invalid module main.sqd: "), "{err}");
+}
+
+#[test]
+#[cfg(feature = "bundle")]
+fn test_bundle() {
+    use crate::sqd_reader::sar_reader::bundle::{bundle, find_archive};
+    use std::io::Cursor;
+
+    let archive = std::fs::read("./examples/helloworld.sar").unwrap();
+    let executable = b"not really an executable".to_vec();
+
+    assert_eq!(find_archive(&mut Cursor::new(&executable)), Ok(None));
+
+    let bundled = bundle(executable.clone(), &archive).unwrap();
+
+    assert!(bundled.starts_with(&executable));
+    assert_eq!(
+        find_archive(&mut Cursor::new(&bundled)),
+        Ok(Some((executable.len() as u64, archive.len() as u64)))
+    );
+
+    // Bundling again replaces the archive instead of stacking them.
+    assert_eq!(bundle(bundled.clone(), &archive).unwrap(), bundled);
+    assert!(bundle(executable, b"not an archive").is_err());
+}
+
+#[test]
+#[cfg(feature = "sar")]
+fn test_resources() {
+    use crate::instructiondefs::*;
+    use crate::vm_internals::immediates::Immediates;
+    use crate::vm_internals::resources::{resource_instruction, Resources};
+    use crate::vm_internals::VMStack;
+    use std::collections::BTreeMap;
+
+    let resources = Resources::new(BTreeMap::from([
+        ("config.txt".to_string(), b"debug = true".to_vec()),
+        ("table.bin".to_string(), vec![0xFF, 0x00]),
+    ]));
+
+    let mut stack = VMStack::new(10);
+    let mut run = |instruction: u8, name: Option<&str>| {
+        if let Some(name) = name {
+            stack.push(Immediates::MutStr(name.to_string())).unwrap();
+        }
+
+        resource_instruction(instruction, &resources, &mut stack)?;
+        stack.pop()
+    };
+
+    assert_eq!(
+        run(RSREADS, Some("config.txt")),
+        Ok(Immediates::MutStr("debug = true".to_string()))
+    );
+    assert_eq!(
+        run(RSREAD, Some("table.bin")),
+        Ok(Immediates::Binary(vec![0xFF, 0x00]))
+    );
+    assert_eq!(
+        run(RSLIST, None),
+        Ok(Immediates::Array(vec![
+            Immediates::MutStr("config.txt".to_string()),
+            Immediates::MutStr("table.bin".to_string()),
+        ]))
+    );
+    assert_eq!(
+        run(RSEXISTS, Some("missing.txt")),
+        Ok(Immediates::Boolean(false))
+    );
+    assert_eq!(
+        run(RSREAD, Some("missing.txt")),
+        Err("[ RESOURCE NOT FOUND ] missing.txt".to_string())
+    );
+    assert_eq!(
+        run(RSREADS, Some("table.bin")),
+        Err("[ RESOURCE NOT UTF-8 ] table.bin".to_string())
+    );
+}
+
+#[test]
+#[cfg(feature = "sar")]
+fn test_native() {
+    use crate::sqd_reader::sar_reader::manifest::Manifest;
+    use crate::vm_internals::immediates::Immediates;
+    use crate::vm_internals::native::{native_instruction, NativeFunctions, NativeType};
+    use crate::vm_internals::VMStack;
+
+    let manifest = Manifest::parse(
+        br#"{
+            "name": "native",
+            "version": "1.0.0",
+            "vm": ">=0.10.0",
+            "entry": "main.sqd",
+            "modules": ["main.sqd"],
+            "capabilities": ["native"],
+            "native": [{
+                "library": "libm.so.6",
+                "functions": [{ "name": "cos", "params": ["f64"], "returns": "f64" }]
+            }]
+        }"#,
+    )
+    .unwrap();
+
+    let (name, signature) = &manifest.native[0].functions[0];
+
+    assert_eq!(name, "cos");
+    assert_eq!(signature.params, vec![NativeType::F64]);
+    assert_eq!(signature.returns, NativeType::F64);
+
+    let error = Manifest::parse(
+        br#"{
+            "name": "native",
+            "version": "1.0.0",
+            "vm": ">=0.10.0",
+            "entry": "main.sqd",
+            "modules": ["main.sqd"],
+            "native": [{
+                "library": "libm.so.6",
+                "functions": [{ "name": "cos", "params": ["double"] }]
+            }]
+        }"#,
+    )
+    .err()
+    .unwrap();
+
+    assert!(error.contains("Native function \"cos\" has an unknown type \"double\""));
+    assert!(error.contains("\"native\" needs the \"native\" capability"));
+
+    let mut stack = VMStack::new(10);
+    stack.push(Immediates::Float(0.0)).unwrap();
+    stack.push(Immediates::MutStr("cos".to_string())).unwrap();
+
+    #[cfg(not(feature = "native"))]
+    assert_eq!(
+        native_instruction(&NativeFunctions::default(), &mut stack),
+        Err("[ NATIVE CALLS NOT SUPPORTED ] cos".to_string())
+    );
+
+    #[cfg(all(feature = "native", target_os = "linux"))]
+    {
+        let natives = NativeFunctions::load(vec![(
+            manifest.native[0].library.clone(),
+            manifest.native[0].functions.clone(),
+        )])
+        .unwrap();
+
+        native_instruction(&natives, &mut stack).unwrap();
+        assert_eq!(stack.pop(), Ok(Immediates::Float(1.0)));
+
+        stack.push(Immediates::Boolean(true)).unwrap();
+        stack.push(Immediates::MutStr("cos".to_string())).unwrap();
+
+        assert_eq!(
+            native_instruction(&natives, &mut stack),
+            Err("[ INVALID NATIVE ARGUMENT ] cos: argument 0 must be f64".to_string())
+        );
+    }
+}
+
+#[test]
+#[cfg(feature = "sar")]
+fn test_sar_integrity() {
+    use crate::sqd_reader::sar_reader::{ArchiveReader, ArchiveWriter};
+    use std::io::{Cursor, Write};
+    use zip::write::FileOptions;
+    use zip::ZipWriter;
+
+    let mut writer = ArchiveWriter::new(
+        br#"{
+            "name": "hello",
+            "version": "1.0.0",
+            "vm": ">=0.10.0",
+            "entry": "main.sqd",
+            "modules": ["main.sqd"],
+            "resources": ["data.txt"]
+        }"#
+        .to_vec(),
+    )
+    .unwrap();
+
+    writer.set_compression(true);
+    writer
+        .add(
+            "main.sqd".to_string(),
+            std::fs::read("./examples/helloworld.sqd").unwrap(),
+        )
+        .unwrap();
+    writer
+        .add("data.txt".to_string(), b"data ".repeat(100))
+        .unwrap();
+
+    let archive = writer.write(Cursor::new(Vec::new())).unwrap();
+    let mut reader = ArchiveReader::new(archive).unwrap();
+
+    assert!(reader
+        .manifest
+        .hash
+        .as_ref()
+        .is_some_and(|hash| hash.starts_with("sha256:")));
+    assert_eq!(reader.read("data.txt").unwrap(), b"data ".repeat(100));
+
+    // Changing a file and its CRC still changes the content hash.
+    let mut tampered = ZipWriter::new(Cursor::new(Vec::new()));
+
+    for name in ["manifest.json", "main.sqd", "data.txt"] {
+        let bytes = match name {
+            "data.txt" => b"evil".to_vec(),
+            _ => reader.read(name).unwrap(),
+        };
+
+        tampered.start_file(name, FileOptions::default()).unwrap();
+        tampered.write_all(&bytes).unwrap();
+    }
+
+    let error = ArchiveReader::new(tampered.finish().unwrap())
+        .err()
+        .unwrap();
+
+    assert!(error.starts_with("Tampered archive: its content hash is sha256:"));
+}
+
+#[test]
+#[cfg(feature = "sar")]
+fn test_sar_signing() {
+    use crate::sqd_reader::sar_reader::signing::{generate_key, TrustedKeys};
+    use crate::sqd_reader::sar_reader::{ArchiveReader, ArchiveWriter};
+    use std::io::{Cursor, Write};
+    use zip::write::FileOptions;
+    use zip::ZipWriter;
+
+    let manifest = br#"{
+        "name": "hello",
+        "version": "1.0.0",
+        "vm": ">=0.10.0",
+        "entry": "main.sqd",
+        "modules": ["main.sqd"]
+    }"#;
+
+    let pack = |key| {
+        let mut writer = ArchiveWriter::new(manifest.to_vec()).unwrap();
+
+        if let Some(key) = key {
+            writer.sign_with(key);
+        }
+
+        writer
+            .add(
+                "main.sqd".to_string(),
+                std::fs::read("./examples/helloworld.sqd").unwrap(),
+            )
+            .unwrap();
+
+        writer.write(Cursor::new(Vec::new())).unwrap()
+    };
+
+    let key = generate_key();
+    let mut trusted = TrustedKeys::new();
+    trusted.add("dev".to_string(), key.verifying_key());
+
+    let mut reader = ArchiveReader::new(pack(Some(key.clone()))).unwrap();
+
+    assert_eq!(reader.signer, Some(key.verifying_key()));
+    assert!(reader.check_signer(&trusted, false).is_ok());
+    assert!(reader.check_signer(&TrustedKeys::new(), true).is_err());
+
+    let unsigned = ArchiveReader::new(pack(None)).unwrap();
+
+    assert!(unsigned.check_signer(&trusted, false).is_err());
+    assert!(unsigned.check_signer(&trusted, true).is_ok());
+
+    // Changing the manifest breaks its signature.
+    let mut tampered = ZipWriter::new(Cursor::new(Vec::new()));
+
+    for name in ["manifest.json", "signature.json", "main.sqd"] {
+        let mut bytes = reader.read(name).unwrap();
+
+        if name == "manifest.json" {
+            bytes = String::from_utf8(bytes)
+                .unwrap()
+                .replace("1.0.0", "6.6.6")
+                .into_bytes();
+        }
+
+        tampered.start_file(name, FileOptions::default()).unwrap();
+        tampered.write_all(&bytes).unwrap();
+    }
+
+    let error = ArchiveReader::new(tampered.finish().unwrap())
+        .err()
+        .unwrap();
+
+    assert!(error.starts_with("Tampered archive: the signature by"));
+}
+
+#[test]
+#[cfg(feature = "sar")]
+fn test_sar_encryption() {
+    use crate::sqd_reader::sar_reader::encryption::EncryptionKey;
+    use crate::sqd_reader::sar_reader::{ArchiveReader, ArchiveWriter};
+    use std::io::Cursor;
+
+    let mut writer = ArchiveWriter::new(
+        br#"{
+            "name": "hello",
+            "version": "1.0.0",
+            "vm": ">=0.10.0",
+            "entry": "main.sqd",
+            "modules": ["main.sqd"],
+            "resources": ["secret.txt"]
+        }"#
+        .to_vec(),
+    )
+    .unwrap();
+
+    let key = EncryptionKey::new([7; 32]);
+    writer.encrypt_with(key.clone());
+
+    let module = std::fs::read("./examples/helloworld.sqd").unwrap();
+
+    writer.add("main.sqd".to_string(), module.clone()).unwrap();
+    writer
+        .add("secret.txt".to_string(), b"hidden".to_vec())
+        .unwrap();
+
+    let archive = writer.write(Cursor::new(Vec::new())).unwrap();
+
+    // The archive is checked without the key, but its files can't be read.
+    let mut reader = ArchiveReader::new(archive.clone()).unwrap();
+
+    assert_eq!(
+        reader.manifest.encryption.as_deref(),
+        Some("xchacha20poly1305")
+    );
+    assert!(reader.read("secret.txt").is_err());
+
+    reader.set_key(EncryptionKey::new([8; 32]));
+    assert!(reader.read("secret.txt").is_err());
+
+    reader.set_key(key);
+    assert_eq!(reader.read("secret.txt").unwrap(), b"hidden");
+    assert!(reader.entry_module(false).is_ok());
+
+    let stored = zip::ZipArchive::new(archive)
+        .unwrap()
+        .by_name("main.sqd")
+        .unwrap()
+        .size();
+
+    assert_ne!(stored, module.len() as u64);
+}
+
+#[test]
+#[cfg(feature = "sar")]
+fn test_sar_manifest() {
+    use crate::sqd_reader::sar_reader::manifest::{Manifest, Version, VersionRange};
+
+    let range = VersionRange::parse(">=0.10.0, <0.11.0").unwrap();
+
+    assert!(range.matches(&Version::parse("0.10.0-alpha").unwrap()));
+    assert!(!range.matches(&Version::parse("0.11.0").unwrap()));
+    assert!(VersionRange::parse("^0.10.2")
+        .unwrap()
+        .matches(&Version::parse("0.10.7").unwrap()));
+    assert!(VersionRange::parse("~1.0.0").is_err());
+
+    let manifest = Manifest::parse(
+        br#"{
+            "name": "hello",
+            "version": "1.2.0",
+            "vm": "^0.10.0",
+            "entry": "main.sqd",
+            "modules": ["main.sqd", "lib.sqd"],
+            "resources": ["greeting.txt"],
+            "capabilities": ["threads"]
+        }"#,
+    )
+    .unwrap();
+
+    assert_eq!(manifest.version, Version::parse("1.2.0").unwrap());
+    assert!(manifest
+        .check_files(&["main.sqd", "lib.sqd", "greeting.txt", "manifest.json"])
+        .is_ok());
+    assert_eq!(
+        manifest.check_files(&["main.sqd"]),
+        Err(
+            "Files listed in manifest.json aren't in the archive: \"lib.sqd\", \"greeting.txt\""
+                .to_string()
+        )
+    );
+
+    // Every problem is listed at once.
+    let error = Manifest::parse(
+        br#"{
+            "name": "hello world",
+            "version": "1.0",
+            "entry": "start.sqd",
+            "modules": ["main.sqd"],
+            "capabilities": ["teleport"]
+        }"#,
+    )
+    .unwrap_err();
+
+    assert_eq!(
+        error,
+        "Invalid manifest.json:\n\t- \"vm\" is missing\n\t- \"name\" can only have letters, digits, '-', '_' and '.' (found \"hello world\")\n\t- \"version\": \"1.0\" isn't a major.minor.patch version\n\t- Entry module \"start.sqd\" isn't in \"modules\"\n\t- Unknown capability \"teleport\" (known: threads, tasks, window, native)"
+    );
+}
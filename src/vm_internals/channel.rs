@@ -0,0 +1,249 @@
+use crate::instructiondefs::{CHCLOSE, CHNEW, CHRECV, CHSEND, CHTRY};
+use crate::vm_internals::immediates::Immediates;
+use crate::vm_internals::VMStack;
+use std::cmp::Ordering;
+use std::collections::VecDeque;
+use std::fmt::{Debug, Formatter};
+use std::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::task::Poll;
+
+/// Used to give every channel its own id.
+static CHANNEL_IDS: AtomicU64 = AtomicU64::new(0);
+
+/// Values waiting to be received and the channel settings.
+struct ChannelState {
+    queue: VecDeque<Immediates>,
+    /// `None` if the channel is unbounded.
+    capacity: Option<usize>,
+    closed: bool,
+}
+
+struct ChannelInner {
+    state: Mutex<ChannelState>,
+    /// Notified when a value is sent or the channel is closed.
+    not_empty: Condvar,
+    /// Notified when a value is received or the channel is closed.
+    not_full: Condvar,
+}
+
+/// ## Channel used to pass values between threads and tasks.
+///
+/// Cloning a channel gives another end of the same channel,
+/// so it can be passed as a thread argument, captured by closures or stored as a global.
+#[derive(Clone)]
+pub struct VMChannel {
+    pub id: u64,
+    inner: Arc<ChannelInner>,
+}
+
+impl VMChannel {
+    /// Creates a new channel, unbounded if `capacity` is `None`.
+    pub fn new(capacity: Option<usize>) -> Self {
+        VMChannel {
+            id: CHANNEL_IDS.fetch_add(1, AtomicOrdering::Relaxed),
+            inner: Arc::new(ChannelInner {
+                state: Mutex::new(ChannelState {
+                    queue: VecDeque::new(),
+                    capacity,
+                    closed: false,
+                }),
+                not_empty: Condvar::new(),
+                not_full: Condvar::new(),
+            }),
+        }
+    }
+
+    /// Sends a value, blocking while a bounded channel is full.
+    ///
+    /// Errors if the channel is closed.
+    pub fn send(&self, value: Immediates) -> Result<(), String> {
+        let mut state = self.inner.state.lock().unwrap();
+
+        while !state.closed && state.capacity.is_some_and(|cap| state.queue.len() >= cap) {
+            state = self.inner.not_full.wait(state).unwrap();
+        }
+
+        if state.closed {
+            return Err(format!("[ CHANNEL CLOSED ] Channel: {}", self.id));
+        }
+
+        state.queue.push_back(value);
+        self.inner.not_empty.notify_one();
+
+        Ok(())
+    }
+
+    /// Receives a value, blocking while the channel is empty.
+    ///
+    /// Returns `None` once the channel is closed and empty.
+    pub fn recv(&self) -> Option<Immediates> {
+        let mut state = self.inner.state.lock().unwrap();
+
+        while state.queue.is_empty() && !state.closed {
+            state = self.inner.not_empty.wait(state).unwrap();
+        }
+
+        let value = state.queue.pop_front();
+        self.inner.not_full.notify_one();
+
+        value
+    }
+
+    /// Sends a value without blocking, giving it back if a bounded channel is full.
+    ///
+    /// Errors if the channel is closed.
+    pub fn try_send(&self, value: Immediates) -> Result<Option<Immediates>, String> {
+        let mut state = self.inner.state.lock().unwrap();
+
+        if state.closed {
+            return Err(format!("[ CHANNEL CLOSED ] Channel: {}", self.id));
+        }
+
+        if state.capacity.is_some_and(|cap| state.queue.len() >= cap) {
+            return Ok(Some(value));
+        }
+
+        state.queue.push_back(value);
+        self.inner.not_empty.notify_one();
+
+        Ok(None)
+    }
+
+    /// Receives a value without blocking.
+    ///
+    /// `Poll::Ready(None)` means the channel is closed and empty,
+    /// `Poll::Pending` that it's still open but empty.
+    pub fn poll_recv(&self) -> Poll<Option<Immediates>> {
+        let mut state = self.inner.state.lock().unwrap();
+
+        match state.queue.pop_front() {
+            Some(value) => {
+                self.inner.not_full.notify_one();
+                Poll::Ready(Some(value))
+            }
+            None if state.closed => Poll::Ready(None),
+            None => Poll::Pending,
+        }
+    }
+
+    /// Receives a value if there's one waiting, never blocks.
+    pub fn try_recv(&self) -> Option<Immediates> {
+        let value = self.inner.state.lock().unwrap().queue.pop_front();
+
+        if value.is_some() {
+            self.inner.not_full.notify_one();
+        }
+
+        value
+    }
+
+    /// Closes the channel, waking everyone waiting on it.
+    ///
+    /// Values already sent can still be received.
+    pub fn close(&self) {
+        self.inner.state.lock().unwrap().closed = true;
+        self.inner.not_empty.notify_all();
+        self.inner.not_full.notify_all();
+    }
+}
+
+impl Debug for VMChannel {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let state = self.inner.state.lock().unwrap();
+
+        write!(
+            f,
+            "Channel {{ id: {}, queued: {}, capacity: {:?}, closed: {} }}",
+            self.id,
+            state.queue.len(),
+            state.capacity,
+            state.closed
+        )
+    }
+}
+
+impl PartialEq for VMChannel {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.inner, &other.inner)
+    }
+}
+
+impl PartialOrd for VMChannel {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        self.id.partial_cmp(&other.id)
+    }
+}
+
+/// Pops a channel from the stack.
+fn pop_channel(stack: &mut VMStack) -> Result<VMChannel, String> {
+    match stack.pop()? {
+        Immediates::Channel(channel) => Ok(channel),
+        _ => Err("[ NOT A CHANNEL ]".to_string()),
+    }
+}
+
+/// Runs a channel instruction, shared by the main thread and the VM threads/tasks.
+///
+/// Returns `false` if `blocking` is off and the instruction would block,
+/// leaving the stack as it was so the instruction can be run again later.
+pub fn channel_instruction(
+    instruction: u8,
+    data: &Immediates,
+    stack: &mut VMStack,
+    blocking: bool,
+) -> Result<bool, String> {
+    match instruction {
+        CHNEW => {
+            let capacity = match data {
+                Immediates::UInteger(0) => None,
+                Immediates::UInteger(capacity) => Some(*capacity as usize),
+                _ => return Err("[ WRONG CHANNEL CAPACITY ]".to_string()),
+            };
+
+            stack.push(Immediates::Channel(VMChannel::new(capacity)))?;
+        }
+        CHSEND => {
+            let value = stack.pop()?;
+            let channel = pop_channel(stack)?;
+
+            if blocking {
+                channel.send(value)?;
+            } else if let Some(value) = channel.try_send(value)? {
+                stack.push(Immediates::Channel(channel))?;
+                stack.push(value)?;
+                return Ok(false);
+            }
+        }
+        CHRECV | CHTRY => {
+            let channel = pop_channel(stack)?;
+
+            let value = match instruction {
+                CHRECV if blocking => channel.recv(),
+                CHRECV => match channel.poll_recv() {
+                    Poll::Ready(value) => value,
+                    Poll::Pending => {
+                        stack.push(Immediates::Channel(channel))?;
+                        return Ok(false);
+                    }
+                },
+                _ => channel.try_recv(),
+            };
+
+            match value {
+                Some(value) => {
+                    stack.push(value)?;
+                    stack.push(Immediates::Boolean(true))?;
+                }
+                None => {
+                    stack.push(Immediates::Null)?;
+                    stack.push(Immediates::Boolean(false))?;
+                }
+            }
+        }
+        CHCLOSE => pop_channel(stack)?.close(),
+        _ => return Err("[ UNKNOWN INSTRUCTION ]".to_string()),
+    }
+
+    Ok(true)
+}
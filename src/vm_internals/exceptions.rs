@@ -0,0 +1,64 @@
+//! Try regions registered by `TRY` and the exceptions thrown by `THROW`.
+//!
+//! Throwing unwinds to the latest try region still open: the call frames entered
+//! after `TRY` are left, the operand stack is cut back to its size at `TRY`,
+//! then the payload is pushed and the handler address is jumped to.
+
+use crate::vm_internals::immediates::Immediates;
+
+/// ## Try region registered by `TRY`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TryHandler {
+    /// Address of the handler, in the code that ran `TRY`.
+    pub handler: usize,
+    /// Operand stack size when the region started.
+    pub stack_len: usize,
+    /// Return stack size when the region started.
+    pub frames: usize,
+}
+
+/// ## Try regions of a thread, the latest one catches first.
+#[derive(Debug, Default)]
+pub struct HandlerStack {
+    handlers: Vec<TryHandler>,
+}
+
+impl HandlerStack {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts a try region.
+    pub fn push(&mut self, handler: TryHandler) {
+        self.handlers.push(handler);
+    }
+
+    /// Ends the latest try region (`ENDTRY`).
+    pub fn pop(&mut self) -> Result<TryHandler, String> {
+        self.handlers
+            .pop()
+            .ok_or_else(|| "[ NO TRY REGION ]".to_string())
+    }
+
+    /// Takes the try region catching an exception, `None` if nobody catches it.
+    pub fn catch(&mut self) -> Option<TryHandler> {
+        self.handlers.pop()
+    }
+
+    /// Drops the try regions left open by functions that returned,
+    /// `frames` being the return stack size after returning.
+    pub fn leave_frames(&mut self, frames: usize) {
+        while self
+            .handlers
+            .last()
+            .is_some_and(|handler| handler.frames > frames)
+        {
+            self.handlers.pop();
+        }
+    }
+}
+
+/// Error a thread fails with when nobody catches the exception.
+pub fn uncaught(payload: &Immediates) -> String {
+    format!("[ UNCAUGHT EXCEPTION ] {payload}")
+}
@@ -0,0 +1,132 @@
+//! Call frames shared by the main thread and the VM threads/tasks.
+//!
+//! `CALL`, `RET`, `THROW` and `CLOSURE` move between the main program code and the function objects
+//! the same way on every thread, so they're implemented once over the state they touch.
+
+use crate::vm_internals::exceptions::{uncaught, HandlerStack};
+use crate::vm_internals::heap::Pointer;
+use crate::vm_internals::immediates::{Closure, Immediates};
+use crate::vm_internals::return_stack::{ReturnStack, ReturnType};
+use crate::vm_internals::{VMHeap, VMStack};
+use std::sync::RwLock;
+
+/// ## Code being run by a thread and the frames to return to, borrowed from the thread.
+pub struct Frames<'a> {
+    pub heap: &'a RwLock<VMHeap>,
+    pub stack: &'a mut VMStack,
+    pub return_stack: &'a mut ReturnStack,
+    pub handlers: &'a mut HandlerStack,
+    pub instructions: &'a mut Vec<u8>,
+    pub data: &'a mut Vec<Immediates>,
+    pub pc: &'a mut usize,
+    /// Function being run, `None` if it's the main program code.
+    pub current_function: &'a mut Option<Pointer>,
+    /// Main program code, parked while a function is running.
+    pub parked_program: &'a mut Option<(Vec<u8>, Vec<Immediates>)>,
+}
+
+impl Frames<'_> {
+    /// Calls the function the pointer points to, pushing the values captured by its closure,
+    /// and saves where to return in the return stack.
+    ///
+    /// Asynchronous functions are refused, they only run as tasks.
+    pub fn call(&mut self, pointer: Pointer, captures: Vec<Immediates>) -> Result<(), String> {
+        let function = self.heap.read().unwrap().get_function(&pointer)?;
+
+        if function.asynchronous {
+            return Err("[ ASYNC FUNCTION ]".to_string());
+        }
+
+        let return_to = match self.current_function.take() {
+            Some(caller) => ReturnType::WithPointer(*self.pc, caller),
+            None => {
+                let instructions = std::mem::take(self.instructions);
+                let data = std::mem::take(self.data);
+                *self.parked_program = Some((instructions, data));
+                ReturnType::WithoutPointer(*self.pc)
+            }
+        };
+
+        self.return_stack.push(return_to)?;
+
+        for capture in captures {
+            self.stack.push(capture)?;
+        }
+
+        *self.instructions = function.instructions;
+        *self.data = function.data;
+        *self.current_function = Some(pointer);
+        *self.pc = 0;
+
+        Ok(())
+    }
+
+    /// Returns from the current function to the address saved in the return stack.
+    pub fn ret(&mut self) -> Result<(), String> {
+        match self.return_stack.pop()? {
+            ReturnType::WithoutPointer(pc) => {
+                if let Some((instructions, data)) = self.parked_program.take() {
+                    *self.instructions = instructions;
+                    *self.data = data;
+                }
+                *self.current_function = None;
+                *self.pc = pc;
+            }
+            ReturnType::WithPointer(pc, pointer) => {
+                let function = self.heap.read().unwrap().get_function(&pointer)?;
+                *self.instructions = function.instructions;
+                *self.data = function.data;
+                *self.current_function = Some(pointer);
+                *self.pc = pc;
+            }
+        }
+
+        self.handlers.leave_frames(self.return_stack.get_length());
+
+        Ok(())
+    }
+
+    /// Unwinds to the latest try region and jumps to its handler with the payload on the stack.
+    pub fn throw(&mut self, payload: Immediates) -> Result<(), String> {
+        let Some(handler) = self.handlers.catch() else {
+            return Err(uncaught(&payload));
+        };
+
+        while self.return_stack.get_length() > handler.frames {
+            self.ret()?;
+        }
+
+        self.stack.truncate(handler.stack_len);
+        self.stack.push(payload)?;
+        *self.pc = handler.handler;
+
+        Ok(())
+    }
+}
+
+/// Pops the captured values (`captures` of them) and the function from the stack
+/// and creates a closure with them, used by `CLOSURE`.
+///
+/// Closing over a closure adds the new values after the ones it already captured.
+pub fn create_closure(stack: &mut VMStack, captures: usize) -> Result<Immediates, String> {
+    let mut values = Vec::with_capacity(captures);
+
+    for _ in 0..captures {
+        values.push(stack.pop()?);
+    }
+
+    values.reverse();
+
+    let mut closure = match stack.pop()? {
+        Immediates::RefPtr(pointer) if pointer.is_fn => Closure {
+            function: pointer,
+            captures: Vec::new(),
+        },
+        Immediates::Closure(closure) => *closure,
+        _ => return Err("[ NOT A FUNCTION ]".to_string()),
+    };
+
+    closure.captures.append(&mut values);
+
+    Ok(Immediates::Closure(Box::new(closure)))
+}
@@ -1,11 +1,16 @@
 use crate::errdef::HEAP_ALLOC_ERR;
 use crate::vm_internals::immediates::*;
+use crate::vm_internals::sync_objects::SyncObject;
 use ahash::AHashMap;
-use std::mem::{size_of, size_of_val};
+use std::fmt::{Display, Formatter};
+use std::mem::size_of;
 use std::sync::RwLock;
 #[allow(unused_imports)]
 use Colors::{Black, Gray, White};
 
+/// Prefix of the error returned when the heap doesn't have enough free memory.
+pub const HEAP_OVERFLOW: &str = "[ HEAP OVERFLOW ]";
+
 #[derive(Debug)]
 pub enum Colors {
     White,
@@ -21,6 +26,9 @@ debug_derive!(
         pub capacity: usize,               // In bytes
         pub threads: usize,                // Number of threads active
         pub tasks: usize,                  // Number of tasks active
+        pub peak: usize,                   // Highest usage reached, in bytes
+        pub allocations: usize,            // Number of allocations made
+        pub gc_cycles: usize,              // Number of garbage collector cycles, no collector yet
     }
 );
 
@@ -42,6 +50,9 @@ impl VMHeap {
             capacity,
             threads: 1,
             tasks: 0,
+            peak: 0,
+            allocations: 0,
+            gc_cycles: 0,
         }
     }
 
@@ -53,6 +64,9 @@ impl VMHeap {
             capacity,
             threads: 0,
             tasks: 0,
+            peak: 0,
+            allocations: 0,
+            gc_cycles: 0,
         }
     }
 
@@ -63,21 +77,12 @@ impl VMHeap {
             key += 1;
         }
 
-        let mut index = 0;
-
-        if self.heap.len() != 0 {
-            while !self.heap[index].is_none() {
-                index += 1;
-                if index + 1 > self.heap.len() {
-                    index = self.heap.len() + 1;
-                    break;
-                }
-            }
-        } else {
-            index += 1;
-        }
-
-        index -= 1;
+        // First free slot, left by an ended thread or task, or a new one at the end.
+        let index = self
+            .heap
+            .iter()
+            .position(|region| region.is_none())
+            .unwrap_or(self.heap.len());
 
         (key, index)
     }
@@ -135,6 +140,157 @@ impl VMHeap {
 
         key
     }
+
+    /// Releases the region of a thread or task that ended.
+    ///
+    /// Empty regions are removed. Regions still holding objects are kept and marked as ended,
+    /// since values given back by the thread can point to them and nothing collects them yet.
+    pub fn end_region(&mut self, region: usize) {
+        let Some(&index) = self.index.get(&region) else {
+            return;
+        };
+
+        match self.heap.get_mut(index) {
+            Some(Some(slot)) if slot.objects == 0 => {
+                self.heap[index] = None;
+                self.index.remove(&region);
+            }
+            Some(Some(slot)) => slot.ended = true,
+            _ => {}
+        }
+    }
+
+    /// Allocates an object inside the region and returns a pointer to it.
+    ///
+    /// Errors out if the heap doesn't have enough free memory or if the region doesn't exist.
+    pub fn allocate(&mut self, region: usize, data: DataType) -> Result<Pointer, String> {
+        let is_fn = matches!(data, DataType::Function(_));
+        let object = AllocatedObject::new(data);
+
+        if object.size > self.free {
+            return Err(format!(
+                "{HEAP_OVERFLOW} Heap Capacity: {}, Free: {}, Requested: {}",
+                self.capacity, self.free, object.size
+            ));
+        }
+
+        let size = object.size;
+
+        let point_to = match self.get_region_mut(region) {
+            Some(region) => region.allocate(object),
+            None => return Err(format!("[ INVALID HEAP REGION ] Region: {region}")),
+        };
+
+        self.free -= size;
+        self.allocations += 1;
+        self.peak = self.peak.max(self.capacity - self.free);
+
+        Ok(Pointer {
+            region,
+            point_to,
+            is_fn,
+        })
+    }
+
+    /// Bytes being used by the objects in the heap.
+    pub fn used(&self) -> usize {
+        self.capacity - self.free
+    }
+
+    /// Takes a snapshot of the heap counters.
+    pub fn stats(&self) -> HeapStats {
+        let mut regions: Vec<RegionStats> = self
+            .index
+            .iter()
+            .filter_map(|(key, index)| {
+                let region = self.heap.get(*index)?.as_ref()?;
+                Some(RegionStats {
+                    key: *key,
+                    objects: region.objects,
+                    bytes: region.used,
+                    global: region.global,
+                    thread: region.assigned_thread,
+                    task: region.assigned_task,
+                    ended: region.ended,
+                })
+            })
+            .collect();
+
+        regions.sort_by_key(|region| region.key);
+
+        HeapStats {
+            objects: regions.iter().map(|region| region.objects).sum(),
+            bytes: self.used(),
+            capacity: self.capacity,
+            peak: self.peak,
+            allocations: self.allocations,
+            gc_cycles: self.gc_cycles,
+            regions,
+        }
+    }
+
+    /// Gets a region by its key.
+    pub fn get_region(&self, region: usize) -> Option<&Region> {
+        let index = self.index.get(&region)?;
+
+        self.heap.get(*index)?.as_ref()
+    }
+
+    /// Gets a mutable region by its key.
+    pub fn get_region_mut(&mut self, region: usize) -> Option<&mut Region> {
+        let index = self.index.get(&region)?;
+
+        self.heap.get_mut(*index)?.as_mut()
+    }
+
+    /// Gets the object the pointer points to.
+    pub fn get(&self, pointer: &Pointer) -> Result<&RwLock<AllocatedObject>, String> {
+        match self.get_region(pointer.region) {
+            Some(region) => match region.get(pointer.point_to) {
+                Some(object) => Ok(object),
+                None => Err(format!(
+                    "[ INVALID POINTER ] Region: {}, Object: {}",
+                    pointer.region, pointer.point_to
+                )),
+            },
+            None => Err(format!(
+                "[ INVALID HEAP REGION ] Region: {}",
+                pointer.region
+            )),
+        }
+    }
+
+    /// Gets a copy of the function the pointer points to.
+    pub fn get_function(&self, pointer: &Pointer) -> Result<Function, String> {
+        let object = self.get(pointer)?;
+
+        let object = match object.read() {
+            Ok(object) => object,
+            Err(err) => return Err(err.to_string()),
+        };
+
+        if let DataType::Function(function) = &object.data {
+            Ok(function.clone())
+        } else {
+            Err("[ NOT A FUNCTION ]".to_string())
+        }
+    }
+
+    /// Gets a handle to the mutex, condition or atomic the pointer points to.
+    pub fn get_sync(&self, pointer: &Pointer) -> Result<SyncObject, String> {
+        let object = self.get(pointer)?;
+
+        let object = match object.read() {
+            Ok(object) => object,
+            Err(err) => return Err(err.to_string()),
+        };
+
+        if let DataType::Sync(sync) = &object.data {
+            Ok(sync.clone())
+        } else {
+            Err("[ NOT A SYNC OBJECT ]".to_string())
+        }
+    }
 }
 
 debug_derive!(
@@ -144,6 +300,9 @@ debug_derive!(
         pub assigned_thread: Option<usize>,
         pub assigned_task: Option<usize>,
         pub global: bool,
+        pub used: usize,    // In bytes
+        pub objects: usize, // Number of objects stored
+        pub ended: bool,    // Its thread or task ended
     }
 );
 
@@ -155,8 +314,46 @@ impl Region {
             assigned_thread: thread,
             assigned_task: task,
             global,
+            used: 0,
+            objects: 0,
+            ended: false,
         }
     }
+
+    /// Stores the object in the first free slot and returns its key.
+    pub fn allocate(&mut self, object: AllocatedObject) -> usize {
+        let object_size = object.size;
+        let mut key = self.index.len();
+
+        while self.index.contains_key(&key) {
+            key += 1;
+        }
+
+        let index = match self.memory.iter().position(|slot| slot.is_none()) {
+            Some(index) => {
+                self.memory[index] = Some(RwLock::new(object));
+                index
+            }
+            None => {
+                self.memory.push(Some(RwLock::new(object)));
+                self.memory.len() - 1
+            }
+        };
+
+        self.used += object_size;
+        self.objects += 1;
+
+        self.index.insert(key, index);
+
+        key
+    }
+
+    /// Gets an object by its key.
+    pub fn get(&self, key: usize) -> Option<&RwLock<AllocatedObject>> {
+        let index = self.index.get(&key)?;
+
+        self.memory.get(*index)?.as_ref()
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -165,6 +362,7 @@ pub enum DataType {
     Function(Function),
     Composite(Composites),
     Pointer(Pointer),
+    Sync(SyncObject),
 }
 
 #[derive(Debug, Clone)]
@@ -176,17 +374,22 @@ pub enum Composites {
 
 #[derive(Debug, Clone)]
 pub struct Sequence {
-    data: Vec<DataType>,
-    length: usize,
+    pub(crate) data: Vec<DataType>,
+    pub(crate) length: usize,
 }
 
-#[derive(Debug, Clone)]
+/// Points to an object inside a heap region.
+#[derive(Debug, Clone, PartialEq, PartialOrd)]
 pub struct Pointer {
-    point_to: usize,
-    is_fn: bool,
-    thread: Option<usize>,
+    /// Key of the region that holds the object.
+    pub(crate) region: usize,
+    /// Key of the object inside the region.
+    pub(crate) point_to: usize,
+    /// Tells if the object pointed to is a function.
+    pub(crate) is_fn: bool,
 }
 
+/// Function object, loaded from the FNOBJSTART/FNOBJEND sections of a file.
 #[derive(Debug, Clone)]
 pub struct Function {
     pub(crate) instructions: Vec<u8>,
@@ -196,8 +399,8 @@ pub struct Function {
 
 #[derive(Debug, Clone)]
 pub struct Struct {
-    attributes: AHashMap<usize, Immediates>,
-    methods: AHashMap<usize, (Option<Function>, Option<Pointer>)>,
+    pub(crate) attributes: AHashMap<usize, Immediates>,
+    pub(crate) methods: AHashMap<usize, (Option<Function>, Option<Pointer>)>,
 }
 
 #[derive(Debug, Clone)]
@@ -208,42 +411,169 @@ pub struct AllocatedObject {
 
 impl AllocatedObject {
     pub fn new(data: DataType) -> Self {
-        let mut size = size_of_val(&data);
+        // The slot in the region memory plus everything the object owns outside of it.
+        let size = size_of::<Option<RwLock<AllocatedObject>>>() + data.retained_size();
 
-        if let DataType::Immediate(immediate) = &data {
-            if let Immediates::Integer(_) = immediate {
-                size += size_of::<i64>();
-            }
+        AllocatedObject { size, data }
+    }
+}
 
-            if let Immediates::Float(_) = immediate {
-                size += size_of::<f64>();
-            }
+/// Used for getting the bytes a value owns outside of its inline size,
+/// so the heap can account for strings, vectors and other buffers.
+pub trait RetainedSize {
+    /// Bytes owned by the value outside of its inline size.
+    fn retained_size(&self) -> usize;
+}
 
-            if let Immediates::Boolean(_) = immediate {
-                size += size_of::<bool>();
+impl RetainedSize for Immediates {
+    fn retained_size(&self) -> usize {
+        match self {
+            Immediates::StaticStr(string) => string.len(),
+            Immediates::MutStr(string) => string.capacity(),
+            Immediates::Binary(binary) => binary.capacity(),
+            Immediates::Array(array) => {
+                array.capacity() * size_of::<Immediates>()
+                    + array
+                        .iter()
+                        .map(|value| value.retained_size())
+                        .sum::<usize>()
             }
+            Immediates::Closure(closure) => size_of::<Closure>() + closure.captures.retained_size(),
+            _ => 0,
+        }
+    }
+}
+
+impl RetainedSize for Vec<Immediates> {
+    fn retained_size(&self) -> usize {
+        self.capacity() * size_of::<Immediates>()
+            + self
+                .iter()
+                .map(|value| value.retained_size())
+                .sum::<usize>()
+    }
+}
 
-            if let Immediates::UInteger(_) = immediate {
-                size += size_of::<u64>();
+impl RetainedSize for Function {
+    fn retained_size(&self) -> usize {
+        self.instructions.capacity() + self.data.retained_size()
+    }
+}
+
+impl RetainedSize for DataType {
+    fn retained_size(&self) -> usize {
+        match self {
+            DataType::Immediate(immediate) => immediate.retained_size(),
+            DataType::Function(function) => function.retained_size(),
+            DataType::Composite(Composites::Vector(vector)) => {
+                vector.capacity() * size_of::<DataType>()
+                    + vector
+                        .iter()
+                        .map(|value| value.retained_size())
+                        .sum::<usize>()
             }
+            DataType::Composite(Composites::Sequence(sequence)) => {
+                sequence.data.capacity() * size_of::<DataType>()
+                    + sequence
+                        .data
+                        .iter()
+                        .map(|value| value.retained_size())
+                        .sum::<usize>()
+            }
+            DataType::Composite(Composites::Struct(structure)) => {
+                structure.attributes.capacity() * size_of::<(usize, Immediates)>()
+                    + structure
+                        .attributes
+                        .values()
+                        .map(|value| value.retained_size())
+                        .sum::<usize>()
+                    + structure.methods.capacity()
+                        * size_of::<(usize, (Option<Function>, Option<Pointer>))>()
+                    + structure
+                        .methods
+                        .values()
+                        .filter_map(|(function, _)| function.as_ref())
+                        .map(|function| function.retained_size())
+                        .sum::<usize>()
+            }
+            DataType::Pointer(_) => 0,
+            DataType::Sync(object) => object.retained_size(),
+        }
+    }
+}
 
-            if let Immediates::MutStr(string) = immediate {
-                let string_size = string.len();
+/// Snapshot of a heap region counters.
+#[derive(Debug, Clone)]
+pub struct RegionStats {
+    pub key: usize,
+    pub objects: usize,
+    pub bytes: usize,
+    pub global: bool,
+    pub thread: Option<usize>,
+    pub task: Option<usize>,
+    /// Its thread or task ended, the objects are kept while they may still be pointed to.
+    pub ended: bool,
+}
 
-                size -= 24;
+/// Snapshot of the heap counters, used for sizing `--maxmem`.
+#[derive(Debug, Clone)]
+pub struct HeapStats {
+    /// Number of objects stored in the heap.
+    pub objects: usize,
+    /// Bytes being used by the objects.
+    pub bytes: usize,
+    /// Heap capacity (`--maxmem`) in bytes.
+    pub capacity: usize,
+    /// Highest usage reached, in bytes.
+    pub peak: usize,
+    /// Number of allocations made.
+    pub allocations: usize,
+    /// Number of garbage collector cycles.
+    ///
+    /// Always 0: there is no collector yet, objects are only released with their region.
+    pub gc_cycles: usize,
+    /// Counters of every region, sorted by key.
+    pub regions: Vec<RegionStats>,
+}
 
-                size += string_size;
+impl Display for HeapStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Heap stats:")?;
+        writeln!(f, "\tObjects => {}", self.objects)?;
+        writeln!(f, "\tUsed => {} B / {} B", self.bytes, self.capacity)?;
+        writeln!(f, "\tPeak => {} B", self.peak)?;
+        writeln!(f, "\tAllocations => {}", self.allocations)?;
+        writeln!(f, "\tGC cycles => {} (no collector yet)", self.gc_cycles)?;
+
+        let ended: Vec<&RegionStats> = self.regions.iter().filter(|region| region.ended).collect();
+
+        writeln!(
+            f,
+            "\tHeld by ended threads and tasks => {} B in {} regions",
+            ended.iter().map(|region| region.bytes).sum::<usize>(),
+            ended.len()
+        )?;
+        write!(f, "\tRegions => {}", self.regions.len())?;
+
+        for region in &self.regions {
+            let mut kind = match (region.global, region.thread, region.task) {
+                (true, _, _) => "global".to_string(),
+                (_, Some(thread), _) => format!("thread {thread}"),
+                (_, _, Some(task)) => format!("task {task}"),
+                _ => "main".to_string(),
             };
-        };
-
-        if let DataType::Function(func) = &data {
-            let idk2 = size_of_val(&func.data);
 
-            let idk = size_of_val(&func.instructions);
+            if region.ended {
+                kind.push_str(", ended");
+            }
 
-            println!("{} {}", idk2, idk);
-        };
+            write!(
+                f,
+                "\n\t\tRegion {} ({}) => {} objects, {} B",
+                region.key, kind, region.objects, region.bytes
+            )?;
+        }
 
-        AllocatedObject { size, data }
+        Ok(())
     }
 }
@@ -0,0 +1,450 @@
+//! Writes the heap to a file so it can be analysed offline,
+//! and summarizes those files for the `heapdump` subcommand.
+//!
+//! ## File format
+//!
+//! The dump is a UTF-8 text file with one record per line.
+//! Fields are separated by a single space and pointers are written as `region:object`.
+//!
+//! ```text
+//! SQUIDVM-HEAPDUMP 1
+//! HEAP <capacity> <used> <peak> <allocations> <gc cycles>
+//! REGION <key> <main|global|thread|task> <objects> <bytes>
+//! OBJECT <region>:<object> <type> <bytes>
+//! EDGE <region>:<object> <region>:<object>
+//! ROOT <stack|global|function|current-function> <index> <region>:<object>
+//! GLOBAL <name> <value>
+//! ```
+//!
+//! * `HEAP` is always the second line; sizes are in bytes.
+//! * `OBJECT` lines come right after the `REGION` they belong to.
+//! * `EDGE` lines go from the object holding a pointer to the object pointed to.
+//! * `ROOT` lines are the pointers the VM holds outside the heap.
+//! * `ROOT stack`, `function` and `current-function` lines belong to the thread that wrote the dump:
+//!   the main thread, or the thread or task that ran out of memory.
+//!   The stacks of the other threads and tasks aren't roots,
+//!   so objects only they point to show up as unreachable.
+//! * `ROOT global` lines use the variable name as index.
+//! * `GLOBAL` lines are the repository entries, the value (debug formatted) takes the rest of the line.
+//! * Lines starting with `#` are comments and should be ignored by readers.
+
+use crate::vm_internals::heap::{Composites, DataType, Pointer, VMHeap, HEAP_OVERFLOW};
+use crate::vm_internals::immediates::{ImmediateType, Immediates};
+use crate::vm_internals::{VMRepository, VMStack};
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::sync::RwLock;
+
+/// First line of every heap dump file, contains the format version.
+pub const HEAP_DUMP_HEADER: &str = "SQUIDVM-HEAPDUMP 1";
+
+/// A pointer held by the VM outside the heap (stack values, loaded functions...).
+pub struct HeapRoot {
+    /// Where the pointer is held: `stack`, `global`, `function` or `current-function`.
+    pub kind: &'static str,
+    /// Position of the pointer inside its holder.
+    pub index: usize,
+    /// The pointer itself.
+    pub pointer: Pointer,
+}
+
+/// Gets the heap pointers a thread holds outside the heap:
+/// its stack, the function objects loaded from the file and the function being run.
+pub fn thread_roots(
+    stack: &VMStack,
+    functions: &[Pointer],
+    current_function: Option<&Pointer>,
+) -> Vec<HeapRoot> {
+    let mut roots = Vec::new();
+
+    for (index, value) in stack.stack_memory.iter().enumerate() {
+        let mut pointers = Vec::new();
+        immediate_references(value, &mut pointers);
+
+        roots.extend(pointers.into_iter().map(|pointer| HeapRoot {
+            kind: "stack",
+            index,
+            pointer,
+        }));
+    }
+
+    for (index, pointer) in functions.iter().enumerate() {
+        roots.push(HeapRoot {
+            kind: "function",
+            index,
+            pointer: pointer.clone(),
+        });
+    }
+
+    if let Some(pointer) = current_function {
+        roots.push(HeapRoot {
+            kind: "current-function",
+            index: 0,
+            pointer: pointer.clone(),
+        });
+    }
+
+    roots
+}
+
+/// Writes the heap to the file with the thread's `roots`, adding the repository globals.
+pub fn dump_heap(
+    heap: &RwLock<VMHeap>,
+    repository: &RwLock<VMRepository>,
+    mut roots: Vec<HeapRoot>,
+    path: &str,
+) -> Result<(), String> {
+    let repository = repository.read().unwrap();
+
+    for (name, value) in repository.repo_vars.iter() {
+        let mut pointers = Vec::new();
+        immediate_references(value, &mut pointers);
+
+        roots.extend(pointers.into_iter().map(|pointer| HeapRoot {
+            kind: "global",
+            index: *name,
+            pointer,
+        }));
+    }
+
+    let mut globals: Vec<(usize, String)> = repository
+        .repo_vars
+        .iter()
+        .map(|(name, value)| (*name, format!("{value:?}")))
+        .collect();
+
+    globals.sort();
+    drop(repository);
+
+    write_heap_dump(&heap.read().unwrap(), &roots, &globals, path)
+}
+
+/// Allocates the data in the heap region, used by the main thread and the VM threads/tasks.
+///
+/// If the heap runs out of memory and `dump_path` is set, the heap is dumped there
+/// with `roots`, the pointers held by the thread allocating, and the error tells where.
+pub fn allocate(
+    heap: &RwLock<VMHeap>,
+    repository: &RwLock<VMRepository>,
+    dump_path: Option<&str>,
+    region: usize,
+    data: DataType,
+    roots: impl FnOnce() -> Vec<HeapRoot>,
+) -> Result<Pointer, String> {
+    let result = heap.write().unwrap().allocate(region, data);
+
+    match (result, dump_path) {
+        (Err(err), Some(path)) if err.starts_with(HEAP_OVERFLOW) => {
+            match dump_heap(heap, repository, roots(), path) {
+                Ok(_) => Err(format!("{err} | Heap dumped to {path}")),
+                Err(dump_err) => Err(format!("{err} | {dump_err}")),
+            }
+        }
+        (result, _) => result,
+    }
+}
+
+/// Gets the name used for the object type in the heap dump.
+pub fn type_name(data: &DataType) -> String {
+    match data {
+        DataType::Immediate(immediate) => format!("Immediate.{:?}", immediate.to_immediate_type()),
+        DataType::Function(_) => "Function".to_string(),
+        DataType::Composite(Composites::Vector(_)) => "Composite.Vector".to_string(),
+        DataType::Composite(Composites::Sequence(_)) => "Composite.Sequence".to_string(),
+        DataType::Composite(Composites::Struct(_)) => "Composite.Struct".to_string(),
+        DataType::Pointer(_) => "Pointer".to_string(),
+        DataType::Sync(object) => format!("Sync.{}", object.kind()),
+    }
+}
+
+/// Gets the pointers an immediate holds, inside arrays and closures too.
+fn immediate_references(immediate: &Immediates, references: &mut Vec<Pointer>) {
+    match immediate {
+        Immediates::RefPtr(pointer) => references.push(pointer.clone()),
+        Immediates::Array(array) => {
+            for value in array {
+                immediate_references(value, references);
+            }
+        }
+        Immediates::Closure(closure) => {
+            references.push(closure.function.clone());
+
+            for value in &closure.captures {
+                immediate_references(value, references);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Gets the pointers an object holds, used for the pointer graph.
+pub fn references(data: &DataType) -> Vec<Pointer> {
+    let mut references = Vec::new();
+
+    match data {
+        DataType::Immediate(immediate) => immediate_references(immediate, &mut references),
+        DataType::Function(function) => {
+            for value in &function.data {
+                immediate_references(value, &mut references);
+            }
+        }
+        DataType::Composite(Composites::Vector(vector)) => {
+            for value in vector {
+                references.append(&mut self::references(value));
+            }
+        }
+        DataType::Composite(Composites::Sequence(sequence)) => {
+            for value in &sequence.data {
+                references.append(&mut self::references(value));
+            }
+        }
+        DataType::Composite(Composites::Struct(structure)) => {
+            for value in structure.attributes.values() {
+                immediate_references(value, &mut references);
+            }
+            for (_, pointer) in structure.methods.values() {
+                if let Some(pointer) = pointer {
+                    references.push(pointer.clone());
+                }
+            }
+        }
+        DataType::Pointer(pointer) => references.push(pointer.clone()),
+        DataType::Sync(_) => {}
+    }
+
+    references
+}
+
+/// Writes all the heap regions, objects, the pointer graph, the roots
+/// and the repository globals to the file in the heap dump format.
+pub fn write_heap_dump(
+    heap: &VMHeap,
+    roots: &[HeapRoot],
+    globals: &[(usize, String)],
+    path: &str,
+) -> Result<(), String> {
+    let file = File::create(path).map_err(|err| format!("Can't create heap dump: {err}"))?;
+    let mut writer = BufWriter::new(file);
+
+    write_records(heap, roots, globals, &mut writer)
+        .and_then(|_| writer.flush())
+        .map_err(|err| format!("Can't write heap dump: {err}"))
+}
+
+fn write_records<W: Write>(
+    heap: &VMHeap,
+    roots: &[HeapRoot],
+    globals: &[(usize, String)],
+    writer: &mut W,
+) -> std::io::Result<()> {
+    writeln!(writer, "{HEAP_DUMP_HEADER}")?;
+    writeln!(
+        writer,
+        "HEAP {} {} {} {} {}",
+        heap.capacity,
+        heap.used(),
+        heap.peak,
+        heap.allocations,
+        heap.gc_cycles
+    )?;
+
+    let mut edges = Vec::new();
+
+    let mut region_keys: Vec<&usize> = heap.index.keys().collect();
+    region_keys.sort();
+
+    for region_key in region_keys {
+        let region = match heap.get_region(*region_key) {
+            Some(region) => region,
+            None => continue,
+        };
+
+        let kind = match (region.global, region.assigned_thread, region.assigned_task) {
+            (true, _, _) => "global",
+            (_, Some(_), _) => "thread",
+            (_, _, Some(_)) => "task",
+            _ => "main",
+        };
+
+        writeln!(
+            writer,
+            "REGION {} {} {} {}",
+            region_key, kind, region.objects, region.used
+        )?;
+
+        let mut object_keys: Vec<&usize> = region.index.keys().collect();
+        object_keys.sort();
+
+        for object_key in object_keys {
+            let object = match region.get(*object_key) {
+                Some(object) => object,
+                None => continue,
+            };
+
+            // A poisoned object is still dumped, it's exactly the kind of thing worth looking at.
+            let object = match object.read() {
+                Ok(object) => object,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+
+            writeln!(
+                writer,
+                "OBJECT {}:{} {} {}",
+                region_key,
+                object_key,
+                type_name(&object.data),
+                object.size
+            )?;
+
+            for pointer in references(&object.data) {
+                edges.push((*region_key, *object_key, pointer));
+            }
+        }
+    }
+
+    for (region, object, pointer) in edges {
+        writeln!(
+            writer,
+            "EDGE {}:{} {}:{}",
+            region, object, pointer.region, pointer.point_to
+        )?;
+    }
+
+    for root in roots {
+        writeln!(
+            writer,
+            "ROOT {} {} {}:{}",
+            root.kind, root.index, root.pointer.region, root.pointer.point_to
+        )?;
+    }
+
+    for (name, value) in globals {
+        writeln!(writer, "GLOBAL {} {}", name, value)?;
+    }
+
+    Ok(())
+}
+
+/// Totals of a single object type inside a heap dump.
+#[derive(Debug, Clone)]
+pub struct TypeSummary {
+    pub type_name: String,
+    pub objects: usize,
+    pub bytes: usize,
+}
+
+/// Summary of a heap dump file, used by the `heapdump` subcommand.
+#[derive(Debug, Clone)]
+pub struct HeapDumpSummary {
+    pub capacity: usize,
+    pub used: usize,
+    pub peak: usize,
+    pub regions: usize,
+    pub objects: usize,
+    pub edges: usize,
+    pub roots: usize,
+    pub globals: usize,
+    /// Object types sorted by the bytes they use, largest first.
+    pub types: Vec<TypeSummary>,
+}
+
+/// Reads a heap dump file and sums the objects by type.
+pub fn summarize(path: &str) -> Result<HeapDumpSummary, String> {
+    let file = File::open(path).map_err(|err| format!("Can't open heap dump: {err}"))?;
+    let mut lines = BufReader::new(file).lines();
+
+    match lines.next() {
+        Some(Ok(header)) if header == HEAP_DUMP_HEADER => {}
+        _ => return Err(format!("{path} is not a SquidVM heap dump!")),
+    }
+
+    let mut summary = HeapDumpSummary {
+        capacity: 0,
+        used: 0,
+        peak: 0,
+        regions: 0,
+        objects: 0,
+        edges: 0,
+        roots: 0,
+        globals: 0,
+        types: Vec::new(),
+    };
+
+    let mut types: HashMap<String, (usize, usize)> = HashMap::new();
+
+    for (number, line) in lines.enumerate() {
+        let line = line.map_err(|err| format!("Can't read heap dump: {err}"))?;
+        let fields: Vec<&str> = line.split(' ').collect();
+
+        let invalid = || format!("Invalid heap dump record at line {}: {}", number + 2, line);
+
+        let number_at = |index: usize| -> Result<usize, String> {
+            fields
+                .get(index)
+                .and_then(|field| field.parse().ok())
+                .ok_or_else(invalid)
+        };
+
+        match fields[0] {
+            "HEAP" => {
+                summary.capacity = number_at(1)?;
+                summary.used = number_at(2)?;
+                summary.peak = number_at(3)?;
+            }
+            "REGION" => summary.regions += 1,
+            "OBJECT" => {
+                let type_name = fields.get(2).ok_or_else(invalid)?;
+                let entry = types.entry(type_name.to_string()).or_insert((0, 0));
+                entry.0 += 1;
+                entry.1 += number_at(3)?;
+                summary.objects += 1;
+            }
+            "EDGE" => summary.edges += 1,
+            "ROOT" => summary.roots += 1,
+            "GLOBAL" => summary.globals += 1,
+            "" => {}
+            comment if comment.starts_with('#') => {}
+            _ => return Err(invalid()),
+        }
+    }
+
+    summary.types = types
+        .into_iter()
+        .map(|(type_name, (objects, bytes))| TypeSummary {
+            type_name,
+            objects,
+            bytes,
+        })
+        .collect();
+
+    summary
+        .types
+        .sort_by(|a, b| b.bytes.cmp(&a.bytes).then(a.type_name.cmp(&b.type_name)));
+
+    Ok(summary)
+}
+
+impl Display for HeapDumpSummary {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Used => {} B / {} B", self.used, self.capacity)?;
+        writeln!(f, "Peak => {} B", self.peak)?;
+        writeln!(
+            f,
+            "Regions => {}, Objects => {}, Edges => {}, Roots => {}, Globals => {}",
+            self.regions, self.objects, self.edges, self.roots, self.globals
+        )?;
+        write!(f, "Largest object types:")?;
+
+        for summary in &self.types {
+            write!(
+                f,
+                "\n\t{} => {} objects, {} B",
+                summary.type_name, summary.objects, summary.bytes
+            )?;
+        }
+
+        Ok(())
+    }
+}
@@ -1,3 +1,5 @@
+use crate::vm_internals::channel::VMChannel;
+use crate::vm_internals::heap::Pointer;
 use std::fmt::{Display, Formatter};
 use std::mem;
 use std::sync::Arc;
@@ -23,6 +25,23 @@ pub enum Immediates {
     Binary(Vec<u8>),
     /// Array of Immediates type
     Array(Vec<Immediates>),
+    /// Reference/Pointer to a heap object
+    RefPtr(Pointer),
+    /// Channel between threads and tasks
+    Channel(VMChannel),
+    /// Function with the values it captured, created by `CLOSURE`
+    Closure(Box<Closure>),
+}
+
+/// ## Function object with the values it captured
+///
+/// It's a value instead of a heap object, so it's released with the last copy holding it.
+#[derive(Clone, PartialEq, PartialOrd, Debug)]
+pub struct Closure {
+    /// Pointer to the function object.
+    pub function: Pointer,
+    /// Values pushed to the stack before the function runs.
+    pub captures: Vec<Immediates>,
 }
 
 impl Display for Immediates {
@@ -55,6 +74,19 @@ impl Display for Immediates {
             Immediates::Array(arr) => {
                 write!(f, "{:?}", arr)
             }
+            Immediates::RefPtr(ptr) => {
+                write!(f, "Pointer({}:{})", ptr.region, ptr.point_to)
+            }
+            Immediates::Channel(channel) => {
+                write!(f, "Channel({})", channel.id)
+            }
+            Immediates::Closure(closure) => {
+                write!(
+                    f,
+                    "Closure({}:{})",
+                    closure.function.region, closure.function.point_to
+                )
+            }
         }
     }
 }
@@ -82,6 +114,10 @@ pub enum ImmediatesType {
     Array,
     /// Reference/Pointer type
     RefPtr,
+    /// Channel type
+    Channel,
+    /// Closure type
+    Closure,
 }
 
 /// ## Creates a function to serialize Immediates to sequences of bytes
@@ -110,6 +146,9 @@ impl ImmediateType for Immediates {
             Immediates::MutStr(_) => ImmediatesType::MutStr,
             Immediates::Binary(_) => ImmediatesType::Binary,
             Immediates::Array(_) => ImmediatesType::Array,
+            Immediates::RefPtr(_) => ImmediatesType::RefPtr,
+            Immediates::Channel(_) => ImmediatesType::Channel,
+            Immediates::Closure(_) => ImmediatesType::Closure,
         }
     }
 }
@@ -149,6 +188,15 @@ impl Serialize for Immediates {
             Immediates::Array(_) => {
                 panic!("Array not permited for instance");
             }
+            Immediates::RefPtr(_) => {
+                panic!("Pointer not permited for instance");
+            }
+            Immediates::Channel(_) => {
+                panic!("Channel not permited for instance");
+            }
+            Immediates::Closure(_) => {
+                panic!("Closure not permited for instance");
+            }
         }
     }
 
@@ -177,6 +225,15 @@ impl Serialize for Immediates {
             Immediates::Array(_) => {
                 panic!("Array not permitted for instance");
             }
+            Immediates::RefPtr(_) => {
+                panic!("Pointer not permitted for instance");
+            }
+            Immediates::Channel(_) => {
+                panic!("Channel not permitted for instance");
+            }
+            Immediates::Closure(_) => {
+                panic!("Closure not permitted for instance");
+            }
         }
     }
 }
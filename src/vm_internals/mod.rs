@@ -5,6 +5,18 @@ pub mod vm;
 /// ## VM's heap and garbage collector implementation
 pub mod heap;
 
+/// ## Channels used for passing values between threads and tasks
+pub mod channel;
+
+/// ## Try regions and exceptions
+pub mod exceptions;
+
+/// ## Call frames shared by the main thread and the threads
+pub mod frames;
+
+/// ## Heap dumps for offline analysis
+pub mod heap_dump;
+
 /// ## VM's Immediate types and other implementations
 pub mod immediates;
 
@@ -12,9 +24,28 @@ pub mod immediates;
 /// Can be used for global variables.
 pub mod repository;
 
+/// ## Native functions declared by the running Squid ARchive
+pub mod native;
+
+/// ## Resources of the running Squid ARchive
+pub mod resources;
+
 /// ## VM's stack implementation
 pub mod stack;
 
+/// ## Green-thread scheduler running the tasks
+#[cfg(feature = "green-threads")]
+pub mod scheduler;
+
+/// ## Supervisor policies and errors of failed threads
+pub mod supervisor;
+
+/// ## Mutexes, conditions and atomics usable from bytecode
+pub mod sync_objects;
+
+/// ## Thread pool running the threads
+pub mod thread_pool;
+
 /// ## Threads implementation
 pub mod vm_threads;
 
@@ -0,0 +1,324 @@
+//! Native functions of the shared libraries declared by the running Squid ARchive.
+//!
+//! `CALLNATIVE` pops the name of the function, then its arguments (the last one on top),
+//! converting them to the C types of its signature. The result is pushed unless it returns `void`.
+//! Libraries are loaded with `dlopen` (through `libloading`) and called through `libffi`,
+//! which needs the `native` feature.
+
+use crate::vm_internals::immediates::Immediates;
+use crate::vm_internals::VMStack;
+#[cfg(feature = "native")]
+use std::collections::HashMap;
+#[cfg(feature = "sar")]
+use std::fmt::{Display, Formatter};
+#[cfg(feature = "native")]
+use std::sync::Arc;
+
+/// ## C types native functions take and return.
+///
+/// `str` is a NUL-terminated `const char *`: strings returned are copied, but never freed.
+#[cfg(feature = "sar")]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NativeType {
+    Void,
+    Bool,
+    I32,
+    I64,
+    U32,
+    U64,
+    F32,
+    F64,
+    Str,
+}
+
+#[cfg(feature = "sar")]
+impl NativeType {
+    /// Parses a type as written in the manifest, like `i64` or `str`.
+    pub fn parse(name: &str) -> Option<Self> {
+        Some(match name {
+            "void" => NativeType::Void,
+            "bool" => NativeType::Bool,
+            "i32" => NativeType::I32,
+            "i64" => NativeType::I64,
+            "u32" => NativeType::U32,
+            "u64" => NativeType::U64,
+            "f32" => NativeType::F32,
+            "f64" => NativeType::F64,
+            "str" => NativeType::Str,
+            _ => return None,
+        })
+    }
+}
+
+#[cfg(feature = "sar")]
+impl Display for NativeType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            NativeType::Void => "void",
+            NativeType::Bool => "bool",
+            NativeType::I32 => "i32",
+            NativeType::I64 => "i64",
+            NativeType::U32 => "u32",
+            NativeType::U64 => "u64",
+            NativeType::F32 => "f32",
+            NativeType::F64 => "f64",
+            NativeType::Str => "str",
+        };
+
+        write!(f, "{name}")
+    }
+}
+
+/// Parameters and result of a native function.
+#[cfg(feature = "sar")]
+#[derive(Debug, Clone, PartialEq)]
+pub struct NativeSignature {
+    pub params: Vec<NativeType>,
+    pub returns: NativeType,
+}
+
+/// Address of a native function, valid while its library is loaded.
+#[cfg(feature = "native")]
+#[derive(Debug, Clone, Copy)]
+struct Address(*const std::ffi::c_void);
+
+// Code addresses are only read, and their libraries are kept with them.
+#[cfg(feature = "native")]
+unsafe impl Send for Address {}
+#[cfg(feature = "native")]
+unsafe impl Sync for Address {}
+
+#[cfg(feature = "native")]
+#[derive(Debug)]
+struct NativeFunction {
+    signature: NativeSignature,
+    address: Address,
+}
+
+#[cfg(feature = "native")]
+#[derive(Debug, Default)]
+struct Loaded {
+    functions: HashMap<String, NativeFunction>,
+    /// Keeps the libraries loaded while their functions can be called.
+    _libraries: Vec<libloading::Library>,
+}
+
+/// ## Native functions of the running Squid ARchive, by name.
+///
+/// Cloning it gives another handle to the same functions, so every thread calls them.
+/// Programs not run from a SAR declaring native libraries have none.
+#[derive(Debug, Clone, Default)]
+pub struct NativeFunctions(#[cfg(feature = "native")] Arc<Loaded>);
+
+impl NativeFunctions {
+    /// Loads the libraries, `(location, functions)`, and finds every function declared for them.
+    ///
+    /// Locations with a `/` are paths, others are searched by the system loader.
+    #[cfg(feature = "native")]
+    pub fn load(libraries: Vec<(String, Vec<(String, NativeSignature)>)>) -> Result<Self, String> {
+        let mut loaded = Loaded::default();
+
+        for (location, functions) in libraries {
+            // Running the initializers of a library is as unsafe as calling its functions,
+            // which the archive asked for with the native capability.
+            let library = unsafe { libloading::Library::new(&location) }
+                .map_err(|err| format!("Couldn't load the native library {location}: {err}"))?;
+
+            for (name, signature) in functions {
+                let symbol = unsafe { library.get::<*const std::ffi::c_void>(name.as_bytes()) }
+                    .map_err(|err| format!("Couldn't find {name} in {location}: {err}"))?;
+
+                loaded.functions.insert(
+                    name,
+                    NativeFunction {
+                        signature,
+                        address: Address(*symbol),
+                    },
+                );
+            }
+
+            loaded._libraries.push(library);
+        }
+
+        Ok(NativeFunctions(Arc::new(loaded)))
+    }
+}
+
+/// Pops the name of a native function from the stack.
+fn pop_name(stack: &mut VMStack) -> Result<String, String> {
+    match stack.pop()? {
+        Immediates::MutStr(name) => Ok(name),
+        Immediates::StaticStr(name) => Ok(name.to_string()),
+        _ => Err("[ INVALID NATIVE FUNCTION NAME ]".to_string()),
+    }
+}
+
+/// Runs `CALLNATIVE`, shared by the main thread and the VM threads/tasks.
+pub fn native_instruction(natives: &NativeFunctions, stack: &mut VMStack) -> Result<(), String> {
+    let name = pop_name(stack)?;
+
+    #[cfg(not(feature = "native"))]
+    {
+        let _ = natives;
+        Err(format!("[ NATIVE CALLS NOT SUPPORTED ] {name}"))
+    }
+
+    #[cfg(feature = "native")]
+    {
+        let function = natives
+            .0
+            .functions
+            .get(&name)
+            .ok_or_else(|| format!("[ NATIVE FUNCTION NOT FOUND ] {name}"))?;
+
+        let mut args = Vec::with_capacity(function.signature.params.len());
+
+        for _ in &function.signature.params {
+            args.push(stack.pop()?);
+        }
+
+        args.reverse();
+
+        if let Some(result) = call(&name, function, args)? {
+            stack.push(result)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(feature = "native")]
+fn call(
+    name: &str,
+    function: &NativeFunction,
+    args: Vec<Immediates>,
+) -> Result<Option<Immediates>, String> {
+    use libffi::low::ffi_arg;
+    use libffi::middle::{Arg, Cif, CodePtr};
+    use std::ffi::{c_char, CStr, CString};
+
+    /// C values of the arguments, kept while the function runs.
+    enum Value {
+        Bool(u8),
+        I32(i32),
+        I64(i64),
+        U32(u32),
+        U64(u64),
+        F32(f32),
+        F64(f64),
+        Str(*const c_char),
+    }
+
+    /// Converts an integer to a C integer type, `None` if it doesn't fit.
+    fn narrow<T: TryFrom<i128>>(integer: Option<i128>) -> Option<T> {
+        integer?.try_into().ok()
+    }
+
+    let signature = &function.signature;
+    let mut strings = Vec::new();
+    let mut values = Vec::with_capacity(args.len());
+
+    for (index, (kind, arg)) in signature.params.iter().zip(args).enumerate() {
+        let invalid =
+            || format!("[ INVALID NATIVE ARGUMENT ] {name}: argument {index} must be {kind}");
+
+        let integer = match &arg {
+            Immediates::Integer(value) => Some(*value as i128),
+            Immediates::UInteger(value) => Some(*value as i128),
+            _ => None,
+        };
+
+        let value = match (kind, arg) {
+            (NativeType::Bool, Immediates::Boolean(value)) => Value::Bool(value as u8),
+            (NativeType::I32, _) => Value::I32(narrow(integer).ok_or_else(invalid)?),
+            (NativeType::I64, _) => Value::I64(narrow(integer).ok_or_else(invalid)?),
+            (NativeType::U32, _) => Value::U32(narrow(integer).ok_or_else(invalid)?),
+            (NativeType::U64, _) => Value::U64(narrow(integer).ok_or_else(invalid)?),
+            (NativeType::F32, Immediates::Float(value)) => Value::F32(value as f32),
+            (NativeType::F64, Immediates::Float(value)) => Value::F64(value),
+            (NativeType::Str, Immediates::MutStr(string)) => {
+                let string = CString::new(string).map_err(|_| invalid())?;
+                let pointer = string.as_ptr();
+                strings.push(string);
+                Value::Str(pointer)
+            }
+            (NativeType::Str, Immediates::StaticStr(string)) => {
+                let string = CString::new(string.as_bytes()).map_err(|_| invalid())?;
+                let pointer = string.as_ptr();
+                strings.push(string);
+                Value::Str(pointer)
+            }
+            _ => return Err(invalid()),
+        };
+
+        values.push(value);
+    }
+
+    let args: Vec<Arg> = values
+        .iter()
+        .map(|value| match value {
+            Value::Bool(value) => Arg::new(value),
+            Value::I32(value) => Arg::new(value),
+            Value::I64(value) => Arg::new(value),
+            Value::U32(value) => Arg::new(value),
+            Value::U64(value) => Arg::new(value),
+            Value::F32(value) => Arg::new(value),
+            Value::F64(value) => Arg::new(value),
+            Value::Str(value) => Arg::new(value),
+        })
+        .collect();
+
+    let cif = Cif::new(
+        signature.params.iter().map(|kind| ffi_type(*kind)),
+        ffi_type(signature.returns),
+    );
+
+    let code = CodePtr::from_ptr(function.address.0);
+
+    // The signature comes from the manifest, so the archive vouches for it.
+    // Integers smaller than a register are returned widened to `ffi_arg`.
+    let result = unsafe {
+        match signature.returns {
+            NativeType::Void => {
+                cif.call::<()>(code, &args);
+                return Ok(None);
+            }
+            NativeType::Bool => Immediates::Boolean(cif.call::<ffi_arg>(code, &args) as u8 != 0),
+            NativeType::I32 => Immediates::Integer(cif.call::<ffi_arg>(code, &args) as i32 as i64),
+            NativeType::I64 => Immediates::Integer(cif.call::<i64>(code, &args)),
+            NativeType::U32 => Immediates::UInteger(cif.call::<ffi_arg>(code, &args) as u32 as u64),
+            NativeType::U64 => Immediates::UInteger(cif.call::<u64>(code, &args)),
+            NativeType::F32 => Immediates::Float(cif.call::<f32>(code, &args) as f64),
+            NativeType::F64 => Immediates::Float(cif.call::<f64>(code, &args)),
+            NativeType::Str => {
+                let pointer = cif.call::<*const c_char>(code, &args);
+
+                if pointer.is_null() {
+                    Immediates::Null
+                } else {
+                    Immediates::MutStr(CStr::from_ptr(pointer).to_string_lossy().into_owned())
+                }
+            }
+        }
+    };
+
+    Ok(Some(result))
+}
+
+/// libffi type of a native type.
+#[cfg(feature = "native")]
+fn ffi_type(kind: NativeType) -> libffi::middle::Type {
+    use libffi::middle::Type;
+
+    match kind {
+        NativeType::Void => Type::void(),
+        NativeType::Bool => Type::u8(),
+        NativeType::I32 => Type::i32(),
+        NativeType::I64 => Type::i64(),
+        NativeType::U32 => Type::u32(),
+        NativeType::U64 => Type::u64(),
+        NativeType::F32 => Type::f32(),
+        NativeType::F64 => Type::f64(),
+        NativeType::Str => Type::pointer(),
+    }
+}
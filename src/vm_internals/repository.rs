@@ -1,3 +1,5 @@
+use crate::vm_internals::immediates::Immediates;
+
 #[cfg(not(feature = "ahash_algorithm"))]
 use std::collections::HashMap;
 
@@ -8,14 +10,15 @@ debug_derive!(
     /// ## Repository struct implementation.
     ///
     /// Used mostly for global variables.
+    /// Values are stored as they are, heap objects are stored as pointers (`RefPtr`).
     pub struct VMRepository {
-        /// Contains pointers to the heap (FNV algorithm)
+        /// Contains the global values (AHash algorithm)
         #[cfg(feature = "ahash_algorithm")]
-        pub repo_var_pointers: AHashMap<usize, usize>,
+        pub repo_vars: AHashMap<usize, Immediates>,
 
-        /// Contains pointers to the heap (Default algorithm)
+        /// Contains the global values (Default algorithm)
         #[cfg(not(feature = "ahash_algorithm"))]
-        pub repo_var_pointers: HashMap<usize, usize>,
+        pub repo_vars: HashMap<usize, Immediates>,
 
         /// Contains the repository's capacity
         pub repo_capacity: usize,
@@ -27,55 +30,106 @@ impl VMRepository {
     pub fn new(repo_capacity: usize) -> Self {
         VMRepository {
             #[cfg(feature = "ahash_algorithm")]
-            repo_var_pointers: AHashMap::with_capacity_and_hasher(
-                repo_capacity,
-                Default::default(),
-            ),
+            repo_vars: AHashMap::with_capacity_and_hasher(repo_capacity, Default::default()),
             #[cfg(not(feature = "ahash_algorithm"))]
-            repo_var_pointers: HashMap::with_capacity_and_hasher(repo_capacity, Default::default()),
+            repo_vars: HashMap::with_capacity_and_hasher(repo_capacity, Default::default()),
             repo_capacity,
         }
     }
 
-    /// Add a variable to the repository, containing the "name"/address,
-    /// and the data/pointer (The pointer points to the heap);
-    pub fn add_var(&mut self, var_name: usize, var_data: usize) {
-        if self.repo_capacity == self.repo_var_pointers.len() {
-            panic!("[ HEAP OVERFLOW ]")
+    /// Add a variable to the repository, containing the "name"/address and its value.
+    ///
+    /// Replaces the value if the variable already exists.
+    pub fn add_var(&mut self, var_name: usize, var_data: Immediates) -> Result<(), String> {
+        if self.repo_capacity == self.repo_vars.len() && !self.repo_vars.contains_key(&var_name) {
+            return Err(format!(
+                "[ REPOSITORY OVERFLOW ] Repository Capacity: {}",
+                self.repo_capacity
+            ));
         }
 
-        self.repo_var_pointers.insert(var_name, var_data);
+        self.repo_vars.insert(var_name, var_data);
+
+        Ok(())
     }
 
-    /// Gets variable pointer from the repository.
-    pub fn get_var(&mut self, var_name: usize) -> &usize {
-        self.repo_var_pointers
+    /// Gets variable value from the repository.
+    pub fn get_var(&self, var_name: usize) -> Result<&Immediates, String> {
+        self.repo_vars
             .get(&var_name)
-            .expect("Variable don't exist!")
+            .ok_or_else(|| format!("[ UNDEFINED VARIABLE ] Variable: {var_name}"))
     }
 
-    /// Pops variable from the repository and return it's pointer.
-    pub fn pop_var(&mut self, var_name: usize) -> usize {
-        if self.repo_var_pointers.is_empty() {
-            panic!("[ HEAP UNDERFLOW ]")
-        }
-
-        self.repo_var_pointers
+    /// Pops variable from the repository and return it's value.
+    pub fn pop_var(&mut self, var_name: usize) -> Result<Immediates, String> {
+        self.repo_vars
             .remove(&var_name)
-            .expect("Variable don't exist!")
+            .ok_or_else(|| format!("[ UNDEFINED VARIABLE ] Variable: {var_name}"))
     }
 
     /// (_Advanced_): Removes variable from the repository.
-    pub fn remove_var(&mut self, var_name: usize) {
-        if !self.repo_var_pointers.contains_key(&var_name) {
-            panic!("[ UNDEFINED VARIABLE ADDRESS ]")
+    pub fn remove_var(&mut self, var_name: usize) -> Result<(), String> {
+        self.pop_var(var_name).map(|_| ())
+    }
+
+    /// Atomically replaces the variable value with `new` if it's equal to `expected`.
+    ///
+    /// Returns if the value was replaced.
+    /// Is atomic as long as it's called with the repository write lock held.
+    pub fn compare_and_swap(
+        &mut self,
+        var_name: usize,
+        expected: &Immediates,
+        new: Immediates,
+    ) -> Result<bool, String> {
+        let value = self
+            .repo_vars
+            .get_mut(&var_name)
+            .ok_or_else(|| format!("[ UNDEFINED VARIABLE ] Variable: {var_name}"))?;
+
+        if value == expected {
+            *value = new;
+            Ok(true)
+        } else {
+            Ok(false)
         }
+    }
+
+    /// Atomically adds `delta` to the variable value and returns the old value.
+    ///
+    /// Works with Integers, UIntegers and Floats, both values must have the same type.
+    pub fn fetch_add(&mut self, var_name: usize, delta: Immediates) -> Result<Immediates, String> {
+        let value = self
+            .repo_vars
+            .get_mut(&var_name)
+            .ok_or_else(|| format!("[ UNDEFINED VARIABLE ] Variable: {var_name}"))?;
+
+        let new = match (&*value, delta) {
+            (Immediates::Integer(v1), Immediates::Integer(v2)) => {
+                Immediates::Integer(v1.wrapping_add(v2))
+            }
+            (Immediates::UInteger(v1), Immediates::UInteger(v2)) => {
+                Immediates::UInteger(v1.wrapping_add(v2))
+            }
+            (Immediates::Float(v1), Immediates::Float(v2)) => Immediates::Float(v1 + v2),
+            _ => return Err("[ MISMATCHED TYPES ]".to_string()),
+        };
+
+        Ok(std::mem::replace(value, new))
+    }
+
+    /// Atomically replaces the variable value and returns the old value.
+    pub fn swap(&mut self, var_name: usize, new: Immediates) -> Result<Immediates, String> {
+        let value = self
+            .repo_vars
+            .get_mut(&var_name)
+            .ok_or_else(|| format!("[ UNDEFINED VARIABLE ] Variable: {var_name}"))?;
 
-        self.repo_var_pointers.remove(&var_name);
+        Ok(std::mem::replace(value, new))
     }
 
     /// (_Advanced_): Removes everything from the repository.
     pub fn clear_repo(&mut self) {
-        self.repo_var_pointers.clear();
+        self.repo_vars.clear();
     }
 }
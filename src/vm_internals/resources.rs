@@ -0,0 +1,75 @@
+use crate::instructiondefs::*;
+use crate::vm_internals::immediates::Immediates;
+use crate::vm_internals::VMStack;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+/// ## Resources of the running Squid ARchive, by name.
+///
+/// Cloning it gives another handle to the same resources, so every thread reads them.
+/// Programs not run from a SAR have no resources.
+#[derive(Debug, Clone, Default)]
+pub struct Resources(Arc<BTreeMap<String, Vec<u8>>>);
+
+impl Resources {
+    #[cfg(feature = "sar")]
+    pub fn new(resources: BTreeMap<String, Vec<u8>>) -> Self {
+        Resources(Arc::new(resources))
+    }
+
+    /// Gets the bytes of a resource.
+    pub fn get(&self, name: &str) -> Result<&[u8], String> {
+        self.0
+            .get(name)
+            .map(Vec::as_slice)
+            .ok_or_else(|| format!("[ RESOURCE NOT FOUND ] {name}"))
+    }
+
+    pub fn exists(&self, name: &str) -> bool {
+        self.0.contains_key(name)
+    }
+
+    /// Names of every resource, sorted.
+    pub fn names(&self) -> Vec<String> {
+        self.0.keys().cloned().collect()
+    }
+}
+
+/// Pops the name of a resource from the stack.
+fn pop_name(stack: &mut VMStack) -> Result<String, String> {
+    match stack.pop()? {
+        Immediates::MutStr(name) => Ok(name),
+        Immediates::StaticStr(name) => Ok(name.to_string()),
+        _ => Err("[ INVALID RESOURCE NAME ]".to_string()),
+    }
+}
+
+/// Runs a resource instruction, shared by the main thread and the VM threads/tasks.
+pub fn resource_instruction(
+    instruction: u8,
+    resources: &Resources,
+    stack: &mut VMStack,
+) -> Result<(), String> {
+    let value = match instruction {
+        RSREAD => Immediates::Binary(resources.get(&pop_name(stack)?)?.to_vec()),
+        RSREADS => {
+            let name = pop_name(stack)?;
+
+            match String::from_utf8(resources.get(&name)?.to_vec()) {
+                Ok(string) => Immediates::MutStr(string),
+                Err(_) => return Err(format!("[ RESOURCE NOT UTF-8 ] {name}")),
+            }
+        }
+        RSLIST => Immediates::Array(
+            resources
+                .names()
+                .into_iter()
+                .map(Immediates::MutStr)
+                .collect(),
+        ),
+        RSEXISTS => Immediates::Boolean(resources.exists(&pop_name(stack)?)),
+        _ => return Err("[ UNKNOWN INSTRUCTION ]".to_string()),
+    };
+
+    stack.push(value)
+}
@@ -1,13 +1,17 @@
+use crate::instructiondefs::mnemonic;
 use crate::vm_internals::heap;
 use arrayvec::ArrayVec;
 
 /// Fixed value of the return stack size.
 const STACK_SIZE: usize = 500;
 
+/// Where the VM should go back to after a function returns.
 #[derive(Debug)]
-enum ReturnType {
+pub enum ReturnType {
+    /// Return address inside the main program.
     WithoutPointer(usize),
-    WithPointer(heap::Pointer),
+    /// Return address inside a function object.
+    WithPointer(usize, heap::Pointer),
 }
 
 debug_derive!(
@@ -57,6 +61,48 @@ impl ReturnStack {
             .expect("Stack should not be empty"))
     }
 
+    /// Return addresses from the latest to the oldest.
+    pub fn frames(&self) -> impl Iterator<Item = &ReturnType> {
+        self.return_addresses.iter().rev()
+    }
+
+    /// Call frames from the innermost to the outermost, used in stack traces.
+    ///
+    /// `pc` and `function` tell where the innermost frame is at, `instructions` being its code.
+    pub fn trace(
+        &self,
+        pc: usize,
+        function: Option<&heap::Pointer>,
+        instructions: &[u8],
+    ) -> Vec<String> {
+        let frame = |function: Option<&heap::Pointer>, pc: usize| match function {
+            Some(function) => format!(
+                "function {}:{}, instruction {pc}",
+                function.region, function.point_to
+            ),
+            None => format!("main code, instruction {pc}"),
+        };
+
+        let current = pc.saturating_sub(1);
+
+        let mut trace = vec![match instructions.get(current) {
+            Some(&instruction) => format!(
+                "{} {} (0x{instruction:02X})",
+                frame(function, current),
+                mnemonic(instruction)
+            ),
+            None => frame(function, current),
+        }];
+
+        // Return addresses point right after the call.
+        trace.extend(self.frames().map(|return_to| match return_to {
+            ReturnType::WithoutPointer(pc) => frame(None, pc.saturating_sub(1)),
+            ReturnType::WithPointer(pc, function) => frame(Some(function), pc.saturating_sub(1)),
+        }));
+
+        trace
+    }
+
     /// Used to check if the stack is empty at the moment.
     pub fn check_empty(&mut self) -> bool {
         self.return_addresses.is_empty()
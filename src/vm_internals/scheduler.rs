@@ -0,0 +1,234 @@
+//! Cooperative scheduler running the VM tasks (`NTASK`) on a fixed pool of worker threads.
+//!
+//! Every task is a `VMThread` in cooperative mode. A worker runs a task for a time slice
+//! (a number of instructions), then puts it back at the end of the run queue.
+//! Tasks also give their worker up early on `YIELD` and on instructions that would block
+//! (receiving from an empty channel, locking a held mutex...), so thousands of tasks
+//! can share a few OS threads.
+//!
+//! Blocked tasks are parked instead of going back to the run queue, until another task
+//! runs an instruction that may release them (a channel operation, an unlock or a notify).
+//! What they wait on can also come from the main thread or a thread,
+//! so parked tasks nothing woke up are retried after a backoff.
+
+use crate::vm_internals::thread_pool::{panic_message, ThreadHandle};
+use crate::vm_internals::vm_threads::{Slice, VMThread};
+use std::collections::VecDeque;
+use std::fmt::{Debug, Formatter};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Default number of instructions a task runs before giving its worker to the next task.
+pub const DEFAULT_TASK_SLICE: usize = 1000;
+
+/// First wait before retrying the parked tasks, doubled every time nothing woke them.
+const MIN_BACKOFF: Duration = Duration::from_millis(1);
+
+/// Longest wait before retrying the parked tasks.
+const MAX_BACKOFF: Duration = Duration::from_millis(50);
+
+/// Task waiting in the run queue.
+struct ScheduledTask {
+    id: usize,
+    thread: VMThread,
+    handle: ThreadHandle,
+}
+
+struct RunQueue {
+    tasks: VecDeque<ScheduledTask>,
+    /// Tasks blocked on an instruction, waiting for something to change.
+    parked: Vec<ScheduledTask>,
+    /// Wait before retrying the parked tasks when nothing woke them up.
+    backoff: Duration,
+    /// When the parked tasks are retried if nothing wakes them up before.
+    retry_at: Instant,
+    shutdown: bool,
+}
+
+struct SchedulerInner {
+    queue: Mutex<RunQueue>,
+    available: Condvar,
+    slice: usize,
+}
+
+impl SchedulerInner {
+    /// Puts the parked tasks back in the run queue, what they wait on may be ready now.
+    fn unpark(&self, queue: &mut RunQueue) {
+        if !queue.parked.is_empty() {
+            queue.backoff = MIN_BACKOFF;
+            queue.tasks.extend(queue.parked.drain(..));
+            self.available.notify_all();
+        }
+    }
+}
+
+/// ## Green-thread scheduler
+///
+/// Owns the worker threads, which are stopped when it's dropped.
+pub struct Scheduler {
+    inner: Arc<SchedulerInner>,
+    workers: Vec<thread::JoinHandle<()>>,
+}
+
+impl Scheduler {
+    /// Starts `workers` worker threads, each running tasks for `slice` instructions at a time.
+    pub fn new(workers: usize, slice: usize) -> Self {
+        let inner = Arc::new(SchedulerInner {
+            queue: Mutex::new(RunQueue {
+                tasks: VecDeque::new(),
+                parked: Vec::new(),
+                backoff: MIN_BACKOFF,
+                retry_at: Instant::now(),
+                shutdown: false,
+            }),
+            available: Condvar::new(),
+            slice: slice.max(1),
+        });
+
+        let workers = (0..workers.max(1))
+            .map(|_| {
+                let inner = Arc::clone(&inner);
+                thread::spawn(move || worker(inner))
+            })
+            .collect();
+
+        Scheduler { inner, workers }
+    }
+
+    /// Puts a new task in the run queue and returns its handle.
+    pub fn spawn(&self, id: usize, mut thread: VMThread) -> ThreadHandle {
+        let handle = ThreadHandle::new(thread.cancel_token.clone());
+
+        thread.cooperative = true;
+
+        self.inner
+            .queue
+            .lock()
+            .unwrap()
+            .tasks
+            .push_back(ScheduledTask {
+                id,
+                thread,
+                handle: handle.clone(),
+            });
+
+        self.inner.available.notify_one();
+
+        handle
+    }
+}
+
+impl Debug for Scheduler {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let queue = self.inner.queue.lock().unwrap();
+
+        write!(
+            f,
+            "Scheduler {{ workers: {}, slice: {}, queued: {}, parked: {} }}",
+            self.workers.len(),
+            self.inner.slice,
+            queue.tasks.len(),
+            queue.parked.len()
+        )
+    }
+}
+
+impl Drop for Scheduler {
+    fn drop(&mut self) {
+        self.inner.queue.lock().unwrap().shutdown = true;
+        self.inner.available.notify_all();
+
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+/// Runs the tasks from the queue until the scheduler shuts down.
+///
+/// Tasks still queued or parked when it shuts down are dropped without finishing.
+fn worker(inner: Arc<SchedulerInner>) {
+    loop {
+        let mut task = {
+            let mut queue = inner.queue.lock().unwrap();
+
+            loop {
+                if queue.shutdown {
+                    return;
+                }
+
+                // Nothing woke the parked tasks up in time, so they're retried,
+                // waiting longer the next time.
+                if !queue.parked.is_empty() && Instant::now() >= queue.retry_at {
+                    let parked = std::mem::take(&mut queue.parked);
+
+                    queue.tasks.extend(parked);
+                    queue.backoff = (queue.backoff * 2).min(MAX_BACKOFF);
+                }
+
+                if let Some(task) = queue.tasks.pop_front() {
+                    break task;
+                }
+
+                if queue.parked.is_empty() {
+                    queue = inner.available.wait(queue).unwrap();
+                } else {
+                    let timeout = queue.retry_at.saturating_duration_since(Instant::now());
+                    queue = inner.available.wait_timeout(queue, timeout).unwrap().0;
+                }
+            }
+        };
+
+        // A Rust panic inside a task must not take the worker down with it.
+        let slice = panic::catch_unwind(AssertUnwindSafe(|| task.thread.run_slice(inner.slice)))
+            .unwrap_or_else(|payload| Err(panic_message(payload)));
+
+        match slice {
+            Ok(Slice::Finished(value)) => {
+                #[cfg(feature = "devkit")]
+                dev_print!("\x1B[42mTask {} ended successfully\x1B[0m", task.id);
+
+                // Its heap region is released before awaiting tasks see the result.
+                drop(task.thread);
+                task.handle.finish(Ok(value));
+
+                // It may have released what the parked tasks wait on.
+                inner.unpark(&mut inner.queue.lock().unwrap());
+            }
+            Ok(Slice::Yielded { progressed }) => {
+                let mut queue = inner.queue.lock().unwrap();
+
+                queue.tasks.push_back(task);
+                inner.available.notify_one();
+
+                if progressed {
+                    inner.unpark(&mut queue);
+                }
+            }
+            Ok(Slice::Blocked { progressed }) => {
+                let mut queue = inner.queue.lock().unwrap();
+
+                if progressed {
+                    inner.unpark(&mut queue);
+                }
+
+                if queue.parked.is_empty() {
+                    queue.retry_at = Instant::now() + queue.backoff;
+                }
+
+                queue.parked.push(task);
+            }
+            Err(err) => {
+                let error = task.thread.fail(task.id, true, err);
+
+                drop(task.thread);
+                task.handle.finish(Err(error));
+
+                // It may have released what the parked tasks wait on.
+                inner.unpark(&mut inner.queue.lock().unwrap());
+            }
+        }
+    }
+}
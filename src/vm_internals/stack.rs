@@ -41,6 +41,17 @@ impl VMStack {
         }
     }
 
+    /// Instantiates a VMStack that only allocates memory as values are pushed.
+    ///
+    /// Used by threads and tasks, since there can be thousands of them.
+    pub fn new_lazy(stack_size: usize) -> VMStack {
+        VMStack {
+            stack_capacity: stack_size,
+            top: 0,
+            stack_memory: Vec::new(),
+        }
+    }
+
     /// Used for getting the size of the stack.
     pub fn get_length(&mut self) -> usize {
         self.stack_memory.len()
@@ -56,6 +67,12 @@ impl VMStack {
         Ok(self.stack_memory.pop().expect("Stack should not be empty"))
     }
 
+    /// Drops the values above the first `len` ones.
+    pub fn truncate(&mut self, len: usize) {
+        self.stack_memory.truncate(len);
+        self.top = self.stack_memory.len();
+    }
+
     /// Used to check if the stack is empty at the moment.
     pub fn check_empty(&mut self) -> bool {
         self.stack_memory.is_empty()
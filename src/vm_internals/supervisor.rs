@@ -0,0 +1,150 @@
+//! What happens when a VM thread or task fails.
+//!
+//! Every failure ends up as a `ThreadError` given back at `JOIN`/`AWAIT`.
+//! The supervisor policy chosen when spawning the thread decides what else happens:
+//! nothing, the main thread failing too, or the whole VM stopping.
+
+use crate::vm_internals::immediates::Immediates;
+use std::collections::VecDeque;
+use std::fmt::{Display, Formatter};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Message of a `PANIC` run with an empty stack.
+pub const EXPLICIT_PANIC: &str = "explicit panic";
+
+/// Bit of the `NTHRD`/`NTASK` flags telling if a handle is pushed.
+pub const SPAWN_TRACKED: u8 = 0b001;
+
+/// Shift of the supervisor policy inside the `NTHRD`/`NTASK` flags.
+const POLICY_SHIFT: u8 = 1;
+
+/// ## What to do when a thread or task fails.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum SupervisorPolicy {
+    /// The error is only given back at `JOIN`/`AWAIT`.
+    #[default]
+    Ignore = 0,
+    /// The main thread fails with the error too.
+    Propagate = 1,
+    /// The VM stops as soon as the main thread notices,
+    /// without waiting for the threads and tasks still running.
+    Abort = 2,
+}
+
+impl SupervisorPolicy {
+    /// Reads the policy from the `NTHRD`/`NTASK` flags.
+    pub fn from_flags(flags: u8) -> Result<Self, String> {
+        match flags >> POLICY_SHIFT {
+            0 => Ok(SupervisorPolicy::Ignore),
+            1 => Ok(SupervisorPolicy::Propagate),
+            2 => Ok(SupervisorPolicy::Abort),
+            _ => Err(format!("[ INVALID SPAWN FLAGS ] Flags: 0b{flags:03b}")),
+        }
+    }
+}
+
+/// ## Error a VM thread or task failed with.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ThreadError {
+    /// Handle index of the thread or task.
+    pub id: usize,
+    pub task: bool,
+    pub message: String,
+    /// Call frames when it failed, innermost first.
+    pub trace: Vec<String>,
+}
+
+impl ThreadError {
+    /// Value pushed by `JOIN`/`AWAIT` for the error.
+    pub fn to_immediate(&self) -> Immediates {
+        Immediates::MutStr(self.to_string())
+    }
+}
+
+impl Display for ThreadError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let kind = if self.task { "Task" } else { "Thread" };
+
+        write!(f, "{kind} {} error: {}", self.id, self.message)?;
+
+        for frame in &self.trace {
+            write!(f, "\n\tat {frame}")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// ## Collects the failures propagated to the main thread.
+///
+/// Cloning it gives another handle to the same supervisor,
+/// so every thread spawned by the main thread reports to it.
+#[derive(Debug, Clone, Default)]
+pub struct Supervisor {
+    /// Set when there are failures waiting, so checking for them is cheap.
+    failed: Arc<AtomicBool>,
+    failures: Arc<Mutex<VecDeque<ThreadError>>>,
+    /// Set when a failure aborted the VM, it stays set.
+    aborted: Arc<AtomicBool>,
+    /// The failure that aborted the VM, until the main thread reports it.
+    abort: Arc<Mutex<Option<ThreadError>>>,
+}
+
+impl Supervisor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Applies the policy to a failure.
+    ///
+    /// With the `Abort` policy the failure is kept for the main thread,
+    /// which reports it and exits.
+    pub fn report(&self, policy: SupervisorPolicy, error: &ThreadError) {
+        match policy {
+            SupervisorPolicy::Ignore => {}
+            SupervisorPolicy::Propagate => {
+                self.failures.lock().unwrap().push_back(error.clone());
+                self.failed.store(true, Ordering::SeqCst);
+            }
+            SupervisorPolicy::Abort => {
+                let mut abort = self.abort.lock().unwrap();
+
+                // Only the first failure is reported, the VM is already stopping for the next ones.
+                if !self.aborted.swap(true, Ordering::SeqCst) {
+                    *abort = Some(error.clone());
+                }
+            }
+        }
+    }
+
+    /// Checks if a failure aborted the VM.
+    pub fn is_aborted(&self) -> bool {
+        self.aborted.load(Ordering::SeqCst)
+    }
+
+    /// Takes the failure that aborted the VM, if it wasn't reported yet.
+    pub fn take_abort(&self) -> Option<ThreadError> {
+        if !self.is_aborted() {
+            return None;
+        }
+
+        self.abort.lock().unwrap().take()
+    }
+
+    /// Takes the oldest failure propagated to the main thread, if any.
+    pub fn take_failure(&self) -> Option<ThreadError> {
+        if !self.failed.load(Ordering::SeqCst) {
+            return None;
+        }
+
+        let mut failures = self.failures.lock().unwrap();
+        let failure = failures.pop_front();
+
+        if failures.is_empty() {
+            self.failed.store(false, Ordering::SeqCst);
+        }
+
+        failure
+    }
+}
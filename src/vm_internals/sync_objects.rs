@@ -0,0 +1,466 @@
+use crate::instructiondefs::*;
+use crate::vm_internals::heap::{DataType, Pointer};
+use crate::vm_internals::immediates::Immediates;
+use crate::vm_internals::{VMHeap, VMStack};
+use std::fmt::{Debug, Formatter};
+use std::mem::size_of;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+
+/// Owner id of the main thread, VM threads and tasks get theirs from `next_owner_id`.
+pub const MAIN_OWNER: u64 = 0;
+
+static OWNER_IDS: AtomicU64 = AtomicU64::new(MAIN_OWNER + 1);
+
+/// Gives a new owner id, used to know which VM thread or task holds a mutex.
+pub fn next_owner_id() -> u64 {
+    OWNER_IDS.fetch_add(1, Ordering::Relaxed)
+}
+
+struct MutexInner {
+    /// Owner id of who holds the mutex.
+    owner: Mutex<Option<u64>>,
+    released: Condvar,
+}
+
+/// ## Mutex that can be locked and unlocked by bytecode.
+///
+/// Isn't tied to a Rust scope, so it keeps track of its owner
+/// to reject unlocks coming from anyone else.
+#[derive(Clone)]
+pub struct VMMutex {
+    inner: Arc<MutexInner>,
+}
+
+impl VMMutex {
+    pub fn new() -> Self {
+        VMMutex {
+            inner: Arc::new(MutexInner {
+                owner: Mutex::new(None),
+                released: Condvar::new(),
+            }),
+        }
+    }
+
+    /// Locks the mutex, blocking while someone else holds it.
+    ///
+    /// Errors if the owner already holds it, since it would never be released.
+    pub fn lock(&self, owner: u64) -> Result<(), String> {
+        let mut holder = self.inner.owner.lock().unwrap();
+
+        if *holder == Some(owner) {
+            return Err("[ DEADLOCK ] Mutex is already held by this thread".to_string());
+        }
+
+        while holder.is_some() {
+            holder = self.inner.released.wait(holder).unwrap();
+        }
+
+        *holder = Some(owner);
+
+        Ok(())
+    }
+
+    /// Locks the mutex if nobody holds it, never blocks.
+    ///
+    /// Returns if the mutex was locked.
+    pub fn try_lock(&self, owner: u64) -> Result<bool, String> {
+        let mut holder = self.inner.owner.lock().unwrap();
+
+        match *holder {
+            Some(holder) if holder == owner => {
+                Err("[ DEADLOCK ] Mutex is already held by this thread".to_string())
+            }
+            Some(_) => Ok(false),
+            None => {
+                *holder = Some(owner);
+                Ok(true)
+            }
+        }
+    }
+
+    /// Unlocks the mutex, errors if it isn't held by the owner.
+    pub fn unlock(&self, owner: u64) -> Result<(), String> {
+        let mut holder = self.inner.owner.lock().unwrap();
+
+        if *holder != Some(owner) {
+            return Err("[ MUTEX NOT OWNED ]".to_string());
+        }
+
+        *holder = None;
+        self.inner.released.notify_one();
+
+        Ok(())
+    }
+
+    /// Checks if both handles are the same mutex.
+    pub fn same(&self, other: &VMMutex) -> bool {
+        Arc::ptr_eq(&self.inner, &other.inner)
+    }
+}
+
+impl Debug for VMMutex {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Mutex {{ owner: {:?} }}",
+            self.inner.owner.lock().unwrap()
+        )
+    }
+}
+
+impl Default for VMMutex {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// ## Mutexes held by a VM thread or task.
+///
+/// Kept so they can be released when it ends without unlocking them,
+/// otherwise nobody else could ever lock them again.
+#[derive(Clone, Debug, Default)]
+pub struct HeldMutexes(Vec<VMMutex>);
+
+impl HeldMutexes {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records a mutex that was just locked.
+    pub fn hold(&mut self, mutex: VMMutex) {
+        self.0.push(mutex);
+    }
+
+    /// Forgets a mutex that was just unlocked.
+    pub fn release(&mut self, mutex: &VMMutex) {
+        if let Some(index) = self.0.iter().position(|held| held.same(mutex)) {
+            self.0.swap_remove(index);
+        }
+    }
+
+    /// Unlocks every mutex still held by the owner.
+    pub fn release_all(&mut self, owner: u64) {
+        for mutex in self.0.drain(..) {
+            let _ = mutex.unlock(owner);
+        }
+    }
+}
+
+/// Waiters and wake up permits of a condition.
+struct ConditionState {
+    waiters: usize,
+    permits: usize,
+}
+
+struct ConditionInner {
+    state: Mutex<ConditionState>,
+    notified: Condvar,
+}
+
+/// ## Condition variable used together with a `VMMutex`.
+#[derive(Clone)]
+pub struct VMCondition {
+    inner: Arc<ConditionInner>,
+}
+
+impl VMCondition {
+    pub fn new() -> Self {
+        VMCondition {
+            inner: Arc::new(ConditionInner {
+                state: Mutex::new(ConditionState {
+                    waiters: 0,
+                    permits: 0,
+                }),
+                notified: Condvar::new(),
+            }),
+        }
+    }
+
+    /// Releases the mutex, waits to be notified and locks the mutex again.
+    ///
+    /// The mutex is released while holding the condition state,
+    /// so a notification sent right after it can't be lost.
+    pub fn wait(&self, mutex: &VMMutex, owner: u64) -> Result<(), String> {
+        let mut state = self.inner.state.lock().unwrap();
+
+        mutex.unlock(owner)?;
+
+        state.waiters += 1;
+
+        while state.permits == 0 {
+            state = self.inner.notified.wait(state).unwrap();
+        }
+
+        state.permits -= 1;
+        state.waiters -= 1;
+
+        drop(state);
+
+        mutex.lock(owner)
+    }
+
+    /// Releases the mutex and registers as a waiter without blocking,
+    /// used by tasks that give their worker up instead.
+    ///
+    /// `take_permit` then tells when the waiter was notified.
+    pub fn register(&self, mutex: &VMMutex, owner: u64) -> Result<(), String> {
+        let mut state = self.inner.state.lock().unwrap();
+
+        mutex.unlock(owner)?;
+
+        state.waiters += 1;
+
+        Ok(())
+    }
+
+    /// Takes a wake up permit for a registered waiter, if it was notified.
+    pub fn take_permit(&self) -> bool {
+        let mut state = self.inner.state.lock().unwrap();
+
+        if state.permits == 0 {
+            return false;
+        }
+
+        state.permits -= 1;
+        state.waiters -= 1;
+
+        true
+    }
+
+    /// Unregisters a waiter that stopped waiting without being woken up,
+    /// leaving any permit to the other waiters.
+    pub fn leave(&self) {
+        let mut state = self.inner.state.lock().unwrap();
+
+        state.waiters -= 1;
+        state.permits = state.permits.min(state.waiters);
+    }
+
+    /// Wakes up one waiter, if any.
+    pub fn notify_one(&self) {
+        let mut state = self.inner.state.lock().unwrap();
+
+        if state.waiters > state.permits {
+            state.permits += 1;
+            self.inner.notified.notify_one();
+        }
+    }
+
+    /// Wakes up every waiter.
+    pub fn notify_all(&self) {
+        let mut state = self.inner.state.lock().unwrap();
+
+        state.permits = state.waiters;
+        self.inner.notified.notify_all();
+    }
+}
+
+impl Debug for VMCondition {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let state = self.inner.state.lock().unwrap();
+
+        write!(f, "Condition {{ waiters: {} }}", state.waiters)
+    }
+}
+
+impl Default for VMCondition {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// ## Synchronization objects stored in the heap.
+///
+/// Cloning one gives another handle to the same object,
+/// so it can be used without holding the heap lock while blocking.
+#[derive(Clone)]
+pub enum SyncObject {
+    Mutex(VMMutex),
+    Condition(VMCondition),
+    Atomic(Arc<AtomicI64>),
+}
+
+impl SyncObject {
+    /// Name of the object kind, used in debug output and heap dumps.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            SyncObject::Mutex(_) => "Mutex",
+            SyncObject::Condition(_) => "Condition",
+            SyncObject::Atomic(_) => "Atomic",
+        }
+    }
+
+    /// Bytes used by the shared state behind the handle.
+    pub fn retained_size(&self) -> usize {
+        match self {
+            SyncObject::Mutex(_) => size_of::<MutexInner>(),
+            SyncObject::Condition(_) => size_of::<ConditionInner>(),
+            SyncObject::Atomic(_) => size_of::<AtomicI64>(),
+        }
+    }
+}
+
+impl Debug for SyncObject {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SyncObject::Atomic(atomic) => write!(f, "Atomic({})", atomic.load(Ordering::SeqCst)),
+            SyncObject::Mutex(mutex) => mutex.fmt(f),
+            SyncObject::Condition(condition) => condition.fmt(f),
+        }
+    }
+}
+
+/// Pops a pointer from the stack and gets the mutex it points to.
+pub(crate) fn pop_mutex(
+    stack: &mut VMStack,
+    heap: &Arc<RwLock<VMHeap>>,
+) -> Result<VMMutex, String> {
+    match pop_sync(stack, heap)? {
+        SyncObject::Mutex(mutex) => Ok(mutex),
+        _ => Err("[ NOT A MUTEX ]".to_string()),
+    }
+}
+
+/// Pops a pointer from the stack and gets the condition it points to.
+pub(crate) fn pop_condition(
+    stack: &mut VMStack,
+    heap: &Arc<RwLock<VMHeap>>,
+) -> Result<VMCondition, String> {
+    match pop_sync(stack, heap)? {
+        SyncObject::Condition(condition) => Ok(condition),
+        _ => Err("[ NOT A CONDITION ]".to_string()),
+    }
+}
+
+/// Pops a pointer from the stack and gets the atomic cell it points to.
+fn pop_atomic(stack: &mut VMStack, heap: &Arc<RwLock<VMHeap>>) -> Result<Arc<AtomicI64>, String> {
+    match pop_sync(stack, heap)? {
+        SyncObject::Atomic(atomic) => Ok(atomic),
+        _ => Err("[ NOT AN ATOMIC ]".to_string()),
+    }
+}
+
+fn pop_sync(stack: &mut VMStack, heap: &Arc<RwLock<VMHeap>>) -> Result<SyncObject, String> {
+    match stack.pop()? {
+        Immediates::RefPtr(pointer) => heap.read().unwrap().get_sync(&pointer),
+        _ => Err("[ NOT A POINTER ]".to_string()),
+    }
+}
+
+fn pop_integer(stack: &mut VMStack) -> Result<i64, String> {
+    match stack.pop()? {
+        Immediates::Integer(value) => Ok(value),
+        _ => Err("[ MISMATCHED TYPES ]".to_string()),
+    }
+}
+
+/// Runs a mutex, condition or atomic instruction,
+/// shared by the main thread and the VM threads/tasks.
+///
+/// New objects are allocated with `allocate`, given the stack for the heap dump roots.
+/// `owner` identifies who locks and unlocks mutexes, `held` keeps track of the ones it holds.
+/// Returns `false` if `blocking` is off and `MXLOCK` would block,
+/// leaving the stack as it was so the instruction can be run again later.
+/// `CVWAIT` always blocks.
+pub fn sync_instruction(
+    instruction: u8,
+    stack: &mut VMStack,
+    heap: &Arc<RwLock<VMHeap>>,
+    allocate: &dyn Fn(&VMStack, DataType) -> Result<Pointer, String>,
+    owner: u64,
+    held: &mut HeldMutexes,
+    blocking: bool,
+) -> Result<bool, String> {
+    let result = match instruction {
+        MXLOCK if !blocking => {
+            let pointer = stack.pop()?;
+
+            let mutex = match &pointer {
+                Immediates::RefPtr(pointer) => heap.read().unwrap().get_sync(pointer)?,
+                _ => return Err("[ NOT A POINTER ]".to_string()),
+            };
+
+            let SyncObject::Mutex(mutex) = mutex else {
+                return Err("[ NOT A MUTEX ]".to_string());
+            };
+
+            if !mutex.try_lock(owner)? {
+                stack.push(pointer)?;
+                return Ok(false);
+            }
+
+            held.hold(mutex);
+
+            Ok(())
+        }
+        MXNEW => {
+            let pointer = allocate(stack, DataType::Sync(SyncObject::Mutex(VMMutex::new())))?;
+            stack.push(Immediates::RefPtr(pointer))
+        }
+        MXLOCK => {
+            let mutex = pop_mutex(stack, heap)?;
+            mutex.lock(owner)?;
+            held.hold(mutex);
+            Ok(())
+        }
+        MXUNLOCK => {
+            let mutex = pop_mutex(stack, heap)?;
+            mutex.unlock(owner)?;
+            held.release(&mutex);
+            Ok(())
+        }
+        CVNEW => {
+            let pointer = allocate(
+                stack,
+                DataType::Sync(SyncObject::Condition(VMCondition::new())),
+            )?;
+            stack.push(Immediates::RefPtr(pointer))
+        }
+        CVWAIT => {
+            let mutex = pop_mutex(stack, heap)?;
+            pop_condition(stack, heap)?.wait(&mutex, owner)
+        }
+        CVNOTIFY => {
+            pop_condition(stack, heap)?.notify_one();
+            Ok(())
+        }
+        CVNOTIFYALL => {
+            pop_condition(stack, heap)?.notify_all();
+            Ok(())
+        }
+        ATNEW => {
+            let value = pop_integer(stack)?;
+            let pointer = allocate(
+                stack,
+                DataType::Sync(SyncObject::Atomic(Arc::new(AtomicI64::new(value)))),
+            )?;
+            stack.push(Immediates::RefPtr(pointer))
+        }
+        ATLOAD => {
+            let value = pop_atomic(stack, heap)?.load(Ordering::SeqCst);
+            stack.push(Immediates::Integer(value))
+        }
+        ATSTORE => {
+            let value = pop_integer(stack)?;
+            pop_atomic(stack, heap)?.store(value, Ordering::SeqCst);
+            Ok(())
+        }
+        ATFADD => {
+            let delta = pop_integer(stack)?;
+            let old = pop_atomic(stack, heap)?.fetch_add(delta, Ordering::SeqCst);
+            stack.push(Immediates::Integer(old))
+        }
+        ATCAS => {
+            let new = pop_integer(stack)?;
+            let expected = pop_integer(stack)?;
+            let swapped = pop_atomic(stack, heap)?
+                .compare_exchange(expected, new, Ordering::SeqCst, Ordering::SeqCst)
+                .is_ok();
+            stack.push(Immediates::Boolean(swapped))
+        }
+        _ => Err("[ UNKNOWN INSTRUCTION ]".to_string()),
+    };
+
+    result.map(|_| true)
+}
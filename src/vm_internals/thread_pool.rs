@@ -0,0 +1,323 @@
+//! Fixed pool of OS threads running the VM threads (`NTHRD`).
+//!
+//! When every worker is busy new threads wait in a queue, so a program spawning
+//! in a loop can't create unbounded OS threads. Since queued threads only start
+//! when a worker is free, threads waiting on each other need enough workers to all run at once.
+
+use crate::vm_internals::immediates::Immediates;
+use crate::vm_internals::supervisor::ThreadError;
+use crate::vm_internals::vm_threads::{CancelToken, VMThread};
+use std::any::Any;
+use std::collections::VecDeque;
+use std::fmt::{Debug, Display, Formatter};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How a VM thread or task ended, pushed by `JOIN`/`AWAIT` as an UInteger.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ThreadStatus {
+    /// Ended normally, its return value is pushed.
+    Finished = 0,
+    /// Ended with an error, its message is pushed.
+    Failed = 1,
+    /// Was cancelled before it ended.
+    Cancelled = 2,
+    /// Didn't end before the timeout, it can still be joined later.
+    TimedOut = 3,
+}
+
+/// Result of a VM thread or task, filled when it ends.
+struct HandleResult {
+    result: Mutex<Option<Result<Immediates, ThreadError>>>,
+    finished: Condvar,
+}
+
+/// ## Handle used to join a VM thread or await a task.
+#[derive(Clone)]
+pub struct ThreadHandle {
+    inner: Arc<HandleResult>,
+    cancel_token: CancelToken,
+}
+
+impl ThreadHandle {
+    pub(crate) fn new(cancel_token: CancelToken) -> Self {
+        ThreadHandle {
+            inner: Arc::new(HandleResult {
+                result: Mutex::new(None),
+                finished: Condvar::new(),
+            }),
+            cancel_token,
+        }
+    }
+
+    pub(crate) fn finish(&self, result: Result<Immediates, ThreadError>) {
+        *self.inner.result.lock().unwrap() = Some(result);
+        self.inner.finished.notify_all();
+    }
+
+    /// Checks if the thread ended.
+    pub fn is_finished(&self) -> bool {
+        self.inner.result.lock().unwrap().is_some()
+    }
+
+    /// Asks the thread to stop, it ends with a `Cancelled` status.
+    pub fn cancel(&self) {
+        self.cancel_token.cancel();
+    }
+
+    /// Gets the status of a result returned by the thread.
+    pub fn status(&self, result: &Result<Immediates, ThreadError>) -> ThreadStatus {
+        match result {
+            Ok(_) => ThreadStatus::Finished,
+            Err(_) if self.cancel_token.is_cancelled() => ThreadStatus::Cancelled,
+            Err(_) => ThreadStatus::Failed,
+        }
+    }
+
+    /// Blocks until the thread ends or the timeout runs out.
+    ///
+    /// Returns `None` on timeout, so it can be joined again later.
+    pub fn join_timeout(&self, timeout: Duration) -> Option<Result<Immediates, ThreadError>> {
+        let deadline = Instant::now() + timeout;
+        let mut result = self.inner.result.lock().unwrap();
+
+        loop {
+            if let Some(result) = result.take() {
+                return Some(result);
+            }
+
+            let now = Instant::now();
+
+            if now >= deadline {
+                return None;
+            }
+
+            result = self
+                .inner
+                .finished
+                .wait_timeout(result, deadline - now)
+                .unwrap()
+                .0;
+        }
+    }
+
+    /// Blocks until the thread ends and returns its result.
+    pub fn join(self) -> Result<Immediates, ThreadError> {
+        let mut result = self.inner.result.lock().unwrap();
+
+        loop {
+            if let Some(result) = result.take() {
+                return result;
+            }
+
+            result = self.inner.finished.wait(result).unwrap();
+        }
+    }
+}
+
+impl Debug for ThreadHandle {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ThreadHandle {{ finished: {} }}", self.is_finished())
+    }
+}
+
+/// Snapshot of the thread pool counters.
+#[derive(Debug, Clone)]
+pub struct ThreadPoolStats {
+    pub workers: usize,
+    /// Workers running a thread at the moment.
+    pub busy: usize,
+    /// Threads waiting for a free worker at the moment.
+    pub queued: usize,
+    /// Most threads waiting for a free worker at once.
+    pub peak_queued: usize,
+    pub submitted: usize,
+    pub completed: usize,
+    /// Threads that had to wait because every worker was busy.
+    pub saturated: usize,
+}
+
+impl Display for ThreadPoolStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Thread pool stats:\n\tWorkers => {} ({} busy)\n\tThreads => {} submitted, {} completed\n\tQueued => {} (peak {})\n\tSaturated => {} threads had to wait for a worker",
+            self.workers,
+            self.busy,
+            self.submitted,
+            self.completed,
+            self.queued,
+            self.peak_queued,
+            self.saturated
+        )
+    }
+}
+
+/// Thread waiting for a worker.
+struct PoolJob {
+    id: usize,
+    thread: VMThread,
+    handle: ThreadHandle,
+}
+
+struct PoolState {
+    jobs: VecDeque<PoolJob>,
+    busy: usize,
+    peak_queued: usize,
+    submitted: usize,
+    completed: usize,
+    saturated: usize,
+    shutdown: bool,
+}
+
+struct PoolInner {
+    state: Mutex<PoolState>,
+    available: Condvar,
+}
+
+/// ## Thread pool
+///
+/// Workers are told to stop when it's dropped, the ones running a thread stop once it ends.
+pub struct ThreadPool {
+    inner: Arc<PoolInner>,
+    workers: usize,
+}
+
+impl ThreadPool {
+    /// Starts `workers` worker threads.
+    pub fn new(workers: usize) -> Self {
+        let workers = workers.max(1);
+
+        let inner = Arc::new(PoolInner {
+            state: Mutex::new(PoolState {
+                jobs: VecDeque::new(),
+                busy: 0,
+                peak_queued: 0,
+                submitted: 0,
+                completed: 0,
+                saturated: 0,
+                shutdown: false,
+            }),
+            available: Condvar::new(),
+        });
+
+        for _ in 0..workers {
+            let inner = Arc::clone(&inner);
+            thread::spawn(move || worker(inner));
+        }
+
+        ThreadPool { inner, workers }
+    }
+
+    /// Runs the thread on a free worker, or queues it if every worker is busy.
+    pub fn spawn(&self, id: usize, thread: VMThread) -> ThreadHandle {
+        let handle = ThreadHandle::new(thread.cancel_token.clone());
+
+        let mut state = self.inner.state.lock().unwrap();
+
+        if state.busy + state.jobs.len() >= self.workers {
+            state.saturated += 1;
+        }
+
+        state.jobs.push_back(PoolJob {
+            id,
+            thread,
+            handle: handle.clone(),
+        });
+
+        state.submitted += 1;
+        state.peak_queued = state.peak_queued.max(state.jobs.len());
+
+        drop(state);
+
+        self.inner.available.notify_one();
+
+        handle
+    }
+
+    /// Takes a snapshot of the pool counters.
+    pub fn stats(&self) -> ThreadPoolStats {
+        let state = self.inner.state.lock().unwrap();
+
+        ThreadPoolStats {
+            workers: self.workers,
+            busy: state.busy,
+            queued: state.jobs.len(),
+            peak_queued: state.peak_queued,
+            submitted: state.submitted,
+            completed: state.completed,
+            saturated: state.saturated,
+        }
+    }
+}
+
+impl Debug for ThreadPool {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ThreadPool {{ {:?} }}", self.stats())
+    }
+}
+
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        self.inner.state.lock().unwrap().shutdown = true;
+        self.inner.available.notify_all();
+    }
+}
+
+/// Runs the queued threads until the pool shuts down.
+fn worker(inner: Arc<PoolInner>) {
+    loop {
+        let mut job = {
+            let mut state = inner.state.lock().unwrap();
+
+            loop {
+                if state.shutdown {
+                    return;
+                }
+
+                if let Some(job) = state.jobs.pop_front() {
+                    state.busy += 1;
+                    break job;
+                }
+
+                state = inner.available.wait(state).unwrap();
+            }
+        };
+
+        // A Rust panic inside a thread must not take the worker down with it.
+        let result = panic::catch_unwind(AssertUnwindSafe(|| job.thread.run()))
+            .unwrap_or_else(|payload| Err(panic_message(payload)));
+
+        {
+            let mut state = inner.state.lock().unwrap();
+            state.busy -= 1;
+            state.completed += 1;
+        }
+
+        let result = result.map_err(|err| job.thread.fail(job.id, false, err));
+
+        #[cfg(feature = "devkit")]
+        if result.is_ok() {
+            dev_print!("\x1B[42mThread {} ended successfully\x1B[0m", job.id);
+        }
+
+        // Its heap region is released before joining threads see the result.
+        drop(job.thread);
+        job.handle.finish(result);
+    }
+}
+
+/// Message of a Rust panic caught while running a thread or task.
+pub(crate) fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    let message = match payload.downcast::<String>() {
+        Ok(message) => *message,
+        Err(payload) => match payload.downcast::<&str>() {
+            Ok(message) => message.to_string(),
+            Err(_) => "unknown cause".to_string(),
+        },
+    };
+
+    format!("[ PANICKED ] {message}")
+}
@@ -1,23 +1,43 @@
 use crate::errdef::*;
 use crate::instructiondefs::*;
 use crate::sqd_reader::sqdbin_reader::FileReader;
+use crate::vm_internals::channel::channel_instruction;
+use crate::vm_internals::exceptions::{HandlerStack, TryHandler};
+use crate::vm_internals::frames::{create_closure, Frames};
+use crate::vm_internals::heap::{DataType, Function, HeapStats, Pointer};
+use crate::vm_internals::heap_dump::{self, thread_roots};
+use crate::vm_internals::immediates::Closure;
 use crate::vm_internals::immediates::Immediates::{
-    self, Array, Binary, Boolean, Float, Integer, MutStr, Null, StaticStr, UInteger,
+    self, Boolean, Float, Integer, MutStr, Null, StaticStr, UInteger,
 };
-use crate::vm_internals::vm_threads::VMThread;
+use crate::vm_internals::native::{native_instruction, NativeFunctions};
+use crate::vm_internals::resources::{resource_instruction, Resources};
+use crate::vm_internals::return_stack::ReturnStack;
+#[cfg(feature = "green-threads")]
+use crate::vm_internals::scheduler::{Scheduler, DEFAULT_TASK_SLICE};
+use crate::vm_internals::supervisor::{
+    Supervisor, SupervisorPolicy, ThreadError, EXPLICIT_PANIC, SPAWN_TRACKED,
+};
+use crate::vm_internals::sync_objects::{sync_instruction, HeldMutexes, MAIN_OWNER};
+use crate::vm_internals::thread_pool::{ThreadHandle, ThreadPool, ThreadPoolStats, ThreadStatus};
+use crate::vm_internals::vm_threads::{ThreadEntry, VMThread};
 use crate::vm_internals::{VMHeap, VMRepository, VMStack};
 
 #[cfg(feature = "green-threads")]
 use async_std::task;
 
-#[cfg(feature = "green-threads")]
-use async_std::task::JoinHandle;
-
 use std::fmt::{Debug, Display};
 use std::sync::mpsc::Sender;
-use std::sync::{mpsc, Arc, RwLock};
+use std::sync::{mpsc, Arc, OnceLock, RwLock};
+use std::time::Duration;
 use std::{process, thread};
 
+/// Values from the top of the stack shown when the main thread panics.
+const PANIC_STACK_VALUES: usize = 5;
+
+/// How often a main thread waiting for a thread or task checks if the VM was aborted.
+const ABORT_CHECK_INTERVAL: Duration = Duration::from_millis(10);
+
 /// Handles errors while pop from the stack
 fn handle_stack_err(result: Result<Immediates, String>) -> Immediates {
     match result {
@@ -96,7 +116,11 @@ debug_derive!(
         /// Normally used for function frames and to store function locals.
         stack: VMStack,
 
-        return_stack: VMStack,
+        /// Contains the return addresses of the functions being called.
+        return_stack: ReturnStack,
+
+        /// Try regions still open.
+        handlers: HandlerStack,
 
         function_stack: Vec<VMStack>,
 
@@ -104,7 +128,22 @@ debug_derive!(
         /// Normally used for dynamic programs that make use of
         /// mutable variables or other objects that can change and/or need to be
         /// stored for a longer time than in the stack.
-        // heap: Arc<RwLock<VMHeap>>,
+        heap: Arc<RwLock<VMHeap>>,
+
+        /// Pointers to the function objects loaded from the file, in file order.
+        functions: Vec<Pointer>,
+
+        /// The function being run at the moment, `None` if it's the main program.
+        current_function: Option<Pointer>,
+
+        /// Main program instructions and data, parked while a function is running.
+        parked_program: Option<(Vec<u8>, Vec<Immediates>)>,
+
+        /// Where to dump the heap when it runs out of memory, if anywhere.
+        heap_dump_on_oom: Option<String>,
+
+        /// Mutexes locked by the main thread, released if it panics.
+        held_mutexes: HeldMutexes,
 
         /// It's used to store pointers for heap values, so it can be used as a global variable storage.
         ///
@@ -116,10 +155,42 @@ debug_derive!(
 
         #[cfg(feature = "green-threads")]
         /// Task handlers to await if the program ends too quickly.
-        pub task_handlers: Vec<JoinHandle<Result<(), String>>>,
+        /// `None` once the task was awaited with `AWAIT`.
+        pub task_handlers: Vec<Option<ThreadHandle>>,
+
+        #[cfg(feature = "green-threads")]
+        /// Runs the tasks, started on the first `NTASK`.
+        scheduler: Option<Scheduler>,
+
+        #[cfg(feature = "green-threads")]
+        /// Number of worker threads the scheduler runs tasks on.
+        task_workers: usize,
+
+        #[cfg(feature = "green-threads")]
+        /// Instructions a task runs before giving its worker to the next one.
+        task_slice: usize,
 
         /// Thread handlers to join if the program ends too quickly.
-        pub thread_handlers: Vec<thread::JoinHandle<Result<(), String>>>,
+        /// `None` once the thread was joined with `JOIN`.
+        pub thread_handlers: Vec<Option<ThreadHandle>>,
+
+        /// Runs the threads, started on the first `NTHRD`.
+        thread_pool: Option<ThreadPool>,
+
+        /// Number of worker threads in the thread pool.
+        thread_pool_size: usize,
+
+        /// Gets the failures of threads and tasks spawned with the propagate policy.
+        pub supervisor: Supervisor,
+
+        /// Resources of the running SAR, shared with the threads.
+        resources: Resources,
+
+        /// Native functions of the running SAR, shared with the threads.
+        natives: NativeFunctions,
+
+        /// Exit code the CLI ends with, set when the program fails.
+        pub exit_code: i32,
 
         pub print_handler: thread::JoinHandle<()>,
 
@@ -189,19 +260,23 @@ pub fn trace<T: Display>(print_sender: &Sender<PrintMessage>, message: T) {
     }
 }
 
+/// Result of setting the logger used by the print threads.
+static LOGGER: OnceLock<Result<(), String>> = OnceLock::new();
+
 impl VMStarter {
     /// Instantiates the VMStarter struct. Very straight forward.
     pub fn new(heap_size: usize, repository_size: usize, stack_size: usize) -> VMStarter {
         let (print_sender, print_receiver) = mpsc::channel::<PrintMessage>();
 
         let print_handler = thread::spawn(move || {
-            match simple_logger::init() {
-                Err(err) => {
-                    eprintln!("Error when initializing print thread: {err}");
-                    process::exit(PRINT_THREAD_ERR);
-                }
-                _ => {}
-            };
+            // Set once, every VM in the process shares it.
+            let logger =
+                LOGGER.get_or_init(|| simple_logger::init().map_err(|err| err.to_string()));
+
+            if let Err(err) = logger {
+                eprintln!("Error when initializing print thread: {err}");
+                process::exit(PRINT_THREAD_ERR);
+            }
 
             loop {
                 if let Ok(msg) = print_receiver.recv() {
@@ -240,13 +315,31 @@ impl VMStarter {
             data: Null,
             data_vault: Vec::new(),
             stack: VMStack::new(stack_size),
-            return_stack: VMStack::new(100),
+            return_stack: ReturnStack::new(),
+            handlers: HandlerStack::new(),
             function_stack: Vec::new(),
-            // heap: Arc::new(RwLock::from(VMHeap::new(heap_size))),
+            heap: Arc::new(RwLock::from(VMHeap::new(heap_size))),
+            functions: Vec::new(),
+            current_function: None,
+            parked_program: None,
+            heap_dump_on_oom: None,
+            held_mutexes: HeldMutexes::new(),
             repository: Arc::new(RwLock::from(VMRepository::new(repository_size))),
             #[cfg(feature = "green-threads")]
             task_handlers: Vec::new(),
+            #[cfg(feature = "green-threads")]
+            scheduler: None,
+            #[cfg(feature = "green-threads")]
+            task_workers: thread::available_parallelism().map_or(1, |workers| workers.get()),
+            #[cfg(feature = "green-threads")]
+            task_slice: DEFAULT_TASK_SLICE,
             thread_handlers: Vec::new(),
+            thread_pool: None,
+            thread_pool_size: thread::available_parallelism().map_or(1, |workers| workers.get()),
+            supervisor: Supervisor::new(),
+            resources: Resources::default(),
+            natives: NativeFunctions::default(),
+            exit_code: 0,
             print_handler,
             print_sender,
             data_register: Null,
@@ -266,10 +359,48 @@ impl VMStarter {
     pub fn interpreter(&mut self, file_reader: FileReader) {
         self.instructions = file_reader.instructions;
         self.data_vault = file_reader.data;
+        self.pc = file_reader.start;
+
+        if self.functions.is_empty() && !file_reader.functions.is_empty() {
+            self.load_functions(file_reader.functions);
+        }
 
         sender_dev_print!(&self.print_sender, "Instructions: {:X?}", self.instructions);
 
-        while self.pc < self.instructions.len() && self.running {
+        while self.running {
+            // A thread spawned with the abort policy failed, so the VM stops.
+            if let Some(failure) = self.supervisor.take_abort() {
+                self.abort(failure);
+                break;
+            }
+
+            // A thread spawned with the propagate policy failed, so the main thread fails too.
+            if let Some(failure) = self.supervisor.take_failure() {
+                // Already reported here, so it isn't printed again when joined at the end.
+                if failure.task {
+                    #[cfg(feature = "green-threads")]
+                    if let Some(slot) = self.task_handlers.get_mut(failure.id) {
+                        *slot = None;
+                    }
+                } else if let Some(slot) = self.thread_handlers.get_mut(failure.id) {
+                    *slot = None;
+                }
+
+                self.exit_code = THREAD_ERR;
+                self.panic(failure);
+                break;
+            }
+
+            if self.pc >= self.instructions.len() {
+                // Functions return implicitly when they reach their end.
+                if self.current_function.is_some() && self.pc == self.instructions.len() {
+                    self.instructor(RET);
+                    continue;
+                }
+
+                break;
+            }
+
             let instruction = self.instructions[self.pc];
             self.data = self.data_vault[self.pc].clone();
             self.instruction = instruction;
@@ -281,13 +412,399 @@ impl VMStarter {
         }
 
         if self.pc > self.instructions.len() {
-            self.instructor(PANIC);
-            // panic!("[ PROGRAM COUNTER OUT OF RANGE ]");
+            self.panic("[ PROGRAM COUNTER OUT OF RANGE ]");
         }
 
         self.pc += 1;
     }
 
+    /// Stops the main thread, reporting why it failed, where it was and the values on top of the stack.
+    ///
+    /// The CLI then exits with `PANIC_ERR`, unless an exit code was already set.
+    fn panic(&mut self, message: impl Display) {
+        error(
+            &self.print_sender,
+            format!("Main thread panicked: {message}"),
+        );
+
+        for frame in
+            self.return_stack
+                .trace(self.pc, self.current_function.as_ref(), &self.instructions)
+        {
+            trace(&self.print_sender, format!("\tat {frame}"));
+        }
+
+        let top: Vec<String> = self
+            .stack
+            .stack_memory
+            .iter()
+            .rev()
+            .take(PANIC_STACK_VALUES)
+            .map(|value| format!("{value:?}"))
+            .collect();
+
+        trace(
+            &self.print_sender,
+            format!(
+                "Stack top ({} of {} values): [{}]",
+                top.len(),
+                self.stack.stack_memory.len(),
+                top.join(", ")
+            ),
+        );
+
+        if self.exit_code == 0 {
+            self.exit_code = PANIC_ERR;
+        }
+
+        // The threads still running must not wait forever on them.
+        self.held_mutexes.release_all(MAIN_OWNER);
+
+        self.running = false;
+    }
+
+    /// Stops the VM after a thread or task failed with the `Abort` policy.
+    ///
+    /// The threads and tasks still running are left behind, they aren't joined.
+    pub fn abort(&mut self, failure: ThreadError) {
+        error(&self.print_sender, failure);
+        error(&self.print_sender, "VM aborted by the failure above");
+
+        self.exit_code = THREAD_ERR;
+        self.running = false;
+    }
+
+    /// Waits for a thread or task to end and returns its result.
+    ///
+    /// Gives up with `None` if a failure aborts the VM meanwhile.
+    pub fn wait(&self, handle: &ThreadHandle) -> Option<Result<Immediates, ThreadError>> {
+        loop {
+            if self.supervisor.is_aborted() {
+                return None;
+            }
+
+            if let Some(result) = handle.join_timeout(ABORT_CHECK_INTERVAL) {
+                return Some(result);
+            }
+        }
+    }
+
+    /// Sets how many worker threads run the threads (one per CPU by default).
+    ///
+    /// Must be called before the first `NTHRD`, the pool is started there.
+    pub fn set_thread_pool_size(&mut self, workers: usize) {
+        self.thread_pool_size = workers.max(1);
+    }
+
+    /// Takes a snapshot of the thread pool counters (workers, queued threads and saturation).
+    pub fn thread_pool_stats(&self) -> ThreadPoolStats {
+        match &self.thread_pool {
+            Some(pool) => pool.stats(),
+            None => ThreadPoolStats {
+                workers: self.thread_pool_size,
+                busy: 0,
+                queued: 0,
+                peak_queued: 0,
+                submitted: 0,
+                completed: 0,
+                saturated: 0,
+            },
+        }
+    }
+
+    #[cfg(feature = "green-threads")]
+    /// Sets how many worker threads run the tasks and how many instructions
+    /// a task runs before giving its worker to the next one.
+    ///
+    /// Must be called before the first `NTASK`, the scheduler is started there.
+    /// Keeps the number of workers (one per CPU by default) if it's `None`.
+    pub fn set_task_scheduler(&mut self, workers: Option<usize>, slice: usize) {
+        if let Some(workers) = workers {
+            self.task_workers = workers.max(1);
+        }
+
+        self.task_slice = slice.max(1);
+    }
+
+    /// Takes a snapshot of the heap counters (objects, bytes, regions, peak usage, allocations and GC cycles).
+    pub fn heap_stats(&self) -> HeapStats {
+        self.heap.read().unwrap().stats()
+    }
+
+    /// Dumps the heap to the path every time it runs out of memory.
+    pub fn set_heap_dump_on_oom(&mut self, path: Option<String>) {
+        self.heap_dump_on_oom = path;
+    }
+
+    /// Gives the program the resources of the SAR it runs from.
+    #[cfg(feature = "sar")]
+    pub fn set_resources(&mut self, resources: Resources) {
+        self.resources = resources;
+    }
+
+    /// Gives the program the native functions declared by the SAR it runs from.
+    #[cfg(feature = "sar")]
+    pub fn set_native_functions(&mut self, natives: NativeFunctions) {
+        self.natives = natives;
+    }
+
+    /// Writes the heap regions, objects, pointer graph and roots to the file.
+    /// See the `heap_dump` module for the file format.
+    pub fn dump_heap(&self, path: &str) -> Result<(), String> {
+        let roots = thread_roots(&self.stack, &self.functions, self.current_function.as_ref());
+
+        heap_dump::dump_heap(&self.heap, &self.repository, roots, path)
+    }
+
+    /// Allocates the data in the heap region,
+    /// dumping the heap if it runs out of memory and `heap_dump_on_oom` is set.
+    fn allocate(&self, region: usize, data: DataType) -> Result<Pointer, String> {
+        heap_dump::allocate(
+            &self.heap,
+            &self.repository,
+            self.heap_dump_on_oom.as_deref(),
+            region,
+            data,
+            || thread_roots(&self.stack, &self.functions, self.current_function.as_ref()),
+        )
+    }
+
+    /// Allocates the function objects from the file in a global heap region,
+    /// so they can be loaded with `LDFN`.
+    fn load_functions(&mut self, functions: Vec<Function>) {
+        let region = self.heap.write().unwrap().allocate_global_region();
+
+        for function in functions {
+            match self.allocate(region, DataType::Function(function)) {
+                Ok(pointer) => self.functions.push(pointer),
+                Err(err) => {
+                    eprintln!("\x1B[41m{err}\x1B[0m");
+                    process::exit(HEAP_ALLOC_ERR);
+                }
+            }
+        }
+    }
+
+    /// Call frames of the main thread, used by `CALL`, `RET` and `THROW`.
+    fn frames(&mut self) -> Frames<'_> {
+        Frames {
+            heap: &self.heap,
+            stack: &mut self.stack,
+            return_stack: &mut self.return_stack,
+            handlers: &mut self.handlers,
+            instructions: &mut self.instructions,
+            data: &mut self.data_vault,
+            pc: &mut self.pc,
+            current_function: &mut self.current_function,
+            parked_program: &mut self.parked_program,
+        }
+    }
+
+    /// Pops the entry point and its arguments used by `NTHRD` and `NTASK`.
+    ///
+    /// Stack layout (top last): `<args...> <arg count: UInteger> <entry>`.
+    /// The entry is a jump address (`UInteger`) into the main program, a function object or a closure.
+    fn thread_entry(&mut self) -> Result<ThreadEntry, String> {
+        let entry = self.stack.pop()?;
+
+        let argc = match self.stack.pop()? {
+            UInteger(argc) => argc as usize,
+            _ => return Err("[ WRONG ARGUMENT COUNT ]".to_string()),
+        };
+
+        let mut args = Vec::with_capacity(argc);
+
+        for _ in 0..argc {
+            args.push(self.stack.pop()?);
+        }
+
+        args.reverse();
+
+        match entry {
+            UInteger(address) => {
+                let (instructions, data) = match &self.parked_program {
+                    Some((instructions, data)) => (instructions.clone(), data.clone()),
+                    None => (self.instructions.clone(), self.data_vault.clone()),
+                };
+
+                if address as usize >= instructions.len() {
+                    return Err("[ PROGRAM COUNTER OUT OF RANGE ]".to_string());
+                }
+
+                Ok(ThreadEntry {
+                    instructions,
+                    data,
+                    pc: address as usize,
+                    function: None,
+                    args,
+                })
+            }
+            Immediates::RefPtr(pointer) if pointer.is_fn => {
+                let function = self.heap.read().unwrap().get_function(&pointer)?;
+
+                Ok(ThreadEntry {
+                    instructions: function.instructions,
+                    data: function.data,
+                    pc: 0,
+                    function: Some(pointer),
+                    args,
+                })
+            }
+            Immediates::Closure(closure) => {
+                let Closure { function, captures } = *closure;
+                let object = self.heap.read().unwrap().get_function(&function)?;
+
+                // Captured values come after the arguments, like when it's called.
+                args.extend(captures);
+
+                Ok(ThreadEntry {
+                    instructions: object.instructions,
+                    data: object.data,
+                    pc: 0,
+                    function: Some(function),
+                    args,
+                })
+            }
+            _ => Err("[ INVALID ENTRY POINT ]".to_string()),
+        }
+    }
+
+    /// Creates the VM thread for `NTHRD` (or `NTASK` if `task`) with its own heap region,
+    /// from the entry point and arguments on the stack.
+    fn new_vm_thread(&mut self, task: bool) -> Result<VMThread, String> {
+        let policy = match self.data {
+            UInteger(flags) => SupervisorPolicy::from_flags(flags as u8)?,
+            _ => return Err("[ INVALID SPAWN FLAGS ]".to_string()),
+        };
+
+        let entry = self.thread_entry()?;
+
+        let region = if task {
+            self.heap.write().unwrap().allocate_task_region()
+        } else {
+            self.heap.write().unwrap().allocate_thread_region()
+        };
+
+        let mut thread = VMThread::new(
+            entry,
+            self.functions.clone(),
+            region,
+            Arc::clone(&self.heap),
+            Arc::clone(&self.repository),
+            self.stack.stack_capacity,
+        )?;
+
+        thread.policy = policy;
+        thread.supervisor = self.supervisor.clone();
+        thread.resources = self.resources.clone();
+        thread.natives = self.natives.clone();
+        thread.heap_dump_on_oom = self.heap_dump_on_oom.clone();
+        thread.print_sender = Some(self.print_sender.clone());
+
+        Ok(thread)
+    }
+
+    /// Gets the handle of a thread (or task if `task`), popped from the stack.
+    fn handle(&mut self, task: bool) -> Result<(usize, &mut Option<ThreadHandle>), String> {
+        let handle = match self.stack.pop()? {
+            UInteger(handle) => handle as usize,
+            _ => return Err("[ INVALID HANDLE ]".to_string()),
+        };
+
+        let handlers = if task {
+            #[cfg(not(feature = "green-threads"))]
+            return Err("'green-threads' feature not activated!".to_string());
+
+            #[cfg(feature = "green-threads")]
+            &mut self.task_handlers
+        } else {
+            &mut self.thread_handlers
+        };
+
+        match handlers.get_mut(handle) {
+            Some(slot) if slot.is_some() => Ok((handle, slot)),
+            _ if task => Err(format!("[ INVALID HANDLE ] Task: {handle}")),
+            _ => Err(format!("[ INVALID HANDLE ] Thread: {handle}")),
+        }
+    }
+
+    /// Joins the thread (`JOIN`, `JOINTO`) or awaits the task (`AWAIT`, `AWAITTO`)
+    /// whose handle is popped from the stack, pushing its return value (or error message) and status.
+    ///
+    /// A handle can only be joined once, unless the join timed out.
+    fn join_handle(&mut self, instruction: u8) -> Result<(), String> {
+        let timeout = match instruction {
+            JOINTO | AWAITTO => match self.stack.pop()? {
+                UInteger(millis) => Some(Duration::from_millis(millis)),
+                _ => return Err("[ INVALID TIMEOUT ]".to_string()),
+            },
+            _ => None,
+        };
+
+        let (_, slot) = self.handle(matches!(instruction, AWAIT | AWAITTO))?;
+
+        let (result, status) = match timeout {
+            Some(timeout) => {
+                let handle = slot.as_ref().unwrap();
+
+                match handle.join_timeout(timeout) {
+                    Some(result) => {
+                        let status = handle.status(&result);
+                        *slot = None;
+                        (result, status)
+                    }
+                    None => (Ok(Null), ThreadStatus::TimedOut),
+                }
+            }
+            None => {
+                let handle = slot.take().unwrap();
+
+                // The main loop reports the abort next.
+                let Some(result) = self.wait(&handle) else {
+                    return Ok(());
+                };
+
+                let status = handle.status(&result);
+
+                (result, status)
+            }
+        };
+
+        match result {
+            Ok(value) => self.stack.push(value)?,
+            Err(err) => self.stack.push(err.to_immediate())?,
+        }
+
+        self.stack.push(UInteger(status as u64))
+    }
+
+    /// Runs an atomic operation (`GCAS`, `GFADD` or `GXCHG`) on a global variable.
+    /// The repository write lock is held during the whole operation.
+    fn atomic_global(&mut self, instruction: u8) -> Result<(), String> {
+        let var_name = match self.data {
+            UInteger(var_name) => var_name as usize,
+            _ => return Err("[ WRONG VARIABLE NAME ]".to_string()),
+        };
+
+        let value = self.stack.pop()?;
+
+        let result = match instruction {
+            GCAS => {
+                let expected = self.stack.pop()?;
+                let mut repo = self.repository.write().unwrap();
+                Boolean(repo.compare_and_swap(var_name, &expected, value)?)
+            }
+            GFADD => self
+                .repository
+                .write()
+                .unwrap()
+                .fetch_add(var_name, value)?,
+            _ => self.repository.write().unwrap().swap(var_name, value)?,
+        };
+
+        self.stack.push(result)
+    }
+
     /// Contains all the instructions and their implementations.
     /// Receives an instruction and works around it.
     ///
@@ -455,8 +972,7 @@ impl VMStarter {
                 let value = match self.stack.pop() {
                     Ok(value) => value,
                     Err(err) => {
-                        error(&self.print_sender, format!("{}", err));
-                        self.instructor(PANIC);
+                        self.panic(format!("{}", err));
                         Null
                     }
                 };
@@ -507,18 +1023,16 @@ impl VMStarter {
             AVP => {
                 dev_print!("[ AVP ]");
 
-                let var_pointer = handle_stack_err(self.stack.pop());
+                let var_value = handle_stack_err(self.stack.pop());
                 let var_name = handle_stack_err(self.stack.pop());
 
                 let repo = Arc::clone(&self.repository);
 
                 if let UInteger(var_name) = var_name {
-                    if let UInteger(var_pointer) = var_pointer {
-                        repo.write()
-                            .unwrap()
-                            .add_var(var_name as usize, var_pointer as usize);
-                    } else {
-                        panic!("[ INVALID VAR POINTER ]");
+                    let result = repo.write().unwrap().add_var(var_name as usize, var_value);
+
+                    if let Err(err) = result {
+                        self.panic(err);
                     }
                 } else {
                     panic!("[ INVALID VAR NAME ]");
@@ -530,10 +1044,13 @@ impl VMStarter {
                 let repo = Arc::clone(&self.repository);
 
                 if let UInteger(var_name) = self.data {
-                    println!(
-                        "Pointer: {}",
-                        repo.write().unwrap().get_var(var_name as usize)
-                    );
+                    match repo.read().unwrap().get_var(var_name as usize) {
+                        Ok(value) => println(
+                            &self.print_sender,
+                            format!("Variable {var_name}: {value:?}"),
+                        ),
+                        Err(err) => error(&self.print_sender, err),
+                    }
                 } else {
                     panic!("[ WRONG VARIABLE NAME ]");
                 }
@@ -544,14 +1061,78 @@ impl VMStarter {
                 let repo = Arc::clone(&self.repository);
 
                 if let UInteger(var_name) = handle_stack_err(self.stack.pop()) {
-                    println!(
-                        "Pointer: {}",
-                        repo.write().unwrap().get_var(var_name as usize)
-                    );
+                    match repo.read().unwrap().get_var(var_name as usize) {
+                        Ok(value) => println(
+                            &self.print_sender,
+                            format!("Variable {var_name}: {value:?}"),
+                        ),
+                        Err(err) => error(&self.print_sender, err),
+                    }
                 } else {
                     panic!("[ WRONG VARIABLE NAME ]");
                 }
             }
+            GETG => {
+                sender_dev_print!(&self.print_sender, "[ GETG ]");
+
+                let result = match self.data {
+                    UInteger(var_name) => self
+                        .repository
+                        .read()
+                        .unwrap()
+                        .get_var(var_name as usize)
+                        .cloned(),
+                    _ => Err("[ WRONG VARIABLE NAME ]".to_string()),
+                };
+
+                match result {
+                    Ok(value) => handle_stack_push_err(self.stack.push(value)),
+                    Err(err) => {
+                        self.panic(err);
+                    }
+                }
+            }
+            SETG => {
+                sender_dev_print!(&self.print_sender, "[ SETG ]");
+
+                let value = handle_stack_err(self.stack.pop());
+
+                let result = match self.data {
+                    UInteger(var_name) => self
+                        .repository
+                        .write()
+                        .unwrap()
+                        .add_var(var_name as usize, value),
+                    _ => Err("[ WRONG VARIABLE NAME ]".to_string()),
+                };
+
+                if let Err(err) = result {
+                    self.panic(err);
+                }
+            }
+            DELG => {
+                sender_dev_print!(&self.print_sender, "[ DELG ]");
+
+                let result = match self.data {
+                    UInteger(var_name) => self
+                        .repository
+                        .write()
+                        .unwrap()
+                        .remove_var(var_name as usize),
+                    _ => Err("[ WRONG VARIABLE NAME ]".to_string()),
+                };
+
+                if let Err(err) = result {
+                    self.panic(err);
+                }
+            }
+            GCAS | GFADD | GXCHG => {
+                sender_dev_print!(&self.print_sender, "[ GATOMIC 0x{:02X} ]", instruction);
+
+                if let Err(err) = self.atomic_global(instruction) {
+                    self.panic(err);
+                }
+            }
             0x18 => {
                 dev_print!("[ NTW ]");
 
@@ -567,217 +1148,238 @@ impl VMStarter {
 
                 #[cfg(feature = "green-threads")]
                 {
-                    // ***WIP***
-                    async fn create_new_task(
-                        // heap: Arc<RwLock<VMHeap>>,
-                        repo: Arc<RwLock<VMRepository>>,
-                        threadnum: usize,
-                        stack_size: usize,
-                    ) -> Result<(), String> {
-                        let instructions = vec![
-                            0x0A, 0x0A, 0x01, 0x14, 0x0A, 0x0A, 0x01, 0x14, 0x0A, 0x0A, 0x01, 0x14,
-                            0x0A, 0x0A, 0x01, 0x14, 0x01, 0,
-                        ];
-
-                        let data = vec![
-                            Integer(1),
-                            Integer(1),
-                            Null,
-                            Null,
-                            Integer(1),
-                            Integer(1),
-                            Null,
-                            Null,
-                            Integer(1),
-                            Integer(1),
-                            Null,
-                            Null,
-                            Integer(1),
-                            Integer(1),
-                            Null,
-                            Null,
-                            Null,
-                            Null,
-                        ];
-
-                        let mut thread = VMThread::new(instructions, data, &repo, stack_size);
-
-                        let mut error: Option<String> = None;
-
-                        while thread.running {
-                            #[cfg(feature = "devkit")]
-                            dev_print!("Task Instructions: {:X?}", thread.instructions);
-
-                            while thread.pc < thread.instructions.len() {
-                                let instruction = thread.instructions[thread.pc];
-                                thread.data = thread.data_vault[thread.pc].clone();
-                                thread.instruction = instruction;
-                                thread.pc += 1;
-                                match thread.instructor(instruction) {
-                                    Ok(_) => {
-                                        #[cfg(feature = "devkit")]
-                                        dev_print!("Task {} is working!", threadnum);
-                                    }
-                                    Err(err) => {
-                                        let task_err = format!("Task {threadnum} error: {err}");
-                                        error = Some(task_err);
-                                        thread.running = false;
-                                    }
-                                };
-                                #[cfg(feature = "devkit")]
-                                dev_print!("Task {} PC: {}", threadnum, thread.pc);
-                                // println!("Length: {}", self.heap.heap_memory.len());
-                            }
+                    let thread = match self.new_vm_thread(true) {
+                        Ok(thread) => thread,
+                        Err(err) => {
+                            self.panic(err);
+                            return;
+                        }
+                    };
 
-                            if thread.pc > thread.instructions.len() {
-                                panic!("[ PROGRAM COUNTER OUT OF RANGE ]");
-                            }
+                    let threadnum = self.task_handlers.len();
 
-                            thread.pc += 1;
-                        }
+                    let (workers, slice) = (self.task_workers, self.task_slice);
 
-                        if let Some(err) = error {
-                            Err(err)
-                        } else {
-                            #[cfg(feature = "devkit")]
-                            dev_print!("\x1B[42mTask {threadnum} ended successfully\x1B[0m");
+                    let handle = self
+                        .scheduler
+                        .get_or_insert_with(|| Scheduler::new(workers, slice))
+                        .spawn(threadnum, thread);
 
-                            Ok(())
-                        }
+                    // Detached tasks don't give a handle back, but still take a slot so ids stay unique.
+                    if matches!(self.data, UInteger(flags) if flags as u8 & SPAWN_TRACKED != 0) {
+                        self.task_handlers.push(Some(handle));
+                        handle_stack_push_err(self.stack.push(UInteger(threadnum as u64)));
+                    } else {
+                        self.task_handlers.push(None);
                     }
+                }
+            }
+            NTHRD => {
+                let thread = match self.new_vm_thread(false) {
+                    Ok(thread) => thread,
+                    Err(err) => {
+                        self.panic(err);
+                        return;
+                    }
+                };
 
-                    // let heap = Arc::clone(&self.heap);
-                    let repo = Arc::clone(&self.repository);
+                let threadnum = self.thread_handlers.len();
 
-                    let threadnum = self.task_handlers.len();
+                let workers = self.thread_pool_size;
 
-                    let stack_size = self.stack.stack_capacity;
+                let handle = self
+                    .thread_pool
+                    .get_or_insert_with(|| ThreadPool::new(workers))
+                    .spawn(threadnum, thread);
 
-                    let handle = task::spawn(create_new_task(repo, threadnum, stack_size));
+                // Detached threads don't give a handle back, but still take a slot so ids stay unique.
+                if matches!(self.data, UInteger(flags) if flags as u8 & SPAWN_TRACKED != 0) {
+                    self.thread_handlers.push(Some(handle));
+                    handle_stack_push_err(self.stack.push(UInteger(threadnum as u64)));
+                } else {
+                    self.thread_handlers.push(None);
+                }
+            }
+            CHNEW | CHSEND | CHRECV | CHTRY | CHCLOSE => {
+                sender_dev_print!(&self.print_sender, "[ CHANNEL 0x{:02X} ]", instruction);
 
-                    // println!("Data: {:?}", self.data);
-                    if let Boolean(bool) = self.data {
-                        if bool {
-                            self.task_handlers.push(handle);
-                        }
-                    }
+                let result = channel_instruction(instruction, &self.data, &mut self.stack, true);
+
+                if let Err(err) = result {
+                    self.panic(err);
                 }
             }
-            NTHRD => {
-                // ***WIP***
-                fn create_new_thread(
-                    // heap: Arc<RwLock<VMHeap>>,
-                    repo: Arc<RwLock<VMRepository>>,
-                    threadnum: usize,
-                    stack_size: usize,
-                ) -> Result<(), String> {
-                    let instructions = vec![
-                        0x0A, 0x0A, 0x01, 0x14, 0x0A, 0x0A, 0x01, 0x14, 0x0A, 0x0A, 0x01, 0x14,
-                        0x0A, 0x0A, 0x01, 0x14, 0x01, 0x01, 0x01, 0x01, 0x01, 0x00,
-                    ];
-
-                    let data = vec![
-                        Integer(1),
-                        Integer(1),
-                        Null,
-                        Null,
-                        Integer(1),
-                        Integer(1),
-                        Null,
-                        Null,
-                        Integer(1),
-                        Integer(1),
-                        Null,
-                        Null,
-                        Integer(1),
-                        Integer(1),
-                        Null,
-                        Null,
-                        Null,
-                        Null,
-                        Null,
-                        Null,
-                        Null,
-                        Null,
-                    ];
-
-                    let mut thread = VMThread::new(instructions, data, &repo, stack_size);
-
-                    let mut error: Option<String> = None;
-
-                    while thread.running {
-                        #[cfg(feature = "devkit")]
-                        dev_print!("Thread Instructions: {:X?}", thread.instructions);
-
-                        while thread.pc < thread.instructions.len() {
-                            let instruction = thread.instructions[thread.pc];
-                            thread.data = thread.data_vault[thread.pc].clone();
-                            thread.instruction = instruction;
-                            thread.pc += 1;
-                            match thread.instructor(instruction) {
-                                Ok(_) => {
-                                    #[cfg(feature = "devkit")]
-                                    dev_print!("Thread {} is working!", threadnum);
-                                }
-                                Err(err) => {
-                                    let thread_err = format!("Thread {threadnum} error: {err}");
-                                    error = Some(thread_err);
-                                    thread.running = false;
-                                }
-                            };
-                            #[cfg(feature = "devkit")]
-                            dev_print!("Thread {} PC: {}", threadnum, thread.pc);
-                            // println!("Length: {}", self.heap.heap_memory.len());
-                        }
+            MXNEW | MXLOCK | MXUNLOCK | CVNEW | CVWAIT | CVNOTIFY | CVNOTIFYALL | ATNEW
+            | ATLOAD | ATSTORE | ATFADD | ATCAS => {
+                sender_dev_print!(&self.print_sender, "[ SYNC 0x{:02X} ]", instruction);
+
+                let allocate = |stack: &VMStack, data| {
+                    heap_dump::allocate(
+                        &self.heap,
+                        &self.repository,
+                        self.heap_dump_on_oom.as_deref(),
+                        0,
+                        data,
+                        || thread_roots(stack, &self.functions, self.current_function.as_ref()),
+                    )
+                };
 
-                        if thread.pc > thread.instructions.len() {
-                            panic!("[ PROGRAM COUNTER OUT OF RANGE ]");
-                        }
+                let result = sync_instruction(
+                    instruction,
+                    &mut self.stack,
+                    &self.heap,
+                    &allocate,
+                    MAIN_OWNER,
+                    &mut self.held_mutexes,
+                    true,
+                );
+
+                if let Err(err) = result {
+                    self.panic(err);
+                }
+            }
+            YIELD => {
+                sender_dev_print!(&self.print_sender, "[ YIELD ]");
+
+                thread::yield_now();
+            }
+            TRY => {
+                sender_dev_print!(&self.print_sender, "[ TRY ]");
+
+                if let UInteger(handler) = self.data {
+                    self.handlers.push(TryHandler {
+                        handler: handler as usize,
+                        stack_len: self.stack.get_length(),
+                        frames: self.return_stack.get_length(),
+                    });
+                } else {
+                    self.panic("[ WRONG ADDRESS ]");
+                }
+            }
+            ENDTRY => {
+                sender_dev_print!(&self.print_sender, "[ ENDTRY ]");
+
+                if let Err(err) = self.handlers.pop() {
+                    self.panic(err);
+                }
+            }
+            THROW => {
+                sender_dev_print!(&self.print_sender, "[ THROW ]");
+
+                let payload = handle_stack_err(self.stack.pop());
+
+                if let Err(err) = self.frames().throw(payload) {
+                    self.panic(err);
+                }
+            }
+            RSREAD | RSREADS | RSLIST | RSEXISTS => {
+                sender_dev_print!(&self.print_sender, "[ RESOURCE 0x{:02X} ]", instruction);
+
+                if let Err(err) =
+                    resource_instruction(instruction, &self.resources, &mut self.stack)
+                {
+                    self.panic(err);
+                }
+            }
+            CALLNATIVE => {
+                sender_dev_print!(&self.print_sender, "[ CALLNATIVE ]");
+
+                if let Err(err) = native_instruction(&self.natives, &mut self.stack) {
+                    self.panic(err);
+                }
+            }
+            CANCEL | TCANCEL => {
+                sender_dev_print!(&self.print_sender, "[ CANCEL 0x{:02X} ]", instruction);
 
-                        thread.pc += 1;
+                match self.handle(instruction == TCANCEL) {
+                    Ok((_, slot)) => slot.as_ref().unwrap().cancel(),
+                    Err(err) => {
+                        self.panic(err);
                     }
+                }
+            }
+            JOIN | AWAIT | JOINTO | AWAITTO => {
+                sender_dev_print!(&self.print_sender, "[ JOIN 0x{:02X} ]", instruction);
 
-                    if let Some(err) = error {
-                        Err(err)
-                    } else {
-                        #[cfg(feature = "devkit")]
-                        dev_print!("\x1B[42mThread {threadnum} ended successfully\x1B[0m");
+                if let Err(err) = self.join_handle(instruction) {
+                    self.panic(err);
+                }
+            }
+            LDFN => {
+                sender_dev_print!(&self.print_sender, "[ LDFN ]");
+
+                let function = match self.data {
+                    UInteger(index) => self.functions.get(index as usize).cloned(),
+                    _ => None,
+                };
 
-                        Ok(())
+                if let Some(pointer) = function {
+                    handle_stack_push_err(self.stack.push(Immediates::RefPtr(pointer)));
+                } else {
+                    self.panic("[ UNDEFINED FUNCTION ]");
+                }
+            }
+            CALL => {
+                sender_dev_print!(&self.print_sender, "[ CALL ]");
+
+                let result = match handle_stack_err(self.stack.pop()) {
+                    Immediates::RefPtr(pointer) if pointer.is_fn => {
+                        self.frames().call(pointer, Vec::new())
                     }
+                    Immediates::Closure(closure) => {
+                        let Closure { function, captures } = *closure;
+                        self.frames().call(function, captures)
+                    }
+                    _ => Err("[ NOT A FUNCTION ]".to_string()),
+                };
+
+                if let Err(err) = result {
+                    self.panic(err);
                 }
+            }
+            RET => {
+                sender_dev_print!(&self.print_sender, "[ RET ]");
 
-                // let heap = Arc::clone(&self.heap);
-                let repo = Arc::clone(&self.repository);
+                if let Err(err) = self.frames().ret() {
+                    self.panic(err);
+                }
+            }
+            CLOSURE => {
+                sender_dev_print!(&self.print_sender, "[ CLOSURE ]");
 
-                let threadnum = self.thread_handlers.len();
+                let captures = if let UInteger(captures) = self.data {
+                    captures as usize
+                } else {
+                    0
+                };
 
-                let stack_size = self.stack.stack_capacity;
+                match create_closure(&mut self.stack, captures) {
+                    Ok(closure) => handle_stack_push_err(self.stack.push(closure)),
+                    Err(err) => self.panic(err),
+                }
+            }
+            HDUMP => {
+                sender_dev_print!(&self.print_sender, "[ HDUMP ]");
 
-                let handle = thread::spawn(move || create_new_thread(repo, threadnum, stack_size));
+                let result = match handle_stack_err(self.stack.pop()) {
+                    MutStr(path) => self.dump_heap(&path),
+                    StaticStr(path) => self.dump_heap(&path),
+                    _ => Err("[ INVALID HEAP DUMP PATH ]".to_string()),
+                };
 
-                // println!("Data: {:?}", self.data);
-                if let Boolean(bool) = self.data {
-                    if bool {
-                        self.thread_handlers.push(handle);
-                    }
+                if let Err(err) = result {
+                    self.panic(err);
                 }
             }
             PANIC => {
                 sender_dev_print!(&self.print_sender, "[ PANIC ]");
 
-                error(&self.print_sender, "Main thread panicked");
-                trace(&self.print_sender, format!("Main {:?}", self.stack));
-                trace(
-                    &self.print_sender,
-                    format!("Program Counter: {:?}", self.pc),
-                );
-                trace(
-                    &self.print_sender,
-                    format!("Last instruction: 0x{:02X}", self.instructions[self.pc - 1]),
-                );
+                let message = self
+                    .stack
+                    .pop()
+                    .map_or(EXPLICIT_PANIC.to_string(), |value| value.to_string());
 
-                self.running = false;
+                self.panic(message);
             }
             PEEK => {
                 sender_dev_print!(&self.print_sender, "[ PEEK ]");
@@ -801,37 +1403,27 @@ impl VMStarter {
                                     Ok(_) => match self.stack.push(second_last) {
                                         Ok(_) => {}
                                         Err(err) => {
-                                            error(&self.print_sender, err);
-                                            self.instructor(PANIC);
+                                            self.panic(err);
                                         }
                                     },
                                     Err(err) => {
-                                        error(&self.print_sender, err);
-                                        self.instructor(PANIC);
+                                        self.panic(err);
                                     }
                                 };
                             }
                             Err(err) => {
-                                error(&self.print_sender, err);
-                                self.instructor(PANIC);
+                                self.panic(err);
                             }
                         };
                     }
                     Err(err) => {
-                        error(&self.print_sender, err);
-                        self.instructor(PANIC);
+                        self.panic(err);
                     }
                 }
 
                 sender_dev_print!(&self.print_sender, "After SWAP: {:?}", self.stack);
             }
-            _ => {
-                self.stack
-                    .push(MutStr("[ UNKNOWN INSTRUCTION ]".to_string()))
-                    .unwrap();
-                self.instructor(PANIC);
-                // panic!("[ UNKNOWN INSTRUCTION ]")
-            }
+            _ => self.panic(format!("[ UNKNOWN INSTRUCTION ] 0x{instruction:02X}")),
         }
     }
 }
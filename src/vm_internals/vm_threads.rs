@@ -1,20 +1,75 @@
 use crate::instructiondefs::*;
+use crate::vm_internals::channel::channel_instruction;
+use crate::vm_internals::exceptions::{HandlerStack, TryHandler};
+use crate::vm_internals::frames::{create_closure, Frames};
+use crate::vm_internals::heap::Pointer;
+use crate::vm_internals::heap_dump::{self, thread_roots};
+use crate::vm_internals::immediates::Closure;
 use crate::vm_internals::immediates::Immediates::{
     self, Array, Binary, Boolean, Float, Integer, MutStr as TypeString, Null, UInteger,
 };
+use crate::vm_internals::native::{native_instruction, NativeFunctions};
 use crate::vm_internals::open_window;
-use crate::vm_internals::{VMHeap, VMRepository, VMStack};
+use crate::vm_internals::resources::{resource_instruction, Resources};
+use crate::vm_internals::return_stack::ReturnStack;
+use crate::vm_internals::supervisor::{Supervisor, SupervisorPolicy, ThreadError, EXPLICIT_PANIC};
+use crate::vm_internals::sync_objects::{
+    next_owner_id, pop_condition, pop_mutex, sync_instruction, HeldMutexes, VMCondition, VMMutex,
+};
+use crate::vm_internals::{PrintMessage, VMHeap, VMRepository, VMStack};
 
 #[cfg(feature = "green-threads")]
 use async_std::task;
-#[cfg(feature = "green-threads")]
-use async_std::task::JoinHandle;
+use std::fmt::Display;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
 use std::sync::{Arc, RwLock};
 
+/// Error a thread ends with when it's cancelled.
+pub const CANCELLED: &str = "[ CANCELLED ]";
+
+/// ## Token used to cancel a thread or task.
+///
+/// Checked by the thread before every instruction, so a thread blocked
+/// inside an instruction (like receiving from a channel) only stops once it wakes up.
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        CancelToken(Arc::new(AtomicBool::new(false)))
+    }
+
+    /// Asks the thread to stop.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+debug_derive!(
+    /// Code and arguments a new thread or task starts with.
+    pub struct ThreadEntry {
+        /// Code the thread will run, from the main program or from a function object.
+        pub instructions: Vec<u8>,
+        /// Data used by the code.
+        pub data: Vec<Immediates>,
+        /// Address the thread starts at.
+        pub pc: usize,
+        /// The function being run, `None` if it's the main program code.
+        pub function: Option<Pointer>,
+        /// Values pushed to the thread's stack before it starts.
+        pub args: Vec<Immediates>,
+    }
+);
+
 debug_derive!(
     /// Creates new threads that the VM can handle,
     /// almost the same implementation of the VMStarter struct
-    pub struct VMThread<'a> {
+    pub struct VMThread {
         pub running: bool,
         pub pc: usize,
         pub instruction: u8,
@@ -22,36 +77,308 @@ debug_derive!(
         pub data: Immediates,
         pub data_vault: Vec<Immediates>,
         pub stack: VMStack,
-        /// Heap is borrowed
-        // pub heap: &'a Arc<RwLock<VMHeap>>,
-        /// Repository is borrowed
-        pub repository: &'a Arc<RwLock<VMRepository>>,
-        #[cfg(feature = "green-threads")]
-        pub task_handlers: Vec<JoinHandle<Result<(), String>>>,
+        /// Contains the return addresses of the functions being called.
+        pub return_stack: ReturnStack,
+        /// Try regions still open.
+        pub handlers: HandlerStack,
+        /// Function being run, `None` if it's the main program code.
+        pub current_function: Option<Pointer>,
+        /// Main program code, parked while a function is running.
+        pub parked_program: Option<(Vec<u8>, Vec<Immediates>)>,
+        /// Pointers to the function objects loaded from the file.
+        pub functions: Vec<Pointer>,
+        /// Heap region used by the thread.
+        pub region: usize,
+        /// Identifies the thread as the owner of the mutexes it locks.
+        pub owner: u64,
+        /// Run by the task scheduler, gives the worker up instead of blocking.
+        pub cooperative: bool,
+        /// Set when the last instruction would block and has to be run again.
+        pub blocked: bool,
+        /// Set when the thread gave its worker up (`YIELD` or a blocking instruction).
+        yielded: bool,
+        /// Mutex to lock again before continuing, after a cooperative `CVWAIT`.
+        pending_lock: Option<VMMutex>,
+        /// Condition a cooperative `CVWAIT` waits to be notified on.
+        pending_wait: Option<VMCondition>,
+        /// Set when an instruction of the current slice may have released a blocked task
+        /// (a channel operation, an unlock or a notify).
+        released: bool,
+        /// Mutexes locked by the thread, released when it ends.
+        held_mutexes: HeldMutexes,
+        /// Stops the thread when cancelled.
+        pub cancel_token: CancelToken,
+        /// What to do if the thread fails.
+        pub policy: SupervisorPolicy,
+        /// Gets the failures of the thread, depending on the policy.
+        pub supervisor: Supervisor,
+        /// Resources of the running SAR, shared with the main thread.
+        pub resources: Resources,
+        /// Native functions of the running SAR, shared with the main thread.
+        pub natives: NativeFunctions,
+        /// Heap is shared with the main thread
+        pub heap: Arc<RwLock<VMHeap>>,
+        /// Repository is shared with the main thread
+        pub repository: Arc<RwLock<VMRepository>>,
+        /// Where to dump the heap when it runs out of memory, if anywhere.
+        pub heap_dump_on_oom: Option<String>,
+        /// Print channel of the main thread, so the output keeps its order.
+        /// Prints straight to stdout without one.
+        pub print_sender: Option<Sender<PrintMessage>>,
     }
 );
 
-impl VMThread<'_> {
+/// How a time slice of a thread ended.
+pub enum Slice {
+    /// The thread ended, returning the value on top of its stack.
+    Finished(Immediates),
+    /// The thread can still run, either its time slice ended or it ran `YIELD`.
+    Yielded {
+        /// Tells if the slice may have released a blocked task.
+        progressed: bool,
+    },
+    /// The thread gave its worker up on an instruction that would block.
+    Blocked {
+        /// Tells if the slice may have released a blocked task before blocking.
+        progressed: bool,
+    },
+}
+
+/// Releases the thread's heap region and the mutexes it still holds when it ends,
+/// however it ends.
+impl Drop for VMThread {
+    fn drop(&mut self) {
+        if let Some(condition) = self.pending_wait.take() {
+            condition.leave();
+        }
+
+        self.held_mutexes.release_all(self.owner);
+
+        if let Ok(mut heap) = self.heap.write() {
+            heap.end_region(self.region);
+        }
+    }
+}
+
+impl VMThread {
     pub fn new(
-        instructions: Vec<u8>,
-        data_vault: Vec<Immediates>,
-        // heap: &'a Arc<RwLock<VMHeap>>,
-        repo: &Arc<RwLock<VMRepository>>,
+        entry: ThreadEntry,
+        functions: Vec<Pointer>,
+        region: usize,
+        heap: Arc<RwLock<VMHeap>>,
+        repo: Arc<RwLock<VMRepository>>,
         stack_size: usize,
-    ) -> VMThread {
-        VMThread {
+    ) -> Result<VMThread, String> {
+        let mut thread = VMThread {
             running: true,
-            pc: 0,
+            pc: entry.pc,
             instruction: 0,
-            instructions,
+            instructions: entry.instructions,
             data: Null,
-            data_vault,
-            stack: VMStack::new(stack_size),
-            // heap,
+            data_vault: entry.data,
+            stack: VMStack::new_lazy(stack_size),
+            return_stack: ReturnStack::new(),
+            handlers: HandlerStack::new(),
+            current_function: entry.function,
+            parked_program: None,
+            functions,
+            region,
+            owner: next_owner_id(),
+            cooperative: false,
+            blocked: false,
+            yielded: false,
+            pending_lock: None,
+            pending_wait: None,
+            released: false,
+            held_mutexes: HeldMutexes::new(),
+            cancel_token: CancelToken::new(),
+            policy: SupervisorPolicy::Ignore,
+            supervisor: Supervisor::new(),
+            resources: Resources::default(),
+            natives: NativeFunctions::default(),
+            heap,
             repository: repo,
-            #[cfg(feature = "green-threads")]
-            task_handlers: Vec::new(),
+            heap_dump_on_oom: None,
+            print_sender: None,
+        };
+
+        for arg in entry.args {
+            thread.stack.push(arg)?;
         }
+
+        Ok(thread)
+    }
+
+    /// Runs the thread until it halts, errors out or its code ends.
+    ///
+    /// A thread started on a function ends when the function returns.
+    /// Returns the value on top of the stack (`Null` if it's empty), which is given to `JOIN`/`AWAIT`.
+    pub fn run(&mut self) -> Result<Immediates, String> {
+        loop {
+            if let Slice::Finished(value) = self.run_slice(usize::MAX)? {
+                return Ok(value);
+            }
+        }
+    }
+
+    /// Runs at most `budget` instructions, stopping earlier if the thread ends or yields.
+    pub fn run_slice(&mut self, budget: usize) -> Result<Slice, String> {
+        self.blocked = false;
+        self.released = false;
+
+        // Checked before the pending wait too, so a parked task can be cancelled.
+        if self.cancel_token.is_cancelled() {
+            return Err(CANCELLED.to_string());
+        }
+
+        if let Some(condition) = &self.pending_wait {
+            if !condition.take_permit() {
+                self.blocked = true;
+                return Ok(Slice::Blocked { progressed: false });
+            }
+
+            self.pending_wait = None;
+        }
+
+        if let Some(mutex) = self.pending_lock.take() {
+            if !mutex.try_lock(self.owner)? {
+                self.pending_lock = Some(mutex);
+                self.blocked = true;
+                return Ok(Slice::Blocked { progressed: false });
+            }
+
+            self.held_mutexes.hold(mutex);
+        }
+
+        let mut executed = 0;
+
+        while self.running {
+            if self.cancel_token.is_cancelled() {
+                return Err(CANCELLED.to_string());
+            }
+
+            if executed == budget {
+                return Ok(Slice::Yielded {
+                    progressed: self.released,
+                });
+            }
+
+            if self.pc >= self.instructions.len() {
+                // Functions return implicitly when they reach their end.
+                if self.current_function.is_some() && self.pc == self.instructions.len() {
+                    self.instructor(RET)?;
+                    continue;
+                }
+
+                if self.pc > self.instructions.len() {
+                    return Err("[ PROGRAM COUNTER OUT OF RANGE ]".to_string());
+                }
+
+                break;
+            }
+
+            let instruction = self.instructions[self.pc];
+            self.data = self.data_vault[self.pc].clone();
+            self.instruction = instruction;
+            self.pc += 1;
+            self.instructor(instruction)?;
+
+            executed += 1;
+
+            if self.yielded {
+                self.yielded = false;
+
+                let progressed = self.released;
+
+                if self.blocked {
+                    return Ok(Slice::Blocked { progressed });
+                }
+
+                return Ok(Slice::Yielded { progressed });
+            }
+        }
+
+        Ok(Slice::Finished(self.stack.pop().unwrap_or(Null)))
+    }
+
+    /// Call frames of the thread, innermost first.
+    pub fn stack_trace(&self) -> Vec<String> {
+        self.return_stack
+            .trace(self.pc, self.current_function.as_ref(), &self.instructions)
+    }
+
+    /// Builds the error the thread failed with and reports it to the supervisor.
+    ///
+    /// Cancelled threads aren't reported, they were stopped on purpose.
+    pub fn fail(&self, id: usize, task: bool, message: String) -> ThreadError {
+        let error = ThreadError {
+            id,
+            task,
+            message,
+            trace: self.stack_trace(),
+        };
+
+        if !self.cancel_token.is_cancelled() {
+            self.supervisor.report(self.policy, &error);
+        }
+
+        error
+    }
+
+    /// Prints through the print channel, or straight to stdout
+    /// if there is none or the main thread already closed it.
+    fn print(&self, message: impl Display) {
+        let message = message.to_string();
+
+        if let Some(print_sender) = &self.print_sender {
+            if print_sender
+                .send(PrintMessage::Print(message.clone().into()))
+                .is_ok()
+            {
+                return;
+            }
+        }
+
+        print!("{message}");
+    }
+
+    /// Prints a line like `print`.
+    fn println(&self, message: impl Display) {
+        self.print(format!("{message}\n"));
+    }
+
+    /// Runs the instruction again on the next time slice, since it would block.
+    fn retry_later(&mut self) {
+        self.pc -= 1;
+        self.blocked = true;
+        self.yielded = true;
+    }
+
+    /// Call frames of the thread, used by `CALL`, `RET` and `THROW`.
+    fn frames(&mut self) -> Frames<'_> {
+        Frames {
+            heap: &self.heap,
+            stack: &mut self.stack,
+            return_stack: &mut self.return_stack,
+            handlers: &mut self.handlers,
+            instructions: &mut self.instructions,
+            data: &mut self.data_vault,
+            pc: &mut self.pc,
+            current_function: &mut self.current_function,
+            parked_program: &mut self.parked_program,
+        }
+    }
+
+    /// Returns from the current function to the address saved in the return stack.
+    ///
+    /// Ends the thread if it was started on the function.
+    fn return_function(&mut self) -> Result<(), String> {
+        if self.return_stack.check_empty() && self.parked_program.is_none() {
+            self.running = false;
+            return Ok(());
+        }
+
+        self.frames().ret()
     }
 
     /// Contains all the instructions and their implementations.
@@ -396,14 +723,14 @@ impl VMThread<'_> {
                     }
                 };
 
-                print!("{}", value);
+                self.print(value);
 
                 Ok(())
             }
             PRTFD => {
                 dev_print!("[ PRTFD ]");
 
-                print!("{}", &self.data);
+                self.print(&self.data);
 
                 Ok(())
             }
@@ -494,7 +821,7 @@ impl VMThread<'_> {
             AVP => {
                 dev_print!("[ AVP ]");
 
-                let var_pointer = match self.stack.pop() {
+                let var_value = match self.stack.pop() {
                     Ok(value) => value,
                     Err(err) => {
                         return Err(err);
@@ -510,14 +837,7 @@ impl VMThread<'_> {
                 let repo = Arc::clone(&self.repository);
 
                 if let UInteger(var_name) = var_name {
-                    if let UInteger(var_pointer) = var_pointer {
-                        repo.write()
-                            .unwrap()
-                            .add_var(var_name as usize, var_pointer as usize);
-                        Ok(())
-                    } else {
-                        Err("[ INVALID VAR POINTER ]".to_string())
-                    }
+                    repo.write().unwrap().add_var(var_name as usize, var_value)
                 } else {
                     Err("[ WRONG VARIABLE NAME ]".to_string())
                 }
@@ -528,10 +848,8 @@ impl VMThread<'_> {
                 let repo = Arc::clone(&self.repository);
 
                 if let UInteger(var_name) = self.data {
-                    println!(
-                        "Pointer: {}",
-                        repo.write().unwrap().get_var(var_name as usize)
-                    );
+                    let value = repo.read().unwrap().get_var(var_name as usize)?.clone();
+                    self.println(format!("Variable {var_name}: {value:?}"));
                     Ok(())
                 } else {
                     Err("[ WRONG VARIABLE NAME ]".to_string())
@@ -550,15 +868,103 @@ impl VMThread<'_> {
                 };
 
                 if let UInteger(var_name) = value {
-                    println!(
-                        "Pointer: {}",
-                        repo.write().unwrap().get_var(var_name as usize)
-                    );
+                    let value = repo.read().unwrap().get_var(var_name as usize)?.clone();
+                    self.println(format!("Variable {var_name}: {value:?}"));
                     Ok(())
                 } else {
                     Err("[ WRONG VARIABLE NAME ]".to_string())
                 }
             }
+            GETG => {
+                dev_print!("[ GETG ]");
+
+                if let UInteger(var_name) = self.data {
+                    let value = self
+                        .repository
+                        .read()
+                        .unwrap()
+                        .get_var(var_name as usize)?
+                        .clone();
+                    self.stack.push(value)
+                } else {
+                    Err("[ WRONG VARIABLE NAME ]".to_string())
+                }
+            }
+            SETG => {
+                dev_print!("[ SETG ]");
+
+                let value = self.stack.pop()?;
+
+                if let UInteger(var_name) = self.data {
+                    self.repository
+                        .write()
+                        .unwrap()
+                        .add_var(var_name as usize, value)
+                } else {
+                    Err("[ WRONG VARIABLE NAME ]".to_string())
+                }
+            }
+            DELG => {
+                dev_print!("[ DELG ]");
+
+                if let UInteger(var_name) = self.data {
+                    self.repository
+                        .write()
+                        .unwrap()
+                        .remove_var(var_name as usize)
+                } else {
+                    Err("[ WRONG VARIABLE NAME ]".to_string())
+                }
+            }
+            GCAS => {
+                dev_print!("[ GCAS ]");
+
+                let new = self.stack.pop()?;
+                let expected = self.stack.pop()?;
+
+                if let UInteger(var_name) = self.data {
+                    let swapped = self.repository.write().unwrap().compare_and_swap(
+                        var_name as usize,
+                        &expected,
+                        new,
+                    )?;
+                    self.stack.push(Boolean(swapped))
+                } else {
+                    Err("[ WRONG VARIABLE NAME ]".to_string())
+                }
+            }
+            GFADD => {
+                dev_print!("[ GFADD ]");
+
+                let delta = self.stack.pop()?;
+
+                if let UInteger(var_name) = self.data {
+                    let old = self
+                        .repository
+                        .write()
+                        .unwrap()
+                        .fetch_add(var_name as usize, delta)?;
+                    self.stack.push(old)
+                } else {
+                    Err("[ WRONG VARIABLE NAME ]".to_string())
+                }
+            }
+            GXCHG => {
+                dev_print!("[ GXCHG ]");
+
+                let value = self.stack.pop()?;
+
+                if let UInteger(var_name) = self.data {
+                    let old = self
+                        .repository
+                        .write()
+                        .unwrap()
+                        .swap(var_name as usize, value)?;
+                    self.stack.push(old)
+                } else {
+                    Err("[ WRONG VARIABLE NAME ]".to_string())
+                }
+            }
             0x18 => {
                 dev_print!("[ NTW ]");
 
@@ -573,106 +979,180 @@ impl VMThread<'_> {
                 #[cfg(not(feature = "green-threads"))]
                 Err("Green-threads not activated!".to_string())
             }
-            #[cfg(feature = "green-threads")]
-            // NTASK => {
-            //     // ***WIP***
-            //     async fn create_new_task(
-            //         heap: Arc<RwLock<VMHeap>>,
-            //         repo: Arc<RwLock<VMRepository>>,
-            //         threadnum: usize,
-            //     ) -> Result<(), String> {
-            //         let mut thread = VMThread {
-            //             running: true,
-            //             pc: 0,
-            //             instruction: 0,
-            //             instructions: vec![
-            //                 0x0A, 0x0A, 0x01, 0x14, 0x0A, 0x0A, 0x01, 0x14, 0x0A, 0x0A, 0x01, 0x14,
-            //                 0x0A, 0x0A, 0x01, 0x14, 0,
-            //             ],
-            //             data: Null,
-            //             data_vault: vec![
-            //                 Integer(1),
-            //                 Integer(1),
-            //                 Null,
-            //                 Null,
-            //                 Integer(1),
-            //                 Integer(1),
-            //                 Null,
-            //                 Null,
-            //                 Integer(1),
-            //                 Integer(1),
-            //                 Null,
-            //                 Null,
-            //                 Integer(1),
-            //                 Integer(1),
-            //                 Null,
-            //                 Null,
-            //                 Null,
-            //             ],
-            //             stack: VMStack::new(),
-            //             heap: &heap,
-            //             repository: &repo,
-            //             task_handlers: Vec::new(),
-            //         };
-            //
-            //         let mut error: Option<String> = None;
-            //
-            //         while thread.running {
-            //             dev_print!("Thread Instructions: {:X?}", thread.instructions);
-            //
-            //             while thread.pc < thread.instructions.len() {
-            //                 let instruction = thread.instructions[thread.pc];
-            //                 thread.data = thread.data_vault[thread.pc].clone();
-            //                 thread.instruction = instruction;
-            //                 thread.pc += 1;
-            //                 match thread.instructor(instruction) {
-            //                     Ok(_) => {
-            //                         #[cfg(feature = "devkit")]
-            //                         dev_print!("Thread {} is working!", threadnum);
-            //                     }
-            //                     Err(err) => {
-            //                         error = Some(err);
-            //                         thread.running = false;
-            //                     }
-            //                 };
-            //                 dev_print!("{}", thread.pc);
-            //                 // println!("Length: {}", self.heap.heap_memory.len());
-            //             }
-            //
-            //             if thread.pc > thread.instructions.len() {
-            //                 panic!("[ PROGRAM COUNTER OUT OF RANGE ]");
-            //             }
-            //
-            //             thread.pc += 1;
-            //         }
-            //
-            //         if let Some(err) = error {
-            //             Err(err)
-            //         } else {
-            //             Ok(())
-            //         }
-            //     }
-            //
-            //     let heap = Arc::clone(&self.heap);
-            //     let repo = Arc::clone(&self.repository);
-            //
-            //     #[cfg(feature = "devkit")]
-            //     let threadnum = self.task_handlers.len();
-            //
-            //     #[cfg(not(feature = "devkit"))]
-            //     let threadnum = 0usize;
-            //
-            //     let handle = task::spawn(create_new_task(heap, repo, threadnum));
-            //
-            //     // println!("Data: {:?}", self.data);
-            //     if let Boolean(bool) = self.data {
-            //         if bool {
-            //             self.task_handlers.push(handle);
-            //         }
-            //     }
-            //     Ok(())
-            // }
-            NTHRD => Err("Threads cannot be created inside other tasks/threads!".to_string()),
+            LDFN => {
+                dev_print!("[ LDFN ]");
+
+                let function = match self.data {
+                    UInteger(index) => self.functions.get(index as usize).cloned(),
+                    _ => None,
+                };
+
+                match function {
+                    Some(pointer) => self.stack.push(Immediates::RefPtr(pointer)),
+                    None => Err("[ UNDEFINED FUNCTION ]".to_string()),
+                }
+            }
+            CALL => {
+                dev_print!("[ CALL ]");
+
+                match self.stack.pop()? {
+                    Immediates::RefPtr(pointer) if pointer.is_fn => {
+                        self.frames().call(pointer, Vec::new())
+                    }
+                    Immediates::Closure(closure) => {
+                        let Closure { function, captures } = *closure;
+                        self.frames().call(function, captures)
+                    }
+                    _ => Err("[ NOT A FUNCTION ]".to_string()),
+                }
+            }
+            RET => {
+                dev_print!("[ RET ]");
+
+                self.return_function()
+            }
+            CLOSURE => {
+                dev_print!("[ CLOSURE ]");
+
+                let captures = if let UInteger(captures) = self.data {
+                    captures as usize
+                } else {
+                    0
+                };
+
+                let closure = create_closure(&mut self.stack, captures)?;
+
+                self.stack.push(closure)
+            }
+            NTASK | NTHRD => {
+                Err("Threads cannot be created inside other tasks/threads!".to_string())
+            }
+            CHNEW | CHSEND | CHRECV | CHTRY | CHCLOSE => {
+                dev_print!("[ CHANNEL 0x{:02X} ]", instruction);
+
+                let blocking = !self.cooperative;
+
+                if !channel_instruction(instruction, &self.data, &mut self.stack, blocking)? {
+                    self.retry_later();
+                } else if instruction != CHNEW {
+                    self.released = true;
+                }
+
+                Ok(())
+            }
+            CVWAIT if self.cooperative => {
+                dev_print!("[ CVWAIT ]");
+
+                // The task waits as a registered waiter, giving the worker up
+                // until it's notified and can lock the mutex again.
+                let mutex = pop_mutex(&mut self.stack, &self.heap)?;
+                let condition = pop_condition(&mut self.stack, &self.heap)?;
+
+                condition.register(&mutex, self.owner)?;
+                self.held_mutexes.release(&mutex);
+
+                self.pending_wait = Some(condition);
+                self.pending_lock = Some(mutex);
+                self.released = true;
+                self.blocked = true;
+                self.yielded = true;
+
+                Ok(())
+            }
+            MXNEW | MXLOCK | MXUNLOCK | CVNEW | CVWAIT | CVNOTIFY | CVNOTIFYALL | ATNEW
+            | ATLOAD | ATSTORE | ATFADD | ATCAS => {
+                dev_print!("[ SYNC 0x{:02X} ]", instruction);
+
+                let blocking = !self.cooperative;
+
+                let allocate = |stack: &VMStack, data| {
+                    heap_dump::allocate(
+                        &self.heap,
+                        &self.repository,
+                        self.heap_dump_on_oom.as_deref(),
+                        self.region,
+                        data,
+                        || thread_roots(stack, &self.functions, self.current_function.as_ref()),
+                    )
+                };
+
+                let done = sync_instruction(
+                    instruction,
+                    &mut self.stack,
+                    &self.heap,
+                    &allocate,
+                    self.owner,
+                    &mut self.held_mutexes,
+                    blocking,
+                )?;
+
+                if !done {
+                    self.retry_later();
+                } else if matches!(instruction, MXUNLOCK | CVNOTIFY | CVNOTIFYALL) {
+                    self.released = true;
+                }
+
+                Ok(())
+            }
+            YIELD => {
+                dev_print!("[ YIELD ]");
+
+                if self.cooperative {
+                    self.yielded = true;
+                } else {
+                    std::thread::yield_now();
+                }
+
+                Ok(())
+            }
+            PANIC => {
+                dev_print!("[ PANIC ]");
+
+                let message = self
+                    .stack
+                    .pop()
+                    .map_or(EXPLICIT_PANIC.to_string(), |value| value.to_string());
+                Err(format!("[ PANIC ] {message}"))
+            }
+            TRY => {
+                dev_print!("[ TRY ]");
+
+                let UInteger(handler) = self.data else {
+                    return Err("[ WRONG ADDRESS ]".to_string());
+                };
+
+                self.handlers.push(TryHandler {
+                    handler: handler as usize,
+                    stack_len: self.stack.get_length(),
+                    frames: self.return_stack.get_length(),
+                });
+
+                Ok(())
+            }
+            ENDTRY => {
+                dev_print!("[ ENDTRY ]");
+
+                self.handlers.pop().map(|_| ())
+            }
+            THROW => {
+                dev_print!("[ THROW ]");
+
+                let payload = self.stack.pop()?;
+                self.frames().throw(payload)
+            }
+            RSREAD | RSREADS | RSLIST | RSEXISTS => {
+                dev_print!("[ RESOURCE 0x{:02X} ]", instruction);
+
+                resource_instruction(instruction, &self.resources, &mut self.stack)
+            }
+            CALLNATIVE => {
+                dev_print!("[ CALLNATIVE ]");
+
+                native_instruction(&self.natives, &mut self.stack)
+            }
+            JOIN | AWAIT | JOINTO | AWAITTO | CANCEL | TCANCEL => {
+                Err("Threads cannot be joined inside other tasks/threads!".to_string())
+            }
             _ => Err("[ UNKNOWN INSTRUCTION ]".to_string()),
         }
     }